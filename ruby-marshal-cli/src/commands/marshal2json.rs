@@ -41,6 +41,7 @@ fn ruby2json_value(
         ruby_marshal::Value::Bool(value) => Ok(serde_json::Value::Bool(value.value())),
         ruby_marshal::Value::Symbol(_value) => bail!("cannot convert a Symbol to Json"),
         ruby_marshal::Value::Fixnum(value) => Ok(serde_json::Value::Number(value.value().into())),
+        ruby_marshal::Value::Bignum(_value) => bail!("cannot convert a Bignum to Json"),
         ruby_marshal::Value::Float(value) => Ok(json!(value.value())),
         ruby_marshal::Value::Array(value) => {
             let value = value.value();
@@ -59,6 +60,9 @@ fn ruby2json_value(
         ruby_marshal::Value::Object(_value) => {
             bail!("cannot convert an Object to Json")
         }
+        ruby_marshal::Value::Struct(_value) => {
+            bail!("cannot convert a Struct to Json")
+        }
         ruby_marshal::Value::String(value) => {
             let instance_variables = value.instance_variables();
             let encoding = instance_variables.and_then(|instance_variables| {
@@ -87,9 +91,15 @@ fn ruby2json_value(
                 }
             }
         }
+        ruby_marshal::Value::Regexp(_value) => {
+            bail!("cannot convert a Regexp to Json")
+        }
         ruby_marshal::Value::UserDefined(_value) => {
             bail!("cannot convert an UserDefined to Json")
         }
+        ruby_marshal::Value::UserMarshal(_value) => {
+            bail!("cannot convert an UserMarshal to Json")
+        }
         ruby_marshal::Value::Class(_value) => {
             bail!("cannot convert a Class to Json")
         }
@@ -1,20 +1,27 @@
 mod from_value;
+mod time;
 
 pub use self::from_value::FromValue;
 pub use self::from_value::FromValueContext;
 pub use self::from_value::FromValueError;
+pub use self::from_value::HashMapFromValueError;
+pub use self::from_value::PathSegment;
+pub use self::from_value::RubyHash;
+pub use self::time::RubyTime;
+use crate::HashMap;
 use crate::ValueArena;
 use crate::ValueHandle;
-use std::collections::HashMap;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 /// A utility to display a byte sequence as a string if it is UTF8 or a slice otherwise.
 #[derive(Debug)]
 pub struct DisplayByteString<'a>(pub &'a [u8]);
 
-impl<'a> std::fmt::Display for DisplayByteString<'a> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<'a> core::fmt::Display for DisplayByteString<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let string = self.0;
-        match std::str::from_utf8(string) {
+        match core::str::from_utf8(string) {
             Ok(string) => write!(f, "{string}"),
             Err(_error) => write!(f, "{string:?}"),
         }
@@ -26,7 +33,7 @@ impl<'a> std::fmt::Display for DisplayByteString<'a> {
 pub enum IntoValueError {
     /// Another user-provided kind of error occured.
     Other {
-        error: Box<dyn std::error::Error + Send + Sync + 'static>,
+        error: Box<dyn core::error::Error + Send + Sync + 'static>,
     },
 }
 
@@ -34,7 +41,7 @@ impl IntoValueError {
     /// Shorthand for creating a new `Other` error variant.
     pub fn new_other<E>(error: E) -> Self
     where
-        E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+        E: Into<Box<dyn core::error::Error + Send + Sync + 'static>>,
     {
         Self::Other {
             error: error.into(),
@@ -42,16 +49,16 @@ impl IntoValueError {
     }
 }
 
-impl std::fmt::Display for IntoValueError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for IntoValueError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::Other { .. } => write!(f, "a user-provided error was encountered"),
         }
     }
 }
 
-impl std::error::Error for IntoValueError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+impl core::error::Error for IntoValueError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
         match self {
             Self::Other { error } => Some(&**error),
             // _ => None,
@@ -83,6 +90,18 @@ impl IntoValue for f64 {
     }
 }
 
+impl<T> IntoValue for Option<T>
+where
+    T: IntoValue,
+{
+    fn into_value(self, arena: &mut ValueArena) -> Result<ValueHandle, IntoValueError> {
+        match self {
+            Some(value) => value.into_value(arena),
+            None => Ok(arena.create_nil().into()),
+        }
+    }
+}
+
 impl<T> IntoValue for Vec<T>
 where
     T: IntoValue,
@@ -125,11 +144,15 @@ mod test {
     use crate::HashValue;
     use crate::NilValue;
     use crate::ObjectValue;
+    use crate::RandomState;
     use crate::StringValue;
+    use crate::StructValue;
     use crate::SymbolValue;
     use crate::UserDefinedValue;
     use crate::ClassValue;
     use crate::Value;
+    use crate::ValueKind;
+    use indexmap::IndexMap;
 
     #[test]
     fn sanity() {
@@ -244,4 +267,429 @@ mod test {
             .into_value(&mut arena)
             .expect("failed to exec HashMap::<i32, i32>::into_value");
     }
+
+    #[test]
+    fn object_or_hash_field() {
+        let mut arena = ValueArena::new();
+
+        let name_symbol = arena.create_symbol("@name".into());
+        let name = arena.create_string("Bob".into()).into_raw();
+        let object_name = arena.create_symbol("User".into());
+        let object = arena
+            .create_object(object_name, vec![(name_symbol, name)])
+            .into_raw();
+
+        let name_key = arena.create_string("name".into()).into_raw();
+        let age_key = arena.create_symbol("age".into()).into_raw();
+        let age = arena.create_fixnum(42).into_raw();
+        let hash = arena
+            .create_hash(vec![(name_key, name), (age_key, age)], None)
+            .into_raw();
+
+        let ctx = FromValueContext::new(&arena);
+
+        assert!(ctx.object_or_hash_field(object, b"name") == Some(name));
+        assert!(ctx.object_or_hash_field(object, b"age").is_none());
+        assert!(ctx.object_or_hash_field(hash, b"name") == Some(name));
+        assert!(ctx.object_or_hash_field(hash, b"age") == Some(age));
+        assert!(ctx.object_or_hash_field(age, b"age").is_none());
+    }
+
+    #[test]
+    fn integers() {
+        // Marshal.dump([5, 2**100, -2**127, 2**127])
+        let mut data = b"\x04\x08[\x09i\x0al+\x0c".to_vec();
+        data.extend([0; 12]);
+        data.extend(b"\x10\x00l-\x0d");
+        data.extend([0; 15]);
+        data.extend(b"\x80l+\x0d");
+        data.extend([0; 15]);
+        data.push(0x80);
+
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let array: &ArrayValue = ctx.from_value(arena.root()).expect("failed to decode");
+        let array = array.value();
+
+        let value: i128 = ctx.from_value(array[0]).expect("failed to decode");
+        assert!(value == 5);
+        let value: i128 = ctx.from_value(array[1]).expect("failed to decode");
+        assert!(value == 1 << 100);
+        let value: i128 = ctx.from_value(array[2]).expect("failed to decode");
+        assert!(value == i128::MIN);
+        let error = ctx
+            .from_value::<i128>(array[3])
+            .expect_err("decoded 2**127 as an i128");
+        assert!(matches!(error, FromValueError::IntegerOutOfRange));
+
+        #[cfg(feature = "bigint")]
+        {
+            let value: num_bigint::BigInt = ctx.from_value(array[0]).expect("failed to decode");
+            assert!(value.to_string() == "5");
+            let value: num_bigint::BigInt = ctx.from_value(array[1]).expect("failed to decode");
+            assert!(value.to_string() == "1267650600228229401496703205376");
+        }
+    }
+
+    #[test]
+    fn option_nil_instance_variable() {
+        // An object of class Foo, with @a = nil and @b = 5.
+        let data = b"\x04\x08o:\x08Foo\x07:\x07@a0:\x07@bi\x0a";
+        let mut arena = crate::load(&data[..]).expect("failed to load");
+
+        let ctx = FromValueContext::new(&arena);
+        let a = ctx
+            .object_or_hash_field(arena.root(), b"a")
+            .expect("missing @a");
+        let b = ctx
+            .object_or_hash_field(arena.root(), b"b")
+            .expect("missing @b");
+
+        let a: Option<i64> = ctx.from_value(a).expect("failed to decode @a");
+        assert!(a.is_none());
+        let b: Option<i64> = ctx.from_value(b).expect("failed to decode @b");
+        assert!(b == Some(5));
+
+        let none = None::<i32>
+            .into_value(&mut arena)
+            .expect("failed to exec Option::<i32>::into_value");
+        assert!(matches!(arena[none], Value::Nil(_)));
+
+        let some = Some(5_i32)
+            .into_value(&mut arena)
+            .expect("failed to exec Option::<i32>::into_value");
+        assert!(matches!(&arena[some], Value::Fixnum(value) if value.value() == 5));
+    }
+
+    #[test]
+    fn error_path() {
+        fn field<'a, T>(
+            ctx: &FromValueContext<'a>,
+            value: &'a Value,
+            name: &[u8],
+        ) -> Result<T, FromValueError>
+        where
+            T: FromValue<'a>,
+        {
+            let object: &ObjectValue = FromValue::from_value(ctx, value)?;
+            for (key, value) in object.instance_variables().iter().copied() {
+                let key: &SymbolValue = ctx.from_value(key.into())?;
+                if key.value() == name {
+                    return ctx.from_value_at(value, PathSegment::Key(name.to_vec()));
+                }
+            }
+
+            Err(FromValueError::MissingInstanceVariable {
+                name: name.to_vec(),
+            })
+        }
+
+        struct User {
+            _age: i32,
+        }
+
+        impl<'a> FromValue<'a> for User {
+            fn from_value(
+                ctx: &FromValueContext<'a>,
+                value: &'a Value,
+            ) -> Result<Self, FromValueError> {
+                Ok(Self {
+                    _age: field(ctx, value, b"@age")?,
+                })
+            }
+        }
+
+        // A Root object whose @users are two User objects, the second with @age = "x".
+        let mut data = b"\x04\x08o:\x09Root\x06:\x0b@users[\x07".to_vec();
+        data.extend(b"o:\x09User\x06:\x09@agei\x06");
+        data.extend(b"o;\x07\x06;\x08\"\x06x");
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let root = ctx
+            .from_value::<&Value>(arena.root())
+            .expect("failed to decode");
+
+        let error = field::<Vec<User>>(&ctx, root, b"@users")
+            .err()
+            .expect("decoded a string as an i32");
+        assert!(error.to_string() == "unexpected value kind String at @users.1.@age");
+        let FromValueError::AtPath { path, error } = &error else {
+            panic!("missing path: {error:?}");
+        };
+        assert!(
+            *path
+                == [
+                    PathSegment::Key(b"@users".to_vec()),
+                    PathSegment::Index(1),
+                    PathSegment::Key(b"@age".to_vec()),
+                ]
+        );
+        assert!(matches!(
+            **error,
+            FromValueError::UnexpectedValueKind {
+                kind: ValueKind::String,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn ruby_hash() {
+        // Marshal.dump({ [1, 2] => 3, [4] => 5 })
+        let data = b"\x04\x08{\x07[\x07i\x06i\x07i\x08[\x06i\x09i\x0a";
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+
+        let hash: RubyHash<i32> = ctx.from_value(arena.root()).expect("failed to decode");
+        assert!(hash.len() == 2);
+        assert!(hash.default_value().is_none());
+
+        let entries: Vec<(Vec<i32>, i32)> = hash
+            .into_entries()
+            .into_iter()
+            .map(|(key, value)| (ctx.from_value(key).expect("failed to decode key"), value))
+            .collect();
+        assert!(entries == [(vec![1, 2], 3), (vec![4], 5)]);
+    }
+
+    #[test]
+    fn ruby_struct() {
+        struct Point(i32, i32);
+
+        impl<'a> FromValue<'a> for Point {
+            fn from_value(
+                ctx: &FromValueContext<'a>,
+                value: &'a Value,
+            ) -> Result<Self, FromValueError> {
+                let value: &StructValue = FromValue::from_value(ctx, value)?;
+                let member = |index| {
+                    let handle = value
+                        .member_at(index)
+                        .ok_or_else(|| FromValueError::Other {
+                            error: format!("missing member {index}").into(),
+                        })?;
+                    ctx.from_value_at(handle, PathSegment::Index(index))
+                };
+
+                Ok(Self(member(0)?, member(1)?))
+            }
+        }
+
+        struct NamedPoint {
+            x: i32,
+            y: i32,
+        }
+
+        impl<'a> FromValue<'a> for NamedPoint {
+            fn from_value(
+                ctx: &FromValueContext<'a>,
+                value: &'a Value,
+            ) -> Result<Self, FromValueError> {
+                let value: &StructValue = FromValue::from_value(ctx, value)?;
+                let member = |name: &[u8]| {
+                    let handle = ctx.struct_member(value, name).ok_or_else(|| {
+                        FromValueError::MissingInstanceVariable {
+                            name: name.to_vec(),
+                        }
+                    })?;
+                    ctx.from_value_at(handle, PathSegment::Key(name.to_vec()))
+                };
+
+                Ok(Self {
+                    x: member(b"x")?,
+                    y: member(b"y")?,
+                })
+            }
+        }
+
+        // Point = Struct.new(:x, :y); Marshal.dump(Point.new(1, 2))
+        let data = b"\x04\x08S:\x0aPoint\x07:\x06xi\x06:\x06yi\x07";
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+
+        let value = ctx
+            .expect_struct(arena.root(), b"Point")
+            .expect("failed to get struct");
+        assert!(value.members().len() == 2);
+        assert!(ctx.struct_member(value, b"z").is_none());
+        assert!(matches!(
+            ctx.expect_struct(arena.root(), b"Line"),
+            Err(FromValueError::UnexpectedObjectName { .. })
+        ));
+
+        let point: Point = ctx.from_value(arena.root()).expect("failed to decode");
+        assert!(point.0 == 1 && point.1 == 2);
+
+        let point: NamedPoint = ctx.from_value(arena.root()).expect("failed to decode");
+        assert!(point.x == 1 && point.y == 2);
+
+        let mut new_data = Vec::new();
+        crate::dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(new_data == data);
+    }
+
+    #[test]
+    fn index_map() {
+        #[derive(Debug, PartialEq, Eq, Hash)]
+        struct Key(Vec<u8>);
+
+        impl<'a> FromValue<'a> for Key {
+            fn from_value(
+                ctx: &FromValueContext<'a>,
+                value: &'a Value,
+            ) -> Result<Self, FromValueError> {
+                let value: &SymbolValue = FromValue::from_value(ctx, value)?;
+                Ok(Self(value.value().to_vec()))
+            }
+        }
+
+        // Marshal.dump({ c: 1, a: 2, b: 3 })
+        let data = b"\x04\x08{\x08:\x06ci\x06:\x06ai\x07:\x06bi\x08";
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let map: IndexMap<Key, i32, RandomState> =
+            ctx.from_value(arena.root()).expect("failed to decode");
+
+        let keys: Vec<&[u8]> = map.keys().map(|key| key.0.as_slice()).collect();
+        assert!(keys == [b"c", b"a", b"b"]);
+        assert!(map.get(&Key(b"a".to_vec())) == Some(&2));
+        assert!(map.get(&Key(b"d".to_vec())).is_none());
+    }
+
+    #[test]
+    fn char() {
+        // Marshal.dump(["a", :b, "é", "ab", "", 1])
+        let data = b"\x04\x08[\x0bI\"\x06a\x06:\x06ET:\x06bI\"\x07\xc3\xa9\x06;\x00T\
+            I\"\x07ab\x06;\x00TI\"\x00\x06;\x00Ti\x06";
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let array: &ArrayValue = ctx.from_value(arena.root()).expect("failed to decode");
+        let array = array.value();
+
+        let value: char = ctx.from_value(array[0]).expect("failed to decode");
+        assert!(value == 'a');
+        let value: char = ctx.from_value(array[1]).expect("failed to decode");
+        assert!(value == 'b');
+        let value: char = ctx.from_value(array[2]).expect("failed to decode");
+        assert!(value == 'é');
+        for handle in [array[3], array[4]] {
+            let error = ctx.from_value::<char>(handle).expect_err("decoded a char");
+            assert!(matches!(error, FromValueError::NotASingleChar));
+        }
+        let error = ctx
+            .from_value::<char>(array[5])
+            .expect_err("decoded a char");
+        assert!(matches!(error, FromValueError::UnexpectedValueKind { .. }));
+    }
+
+    #[test]
+    fn ruby_time() {
+        // Marshal.dump(Time.at(1700000000, 123456, :usec).getlocal("+09:00"))
+        let data = b"\x04\x08Iu:\x09Time\x0d\xd6\xe9\x1e\x80\x40\xe2\x41\x35\
+            \x06:\x0boffseti\x02\x90\x7e";
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let time: RubyTime = ctx.from_value(arena.root()).expect("failed to decode");
+        assert!(time.seconds == 1700000000);
+        assert!(time.nanoseconds == 123456000);
+        assert!(!time.is_utc);
+        assert!(time.utc_offset == 9 * 3600);
+        assert!(time.zone.is_none());
+        assert!(time.local_seconds() == 1700000000 + 9 * 3600);
+        // Marshal.dump(Time.at(0).utc)
+        let data = b"\x04\x08Iu:\x09Time\x0d\x20\x80\x11\xc0\x00\x00\x00\x00\
+            \x06:\x09zoneI\"\x08UTC\x06:\x06EF";
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let time: RubyTime = ctx.from_value(arena.root()).expect("failed to decode");
+        assert!(time.seconds == 0);
+        assert!(time.is_utc);
+        assert!(time.utc_offset == 0);
+        assert!(time.zone.as_deref() == Some(&b"UTC"[..]));
+    }
+
+    #[test]
+    fn integer_kinds() {
+        // Marshal.dump([7, 2**40, 2**30, 2**31])
+        let data = b"\x04\x08[\x09i\x0cl+\x08\x00\x00\x00\x00\x00\x01\
+            l+\x07\x00\x00\x00\x40l+\x07\x00\x00\x00\x80";
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let array: &ArrayValue = ctx.from_value(arena.root()).expect("failed to decode");
+        let array = array.value();
+
+        let value: i128 = ctx.from_value(array[0]).expect("failed to decode");
+        assert!(value == 7);
+        let value: i128 = ctx.from_value(array[1]).expect("failed to decode");
+        assert!(value == 1 << 40);
+        let value: i64 = ctx.from_value(array[0]).expect("failed to decode");
+        assert!(value == 7);
+        let value: i64 = ctx.from_value(array[1]).expect("failed to decode");
+        assert!(value == 1 << 40);
+        let value: i32 = ctx.from_value(array[2]).expect("failed to decode");
+        assert!(value == 1 << 30);
+        let error = ctx
+            .from_value::<i32>(array[3])
+            .expect_err("decoded 2**31 as an i32");
+        assert!(matches!(error, FromValueError::IntegerOutOfRange));
+    }
+
+    #[test]
+    fn open_struct() {
+        // An OpenStruct.new(a: 1, b: "x") dumped as an object, as older Rubies do.
+        let data = b"\x04\x08o:\x0fOpenStruct\x06:\x0b@table{\x07:\x06ai\x06\
+            :\x06bI\"\x06x\x06:\x06ET";
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let fields = ctx
+            .parse_open_struct(arena.root())
+            .expect("failed to parse");
+        assert!(fields.len() == 2);
+        assert!(fields[0].0 == b"a");
+        let value: i32 = ctx.from_value(fields[0].1).expect("failed to decode");
+        assert!(value == 1);
+        assert!(fields[1].0 == b"b");
+        let value: &StringValue = ctx.from_value(fields[1].1).expect("failed to decode");
+        assert!(value.value() == b"x");
+
+        // Marshal.dump(OpenStruct.new(a: 1)), with marshal_dump
+        let data = b"\x04\x08U:\x0fOpenStruct{\x06:\x06ai\x06";
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let fields = ctx
+            .parse_open_struct(arena.root())
+            .expect("failed to parse");
+        assert!(fields.len() == 1);
+        assert!(fields[0].0 == b"a");
+
+        // Marshal.dump(Point.new(1, 2))
+        let data = b"\x04\x08o:\x0aPoint\x07:\x07@xi\x06:\x07@yi\x07";
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let error = ctx
+            .parse_open_struct(arena.root())
+            .expect_err("parsed a Point");
+        assert!(matches!(error, FromValueError::UnexpectedObjectName { .. }));
+    }
+
+    #[test]
+    fn range() {
+        // Marshal.dump([1..5, 1...5])
+        let data = b"\x04\x08[\x07o:\x0aRange\x08:\x09exclF:\x0abegini\x06:\x08endi\x0a\
+            o;\x00\x08;\x06T;\x07i\x06;\x08i\x0a";
+        let arena = crate::load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let array: &ArrayValue = ctx.from_value(arena.root()).expect("failed to decode");
+        let array = array.value();
+
+        let (begin, end, exclude_end) = ctx.parse_range(array[0]).expect("failed to parse");
+        assert!(ctx.from_value::<i32>(begin).expect("failed to decode") == 1);
+        assert!(ctx.from_value::<i32>(end).expect("failed to decode") == 5);
+        assert!(!exclude_end);
+        let (_, _, exclude_end) = ctx.parse_range(array[1]).expect("failed to parse");
+        assert!(exclude_end);
+
+        let error = ctx.parse_range(arena.root()).expect_err("parsed an array");
+        assert!(matches!(error, FromValueError::UnexpectedValueKind { .. }));
+    }
 }
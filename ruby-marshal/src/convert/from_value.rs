@@ -1,22 +1,32 @@
 use super::DisplayByteString;
 use crate::ArrayValue;
+use crate::BignumValue;
 use crate::BoolValue;
 use crate::FixnumValue;
 use crate::FloatValue;
+use crate::HashMap;
 use crate::HashValue;
 use crate::NilValue;
 use crate::ObjectValue;
+use crate::RegexpValue;
 use crate::StringValue;
+use crate::StructValue;
 use crate::SymbolValue;
+use crate::TypedValueHandle;
 use crate::UserDefinedValue;
+use crate::UserMarshalValue;
 use crate::ClassValue;
 use crate::Value;
 use crate::ValueArena;
 use crate::ValueHandle;
 use crate::ValueKind;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::hash::Hash;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::hash::BuildHasher;
+use core::hash::Hash;
+use indexmap::IndexMap;
 
 /// An error that may occur while creating a type from a Ruby Value.
 #[derive(Debug)]
@@ -90,9 +100,24 @@ pub enum FromValueError {
         key: ValueHandle,
     },
 
+    /// An integer did not fit in the target type.
+    IntegerOutOfRange,
+
+    /// A string or symbol did not hold exactly one UTF-8 character.
+    NotASingleChar,
+
+    /// An error occured while extracting a nested value.
+    AtPath {
+        /// The path from the outermost value to the value that failed.
+        path: Vec<PathSegment>,
+
+        /// The error.
+        error: Box<FromValueError>,
+    },
+
     /// Another user-provided kind of error occured.
     Other {
-        error: Box<dyn std::error::Error + Send + Sync + 'static>,
+        error: Box<dyn core::error::Error + Send + Sync + 'static>,
     },
 }
 
@@ -100,16 +125,30 @@ impl FromValueError {
     /// Shorthand for creating a new `Other` error variant.
     pub fn new_other<E>(error: E) -> Self
     where
-        E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+        E: Into<Box<dyn core::error::Error + Send + Sync + 'static>>,
     {
         Self::Other {
             error: error.into(),
         }
     }
+
+    /// Prefix the path of this error with the given segment.
+    pub fn at(self, segment: PathSegment) -> Self {
+        match self {
+            Self::AtPath { mut path, error } => {
+                path.insert(0, segment);
+                Self::AtPath { path, error }
+            }
+            error => Self::AtPath {
+                path: vec![segment],
+                error: Box::new(error),
+            },
+        }
+    }
 }
 
-impl std::fmt::Display for FromValueError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for FromValueError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::Cycle { .. } => write!(f, "attempted to extract recursively"),
             Self::InvalidValueHandle { .. } => write!(f, "a handle was invalid"),
@@ -148,20 +187,54 @@ impl std::fmt::Display for FromValueError {
             Self::DuplicateHashKey { .. } => {
                 write!(f, "duplicate hash key")
             }
+            Self::IntegerOutOfRange => write!(f, "integer out of range"),
+            Self::NotASingleChar => write!(f, "not a single character"),
+            Self::AtPath { path, error } => {
+                write!(f, "{error} at ")?;
+                for (i, segment) in path.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ".")?;
+                    }
+                    write!(f, "{segment}")?;
+                }
+                Ok(())
+            }
             Self::Other { .. } => write!(f, "a user-provided error was encountered"),
         }
     }
 }
 
-impl std::error::Error for FromValueError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+impl core::error::Error for FromValueError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
         match self {
             Self::Other { error } => Some(&**error),
+            Self::AtPath { error, .. } => Some(&**error),
             _ => None,
         }
     }
 }
 
+/// A step into a nested value, used to locate a [`FromValueError`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    /// An array element, or a hash entry whose key is not a String or Symbol.
+    Index(usize),
+
+    /// A hash entry with a String or Symbol key, or an instance variable.
+    ///
+    /// This may or may not be UTF-8.
+    Key(Vec<u8>),
+}
+
+impl core::fmt::Display for PathSegment {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "{index}"),
+            Self::Key(key) => write!(f, "{}", DisplayByteString(key)),
+        }
+    }
+}
+
 /// A context to manage extracting values.
 pub struct FromValueContext<'a> {
     arena: &'a ValueArena,
@@ -216,6 +289,193 @@ impl<'a> FromValueContext<'a> {
         Ok(value)
     }
 
+    /// Extract a type from a nested value.
+    ///
+    /// Errors are prefixed with the given segment, as with [`FromValueError::at`].
+    pub fn from_value_at<T>(
+        &self,
+        handle: ValueHandle,
+        segment: PathSegment,
+    ) -> Result<T, FromValueError>
+    where
+        T: FromValue<'a>,
+    {
+        self.from_value(handle).map_err(|error| error.at(segment))
+    }
+
+    /// Get the path segment of the value of a hash entry.
+    fn hash_entry_segment(&self, key: ValueHandle, index: usize) -> PathSegment {
+        self.arena.hash_entry_segment(key, index)
+    }
+
+    /// Look up a field by name on an object or a hash.
+    ///
+    /// For objects, this is the instance variable named `name` prefixed with `@`.
+    /// For hashes, this is the value of the first String or Symbol key equal to `name`.
+    /// This allows the same [`FromValue`] impl to handle both serialization shapes.
+    ///
+    /// # Returns
+    /// Returns None if the field does not exist or the value is not an object or hash.
+    pub fn object_or_hash_field(&self, handle: ValueHandle, name: &[u8]) -> Option<ValueHandle> {
+        match self.arena.get(handle)? {
+            Value::Object(object) => object.instance_variables().iter().find_map(|(key, value)| {
+                let key = self.arena.get_symbol(*key)?.value();
+                let key = key.strip_prefix(b"@")?;
+                (key == name).then_some(*value)
+            }),
+            Value::Hash(hash) => hash.value().iter().find_map(|(key, value)| {
+                let key = match self.arena.get(*key)? {
+                    Value::String(key) => key.value(),
+                    Value::Symbol(key) => key.value(),
+                    _ => return None,
+                };
+                (key == name).then_some(*value)
+            }),
+            _ => None,
+        }
+    }
+
+    /// Get the struct at the given handle, checking that it is of class `class_name`.
+    ///
+    /// # Returns
+    /// Returns [`FromValueError::UnexpectedValueKind`] if the value is not a struct,
+    /// and [`FromValueError::UnexpectedObjectName`] if it is of another class.
+    pub fn expect_struct(
+        &self,
+        handle: ValueHandle,
+        class_name: &[u8],
+    ) -> Result<&'a StructValue, FromValueError> {
+        let value: &'a StructValue = self.from_value(handle)?;
+        let name: &'a SymbolValue = self.from_value(value.name().into())?;
+        if name.value() != class_name {
+            return Err(FromValueError::UnexpectedObjectName {
+                name: name.value().to_vec(),
+            });
+        }
+
+        Ok(value)
+    }
+
+    /// Get the fields of the `OpenStruct` at the given handle, in order.
+    ///
+    /// This reads both the object shape, where the fields are in a `@table` hash of Symbol keys,
+    /// and the user marshal shape newer Rubies write, where `marshal_dump` returns that hash.
+    ///
+    /// # Returns
+    /// Returns [`FromValueError::UnexpectedObjectName`] if the value is of another class,
+    /// and [`FromValueError::MissingInstanceVariable`] if an object has no `@table`.
+    pub fn parse_open_struct(
+        &self,
+        handle: ValueHandle,
+    ) -> Result<Vec<(Vec<u8>, ValueHandle)>, FromValueError> {
+        let check_name = |name: TypedValueHandle<SymbolValue>| {
+            let name: &'a SymbolValue = self.from_value(name.into())?;
+            if name.value() != b"OpenStruct" {
+                return Err(FromValueError::UnexpectedObjectName {
+                    name: name.value().to_vec(),
+                });
+            }
+
+            Ok(())
+        };
+
+        let table = match self.from_value::<&'a UserMarshalValue>(handle) {
+            Ok(value) => {
+                check_name(value.name())?;
+                value.value()
+            }
+            Err(_) => {
+                let value: &'a ObjectValue = self.from_value(handle)?;
+                check_name(value.name())?;
+                self.object_or_hash_field(handle, b"table").ok_or_else(|| {
+                    FromValueError::MissingInstanceVariable {
+                        name: b"@table".to_vec(),
+                    }
+                })?
+            }
+        };
+        let table: &'a HashValue = self.from_value(table)?;
+        table
+            .value()
+            .iter()
+            .map(|(key, value)| {
+                let key: &'a SymbolValue = self.from_value(*key)?;
+                Ok((key.value().to_vec(), *value))
+            })
+            .collect()
+    }
+
+    /// Get the begin, end, and whether the end is excluded, of the `Range` at the given handle.
+    ///
+    /// Ruby dumps a range as an object with `begin`, `end`, and `excl` instance variables,
+    /// which have no `@` prefix.
+    ///
+    /// # Returns
+    /// Returns [`FromValueError::UnexpectedObjectName`] if the object is of another class,
+    /// and [`FromValueError::MissingInstanceVariable`] if any of the fields is missing.
+    pub fn parse_range(
+        &self,
+        handle: ValueHandle,
+    ) -> Result<(ValueHandle, ValueHandle, bool), FromValueError> {
+        let value: &'a ObjectValue = self.from_value(handle)?;
+        let name: &'a SymbolValue = self.from_value(value.name().into())?;
+        if name.value() != b"Range" {
+            return Err(FromValueError::UnexpectedObjectName {
+                name: name.value().to_vec(),
+            });
+        }
+
+        let field = |name: &[u8]| {
+            value
+                .instance_variables()
+                .iter()
+                .find_map(|(key, value)| {
+                    let key = self.arena.get_symbol(*key)?.value();
+                    (key == name).then_some(*value)
+                })
+                .ok_or_else(|| FromValueError::MissingInstanceVariable {
+                    name: name.to_vec(),
+                })
+        };
+        let begin = field(b"begin")?;
+        let end = field(b"end")?;
+        let exclude_end =
+            self.from_value_at(field(b"excl")?, PathSegment::Key(b"excl".to_vec()))?;
+
+        Ok((begin, end, exclude_end))
+    }
+
+    /// Look up a struct member by name.
+    ///
+    /// Member names do not have an `@` prefix.
+    /// Use [`StructValue::member_at`] to look up members by index instead.
+    ///
+    /// # Returns
+    /// Returns None if the struct has no member with that name.
+    pub fn struct_member(&self, value: &StructValue, name: &[u8]) -> Option<ValueHandle> {
+        value.members().iter().find_map(|(key, value)| {
+            let key = self.arena.get_symbol(*key)?.value();
+            (key == name).then_some(*value)
+        })
+    }
+
+    /// Look up an instance variable of a user defined value by name.
+    ///
+    /// The name is matched exactly, as classes like `Time` attach ivars without an `@` prefix.
+    ///
+    /// # Returns
+    /// Returns None if the value has no instance variable with that name.
+    pub fn user_defined_instance_variable(
+        &self,
+        value: &UserDefinedValue,
+        name: &[u8],
+    ) -> Option<ValueHandle> {
+        value.instance_variables()?.iter().find_map(|(key, value)| {
+            let key = self.arena.get_symbol(*key)?.value();
+            (key == name).then_some(*value)
+        })
+    }
+
     /// Create a new UnexpectedValueKind error
     pub fn new_unexpected_value_kind_error(&self, kind: ValueKind) -> FromValueError {
         FromValueError::UnexpectedValueKind {
@@ -290,6 +550,15 @@ impl<'a> FromValue<'a> for &'a FixnumValue {
     }
 }
 
+impl<'a> FromValue<'a> for &'a BignumValue {
+    fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
+        match value {
+            Value::Bignum(value) => Ok(value),
+            value => Err(ctx.new_unexpected_value_kind_error(value.kind())),
+        }
+    }
+}
+
 impl<'a> FromValue<'a> for &'a FloatValue {
     fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
         match value {
@@ -335,6 +604,15 @@ impl<'a> FromValue<'a> for &'a ObjectValue {
     }
 }
 
+impl<'a> FromValue<'a> for &'a StructValue {
+    fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
+        match value {
+            Value::Struct(value) => Ok(value),
+            value => Err(ctx.new_unexpected_value_kind_error(value.kind())),
+        }
+    }
+}
+
 impl<'a> FromValue<'a> for &'a StringValue {
     fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
         match value {
@@ -344,6 +622,15 @@ impl<'a> FromValue<'a> for &'a StringValue {
     }
 }
 
+impl<'a> FromValue<'a> for &'a RegexpValue {
+    fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
+        match value {
+            Value::Regexp(value) => Ok(value),
+            value => Err(ctx.new_unexpected_value_kind_error(value.kind())),
+        }
+    }
+}
+
 impl<'a> FromValue<'a> for &'a UserDefinedValue {
     fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
         match value {
@@ -353,6 +640,15 @@ impl<'a> FromValue<'a> for &'a UserDefinedValue {
     }
 }
 
+impl<'a> FromValue<'a> for &'a UserMarshalValue {
+    fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
+        match value {
+            Value::UserMarshal(value) => Ok(value),
+            value => Err(ctx.new_unexpected_value_kind_error(value.kind())),
+        }
+    }
+}
+
 impl<'a> FromValue<'a> for &'a ClassValue {
     fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
         match value {
@@ -369,10 +665,43 @@ impl<'a> FromValue<'a> for bool {
     }
 }
 
+// Ruby writes integers outside of 31 bits as Bignums, so integer impls accept either kind.
 impl<'a> FromValue<'a> for i32 {
     fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
-        let value: &FixnumValue = FromValue::from_value(ctx, value)?;
-        Ok(value.value())
+        let value: i128 = FromValue::from_value(ctx, value)?;
+        value
+            .try_into()
+            .map_err(|_| FromValueError::IntegerOutOfRange)
+    }
+}
+
+impl<'a> FromValue<'a> for i64 {
+    fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
+        let value: i128 = FromValue::from_value(ctx, value)?;
+        value
+            .try_into()
+            .map_err(|_| FromValueError::IntegerOutOfRange)
+    }
+}
+
+impl<'a> FromValue<'a> for i128 {
+    fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
+        match value {
+            Value::Fixnum(value) => Ok(value.value().into()),
+            Value::Bignum(value) => value.to_i128().ok_or(FromValueError::IntegerOutOfRange),
+            value => Err(ctx.new_unexpected_value_kind_error(value.kind())),
+        }
+    }
+}
+
+#[cfg(feature = "bigint")]
+impl<'a> FromValue<'a> for num_bigint::BigInt {
+    fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
+        match value {
+            Value::Fixnum(value) => Ok(value.value().into()),
+            Value::Bignum(value) => Ok(value.to_bigint()),
+            value => Err(ctx.new_unexpected_value_kind_error(value.kind())),
+        }
     }
 }
 
@@ -383,6 +712,23 @@ impl<'a> FromValue<'a> for f64 {
     }
 }
 
+impl<'a> FromValue<'a> for char {
+    fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
+        let value = match value {
+            Value::String(value) => value.value(),
+            Value::Symbol(value) => value.value(),
+            value => return Err(ctx.new_unexpected_value_kind_error(value.kind())),
+        };
+        let mut chars = core::str::from_utf8(value)
+            .map_err(|_| FromValueError::NotASingleChar)?
+            .chars();
+        match (chars.next(), chars.next()) {
+            (Some(value), None) => Ok(value),
+            _ => Err(FromValueError::NotASingleChar),
+        }
+    }
+}
+
 impl<'a, T> FromValue<'a> for Option<T>
 where
     T: FromValue<'a>,
@@ -404,8 +750,8 @@ where
         let array = array.value();
 
         let mut vec = Vec::with_capacity(array.len());
-        for handle in array.iter().copied() {
-            let value = ctx.from_value(handle)?;
+        for (index, handle) in array.iter().copied().enumerate() {
+            let value = ctx.from_value_at(handle, PathSegment::Index(index))?;
             vec.push(value);
         }
 
@@ -423,8 +769,8 @@ pub enum HashMapFromValueError {
     },
 }
 
-impl std::fmt::Display for HashMapFromValueError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for HashMapFromValueError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
             Self::HasDefaultValue { .. } => {
                 write!(f, "HashValue has a default value")
@@ -433,7 +779,7 @@ impl std::fmt::Display for HashMapFromValueError {
     }
 }
 
-impl std::error::Error for HashMapFromValueError {}
+impl core::error::Error for HashMapFromValueError {}
 
 impl<'a, K, V> FromValue<'a> for HashMap<K, V>
 where
@@ -454,9 +800,10 @@ where
         let value = value.value();
 
         let mut map = HashMap::with_capacity(value.len());
-        for (key_handle, value_handle) in value.iter().copied() {
-            let key = ctx.from_value(key_handle)?;
-            let value = ctx.from_value(value_handle)?;
+        for (index, (key_handle, value_handle)) in value.iter().copied().enumerate() {
+            let key = ctx.from_value_at(key_handle, PathSegment::Index(index))?;
+            let value =
+                ctx.from_value_at(value_handle, ctx.hash_entry_segment(key_handle, index))?;
 
             let old_value = map.insert(key, value);
 
@@ -468,3 +815,98 @@ where
         Ok(map)
     }
 }
+
+/// Unlike a [`HashMap`], this keeps the entries in the order of the Ruby hash.
+impl<'a, K, V, S> FromValue<'a> for IndexMap<K, V, S>
+where
+    K: FromValue<'a> + Hash + Eq,
+    V: FromValue<'a>,
+    S: BuildHasher + Default,
+{
+    fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
+        let value: &HashValue = FromValue::from_value(ctx, value)?;
+
+        if let Some(default_value) = value.default_value() {
+            return Err(FromValueError::new_other(
+                HashMapFromValueError::HasDefaultValue {
+                    value: default_value,
+                },
+            ));
+        }
+
+        let value = value.value();
+
+        let mut map = IndexMap::with_capacity_and_hasher(value.len(), S::default());
+        for (index, (key_handle, value_handle)) in value.iter().copied().enumerate() {
+            let key = ctx.from_value_at(key_handle, PathSegment::Index(index))?;
+            let value =
+                ctx.from_value_at(value_handle, ctx.hash_entry_segment(key_handle, index))?;
+
+            let old_value = map.insert(key, value);
+
+            if old_value.is_some() {
+                return Err(FromValueError::DuplicateHashKey { key: key_handle });
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+/// A hash with keys of any kind, and values of a Rust type.
+///
+/// Unlike a [`HashMap`], this can hold keys that cannot be Rust map keys, like arrays and hashes.
+/// The keys are kept as handles, in order, and duplicates are kept.
+#[derive(Debug)]
+pub struct RubyHash<V> {
+    entries: Vec<(ValueHandle, V)>,
+    default_value: Option<ValueHandle>,
+}
+
+impl<V> RubyHash<V> {
+    /// Get the entries, in order.
+    pub fn entries(&self) -> &[(ValueHandle, V)] {
+        &self.entries
+    }
+
+    /// Take the entries, in order.
+    pub fn into_entries(self) -> Vec<(ValueHandle, V)> {
+        self.entries
+    }
+
+    /// Get the number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get the default value.
+    pub fn default_value(&self) -> Option<ValueHandle> {
+        self.default_value
+    }
+}
+
+impl<'a, V> FromValue<'a> for RubyHash<V>
+where
+    V: FromValue<'a>,
+{
+    fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
+        let value: &HashValue = FromValue::from_value(ctx, value)?;
+
+        let mut entries = Vec::with_capacity(value.value().len());
+        for (index, (key_handle, value_handle)) in value.value().iter().copied().enumerate() {
+            let value =
+                ctx.from_value_at(value_handle, ctx.hash_entry_segment(key_handle, index))?;
+            entries.push((key_handle, value));
+        }
+
+        Ok(Self {
+            entries,
+            default_value: value.default_value(),
+        })
+    }
+}
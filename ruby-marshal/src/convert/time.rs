@@ -0,0 +1,122 @@
+use super::FromValue;
+use super::FromValueContext;
+use super::FromValueError;
+use crate::StringValue;
+use crate::SymbolValue;
+use crate::UserDefinedValue;
+use crate::Value;
+use alloc::vec::Vec;
+
+/// A Ruby `Time`, decoded from its `_dump` payload and instance variables.
+///
+/// The instant is always stored in UTC, with the offset alongside it.
+/// To build a `chrono::DateTime<FixedOffset>`, pass `utc_offset` to `FixedOffset::east_opt`
+/// and `seconds` and `nanoseconds` to `timestamp_opt`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RubyTime {
+    /// Seconds since the Unix epoch
+    pub seconds: i64,
+
+    /// Nanoseconds within the second
+    pub nanoseconds: u32,
+
+    /// Whether the time was in UTC mode
+    pub is_utc: bool,
+
+    /// The offset from UTC, in seconds.
+    ///
+    /// This is 0 for UTC times, and for local times dumped with a fractional offset.
+    pub utc_offset: i32,
+
+    /// The zone name, if Ruby knew it.
+    ///
+    /// Times created with a numeric offset have no zone name.
+    pub zone: Option<Vec<u8>>,
+}
+
+impl RubyTime {
+    /// Get the seconds since the Unix epoch, shifted into the time's own offset.
+    pub fn local_seconds(&self) -> i64 {
+        self.seconds + i64::from(self.utc_offset)
+    }
+}
+
+impl<'a> FromValue<'a> for RubyTime {
+    fn from_value(ctx: &FromValueContext<'a>, value: &'a Value) -> Result<Self, FromValueError> {
+        let value: &UserDefinedValue = FromValue::from_value(ctx, value)?;
+        let name: &SymbolValue = ctx.from_value(value.name().into())?;
+        if name.value() != b"Time" {
+            return Err(FromValueError::UnexpectedUserDefinedName {
+                name: name.value().to_vec(),
+            });
+        }
+
+        let data: [u8; 8] = value
+            .value()
+            .try_into()
+            .map_err(|_| FromValueError::new_other("Time payload is not 8 bytes"))?;
+        let p = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let s = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+
+        let (seconds, microseconds, is_utc) = if p & (1 << 31) == 0 {
+            // Ruby 1.8 and older wrote the raw seconds and microseconds.
+            (i64::from(p), s, false)
+        } else {
+            let year = 1900 + i64::from((p >> 14) & 0xffff);
+            let month = ((p >> 10) & 0xf) + 1;
+            let day = (p >> 5) & 0x1f;
+            let hour = p & 0x1f;
+            let minute = (s >> 26) & 0x3f;
+            let second = (s >> 20) & 0x3f;
+
+            let seconds = days_from_civil(year, month, day) * 86400
+                + i64::from(hour * 3600 + minute * 60 + second);
+            (seconds, s & 0xfffff, (p >> 30) & 1 == 1)
+        };
+
+        let mut nanoseconds = microseconds * 1000;
+        let nano_num = ctx.user_defined_instance_variable(value, b"nano_num");
+        let nano_den = ctx.user_defined_instance_variable(value, b"nano_den");
+        if let (Some(nano_num), Some(nano_den)) = (nano_num, nano_den) {
+            let nano_num: i32 = ctx.from_value(nano_num)?;
+            let nano_den: i32 = ctx.from_value(nano_den)?;
+            if nano_den > 0 {
+                nanoseconds += u32::try_from(nano_num / nano_den)
+                    .map_err(|_| FromValueError::IntegerOutOfRange)?;
+            }
+        }
+
+        // A fractional offset is dumped as a Rational, which is not supported.
+        let utc_offset = ctx
+            .user_defined_instance_variable(value, b"offset")
+            .and_then(|offset| ctx.from_value::<i32>(offset).ok());
+        let zone = match ctx.user_defined_instance_variable(value, b"zone") {
+            Some(zone) => ctx
+                .from_value::<Option<&StringValue>>(zone)?
+                .map(|zone| zone.value().to_vec()),
+            None => None,
+        };
+
+        Ok(Self {
+            seconds,
+            nanoseconds,
+            is_utc,
+            utc_offset: utc_offset.unwrap_or(0),
+            zone,
+        })
+    }
+}
+
+/// Get the number of days since the Unix epoch of a date in the proleptic Gregorian calendar.
+///
+/// The month is 1-based.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (i64::from(month) + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
@@ -1,13 +1,23 @@
+use crate::io;
+use crate::io::Write;
 use crate::Error;
+use crate::GenericValueArena;
+use crate::HashMap;
+use crate::HashSet;
+use crate::RandomState;
+use crate::SlotMapStorage;
+use crate::Source;
+use crate::Storage;
 use crate::SymbolValue;
 use crate::TypedValueHandle;
 use crate::VALUE_KIND_FLOAT;
 use crate::Value;
-use crate::ValueArena;
 use crate::ValueHandle;
 use crate::MAJOR_VERSION;
 use crate::MINOR_VERSION;
 use crate::VALUE_KIND_ARRAY;
+use crate::VALUE_KIND_BIGNUM;
+use crate::VALUE_KIND_EXTENDED;
 use crate::VALUE_KIND_FALSE;
 use crate::VALUE_KIND_FIXNUM;
 use crate::VALUE_KIND_HASH;
@@ -16,49 +26,276 @@ use crate::VALUE_KIND_INSTANCE_VARIABLES;
 use crate::VALUE_KIND_NIL;
 use crate::VALUE_KIND_OBJECT;
 use crate::VALUE_KIND_OBJECT_LINK;
+use crate::VALUE_KIND_REGEXP;
 use crate::VALUE_KIND_STRING;
+use crate::VALUE_KIND_STRUCT;
 use crate::VALUE_KIND_SYMBOL;
 use crate::VALUE_KIND_SYMBOL_LINK;
 use crate::VALUE_KIND_TRUE;
+use crate::VALUE_KIND_USER_CLASS;
 use crate::VALUE_KIND_USER_DEFINED;
+use crate::VALUE_KIND_USER_MARSHAL;
 use crate::VALUE_KIND_CLASS;
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use indexmap::IndexSet;
-use std::io::Write;
 
-/// A dumper for ruby data
-pub struct Dumper<'a, W> {
+/// Options for dumping.
+#[derive(Debug, Default, Clone)]
+pub struct DumpOptions {
+    /// The maximum number of bytes that may be written.
+    ///
+    /// Dumping fails with [`Error::DumpSizeLimitExceeded`] before writing anything past it.
+    ///
+    /// Defaults to unlimited.
+    pub max_bytes: Option<usize>,
+
+    /// Whether to link symbols by name instead of by handle.
+    ///
+    /// Values are written in pre-order: a value comes before its children,
+    /// array elements and hash entries are written in order with each key before its value,
+    /// a hash default comes after the entries,
+    /// and an object or user defined name comes before its instance variables.
+    /// A wrapped value writes its class name before its body, and its instance variables after.
+    /// As in Ruby, each symbol gets the next symbol table index the first time it is written.
+    ///
+    /// By default, distinct symbol values with the same name each get their own entry,
+    /// so an arena built with repeated calls to
+    /// [`create_symbol`](crate::GenericValueArena::create_symbol) is dumped differently from Ruby.
+    /// When set, every symbol with a name that was already written is written as a link,
+    /// which matches Ruby exactly.
+    ///
+    /// Defaults to false.
+    pub canonical_symbol_order: bool,
+
+    /// A filter for instance variables to leave out.
+    ///
+    /// This shrinks the output, but Ruby will load the values without the stripped variables.
+    /// Struct members are never stripped.
+    /// When set, values are never copied from the source of an arena, as the copy may contain
+    /// variables that should be stripped.
+    ///
+    /// Defaults to None.
+    pub strip_ivars: Option<IvarFilter>,
+}
+
+/// A check for whether to leave out an instance variable, for [`DumpOptions::strip_ivars`].
+#[derive(Clone)]
+pub struct IvarFilter(Arc<StripFn>);
+
+type StripFn = dyn Fn(&[u8], ValueHandle) -> bool + Send + Sync;
+
+impl IvarFilter {
+    /// Make a new [`IvarFilter`] from a function that gets the name and value of a variable,
+    /// and returns true to leave it out.
+    pub fn new<F>(should_strip: F) -> Self
+    where
+        F: Fn(&[u8], ValueHandle) -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(should_strip))
+    }
+
+    /// Check whether to leave out a variable.
+    pub fn should_strip(&self, name: &[u8], value: ValueHandle) -> bool {
+        (self.0)(name, value)
+    }
+}
+
+impl core::fmt::Debug for IvarFilter {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("IvarFilter(..)")
+    }
+}
+
+/// A dumper for ruby data.
+///
+/// Symbol and object links are shared across every value written with the same dumper.
+/// Link indices are assigned in the order values are written, never by iterating a map,
+/// so the output only depends on the arena.
+pub struct Dumper<'a, W, S = SlotMapStorage> {
     writer: W,
-    arena: &'a ValueArena,
+    arena: &'a GenericValueArena<S>,
+
+    symbol_links: IndexSet<TypedValueHandle<SymbolValue>, RandomState>,
+    object_links: IndexSet<ValueHandle, RandomState>,
+
+    /// The symbol table index of each symbol name, if symbols are linked by name.
+    symbol_names: Option<HashMap<&'a [u8], usize>>,
+
+    written: usize,
+    max_bytes: Option<usize>,
+    strip_ivars: Option<IvarFilter>,
+
+    source: Option<SourceState<'a>>,
+
+    /// Called with the handle and offset of each value as it starts being written.
+    on_node: Option<Box<dyn FnMut(ValueHandle, usize) + 'a>>,
+}
+
+/// The state needed to copy unmodified values from the source of an arena.
+struct SourceState<'a> {
+    source: &'a Source,
+
+    /// Values that were modified, or that reach a modified or new value.
+    tainted: HashSet<ValueHandle>,
+
+    /// The length of the prefix of the symbol table that matches the source.
+    matching_symbol_links: usize,
+
+    /// The length of the prefix of the object table that matches the source.
+    matching_object_links: usize,
+}
 
-    symbol_links: IndexSet<TypedValueHandle<SymbolValue>>,
-    object_links: IndexSet<ValueHandle>,
+impl<'a> SourceState<'a> {
+    /// Make a new [`SourceState`] for the values of an arena.
+    fn new<S>(arena: &'a GenericValueArena<S>, source: &'a Source) -> Self
+    where
+        S: Storage,
+    {
+        let mut parents: HashMap<ValueHandle, Vec<ValueHandle>> = HashMap::new();
+        let mut stack = Vec::new();
+        for (handle, value) in arena.iter() {
+            value.for_each_child(|child| parents.entry(child).or_default().push(handle));
+
+            // Symbols read as names have no span, but are copied along with their parent.
+            let untracked =
+                !matches!(value, Value::Symbol(_)) && !source.spans.contains_key(&handle);
+            if untracked || arena.is_dirty(handle) {
+                stack.push(handle);
+            }
+        }
+
+        let mut tainted = HashSet::new();
+        while let Some(handle) = stack.pop() {
+            if tainted.insert(handle) {
+                if let Some(parents) = parents.get(&handle) {
+                    stack.extend(parents.iter().copied());
+                }
+            }
+        }
+
+        Self {
+            source,
+            tainted,
+            matching_symbol_links: 0,
+            matching_object_links: 0,
+        }
+    }
 }
 
-impl<'a, W> Dumper<'a, W> {
+impl<'a, W, S> Dumper<'a, W, S>
+where
+    S: Storage,
+{
     /// Create a new [`Dumper`] from a writer and entry arena.
-    fn new(writer: W, arena: &'a ValueArena) -> Self {
+    pub fn new(writer: W, arena: &'a GenericValueArena<S>) -> Self {
+        Self::with_options(writer, arena, &DumpOptions::default())
+    }
+
+    /// Create a new [`Dumper`] from a writer and entry arena, with options.
+    pub fn with_options(writer: W, arena: &'a GenericValueArena<S>, options: &DumpOptions) -> Self {
         Self {
             writer,
             arena,
-            symbol_links: IndexSet::new(),
-            object_links: IndexSet::new(),
+            symbol_links: IndexSet::default(),
+            object_links: IndexSet::default(),
+            symbol_names: options.canonical_symbol_order.then(HashMap::new),
+            written: 0,
+            max_bytes: options.max_bytes,
+            strip_ivars: options.strip_ivars.clone(),
+            source: arena
+                .source()
+                .filter(|_| options.strip_ivars.is_none())
+                .map(|source| SourceState::new(arena, source)),
+            on_node: None,
+        }
+    }
+
+    /// Set a function to call with the handle of each value as it starts being written,
+    /// and the number of bytes written before it.
+    ///
+    /// This includes links and names.
+    /// Values inside bytes copied from the source of an arena are not reported.
+    pub fn set_on_node<F>(&mut self, on_node: F)
+    where
+        F: FnMut(ValueHandle, usize) + 'a,
+    {
+        self.on_node = Some(Box::new(on_node));
+    }
+
+    /// Get the inner writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Get the number of bytes written so far.
+    pub fn bytes_written(&self) -> usize {
+        self.written
+    }
+
+    /// Record the name of a symbol that was added to the symbol table at the given index.
+    fn add_symbol_name(&mut self, index: usize, handle: TypedValueHandle<SymbolValue>) {
+        if let Some(symbol_names) = self.symbol_names.as_mut() {
+            if let Some(symbol) = self.arena.get_symbol(handle) {
+                symbol_names.entry(symbol.value()).or_insert(index);
+            }
+        }
+    }
+
+    /// Record that a symbol was added to the symbol table at the given index.
+    fn add_symbol_link(&mut self, index: usize, handle: TypedValueHandle<SymbolValue>) {
+        self.add_symbol_name(index, handle);
+        if let Some(state) = self.source.as_mut() {
+            if state.matching_symbol_links == index
+                && state.source.symbol_links.get(index) == Some(&handle)
+            {
+                state.matching_symbol_links += 1;
+            }
+        }
+    }
+
+    /// Record that an object was added to the object table at the given index.
+    fn add_object_link(&mut self, index: usize, handle: ValueHandle) {
+        if let Some(state) = self.source.as_mut() {
+            if state.matching_object_links == index
+                && state.source.object_links.get(index) == Some(&handle)
+            {
+                state.matching_object_links += 1;
+            }
         }
     }
 }
 
-impl<'a, W> Dumper<'a, W>
+impl<'a, W, S> Dumper<'a, W, S>
 where
     W: Write,
+    S: Storage,
 {
     /// Write the header
-    fn write_header(&mut self) -> Result<(), Error> {
-        self.writer.write_all(&[MAJOR_VERSION, MINOR_VERSION])?;
+    pub fn write_header(&mut self) -> Result<(), Error> {
+        self.write_all(&[MAJOR_VERSION, MINOR_VERSION])?;
+        Ok(())
+    }
+
+    /// Write bytes, failing if that would exceed the size limit.
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let written = self.written + bytes.len();
+        if let Some(limit) = self.max_bytes {
+            if written > limit {
+                return Err(Error::DumpSizeLimitExceeded { limit });
+            }
+        }
+        io::write_all(&mut self.writer, bytes)?;
+        self.written = written;
+
         Ok(())
     }
 
     /// Write a byte
     fn write_byte(&mut self, byte: u8) -> Result<(), Error> {
-        self.writer.write_all(std::slice::from_ref(&byte))?;
+        self.write_all(core::slice::from_ref(&byte))?;
         Ok(())
     }
 
@@ -70,7 +307,7 @@ where
             i32::try_from(value.len()).map_err(|error| Error::USizeInvalidFixnum { error })?;
 
         self.write_fixnum(len)?;
-        self.writer.write_all(value)?;
+        self.write_all(value)?;
 
         Ok(())
     }
@@ -78,25 +315,25 @@ where
     /// Write a Fixnum
     fn write_fixnum(&mut self, mut value: i32) -> Result<(), Error> {
         if value == 0 {
-            self.writer.write_all(&[0])?;
+            self.write_all(&[0])?;
             return Ok(());
         }
 
         if value > 0 && value < 123 {
             let value = u8::try_from(value).unwrap();
-            self.writer.write_all(&[value + 5])?;
+            self.write_all(&[value + 5])?;
             return Ok(());
         }
 
         if value < 0 && value > -124 {
             let value = u8::try_from((value - 5) & 0xFF).unwrap();
-            self.writer.write_all(&[value])?;
+            self.write_all(&[value])?;
             return Ok(());
         }
 
-        let mut buffer = [0; std::mem::size_of::<i32>() + 1];
+        let mut buffer = [0; core::mem::size_of::<i32>() + 1];
         let mut buffer_size = 0;
-        for i in 1..(std::mem::size_of::<i32>() + 1) {
+        for i in 1..(core::mem::size_of::<i32>() + 1) {
             buffer[i] = u8::try_from(value & 0xFF).unwrap();
             buffer_size = i + 1;
 
@@ -110,13 +347,13 @@ where
                 break;
             }
         }
-        self.writer.write_all(&buffer[..buffer_size])?;
+        self.write_all(&buffer[..buffer_size])?;
 
         Ok(())
     }
 
     /// Write a Float
-    fn write_float(&mut self, mut value: f64) -> Result<(), Error> {
+    fn write_float(&mut self, value: f64) -> Result<(), Error> {
         if value.is_nan() {
             self.write_byte_string("nan".as_bytes())?;
             return Ok(())
@@ -147,17 +384,72 @@ where
             self.write_value_object_link(index)?;
             return Ok(true);
         }
+        self.add_object_link(index, handle);
 
         Ok(false)
     }
 
+    /// Try to copy the bytes of a value verbatim from the source of the arena, if possible.
+    ///
+    /// This is only possible if the value and everything it reaches are unmodified,
+    /// and the symbol and object tables written so far match the source,
+    /// so that any links in the copied bytes still refer to the same values.
+    ///
+    /// # Returns
+    /// Returns true if successful.
+    fn try_write_value_source(&mut self, handle: ValueHandle) -> Result<bool, Error> {
+        let state = match self.source.as_ref() {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        let source = state.source;
+        let span = match source.spans.get(&handle) {
+            Some(span) => span,
+            None => return Ok(false),
+        };
+
+        let in_sync = state.matching_symbol_links == self.symbol_links.len()
+            && state.matching_object_links == self.object_links.len()
+            && span.symbol_links.start == self.symbol_links.len()
+            && span.object_links.start == self.object_links.len();
+        if !in_sync || state.tainted.contains(&handle) {
+            return Ok(false);
+        }
+
+        self.write_all(&source.bytes[span.bytes.clone()])?;
+
+        self.symbol_links.extend(
+            source.symbol_links[span.symbol_links.clone()]
+                .iter()
+                .copied(),
+        );
+        self.object_links.extend(
+            source.object_links[span.object_links.clone()]
+                .iter()
+                .copied(),
+        );
+        let state = self.source.as_mut().expect("missing source state");
+        state.matching_symbol_links = span.symbol_links.end;
+        state.matching_object_links = span.object_links.end;
+
+        for index in span.symbol_links.clone() {
+            self.add_symbol_name(index, source.symbol_links[index]);
+        }
+
+        Ok(true)
+    }
+
     /// Write a value that is either a symbol or a symbol link.
     fn write_value_symbol_like(
         &mut self,
         handle: TypedValueHandle<SymbolValue>,
         value: &SymbolValue,
     ) -> Result<(), Error> {
-        match self.symbol_links.get_index_of(&handle) {
+        let index = match self.symbol_names.as_ref() {
+            Some(symbol_names) => symbol_names.get(value.value()).copied(),
+            None => self.symbol_links.get_index_of(&handle),
+        };
+        match index {
             Some(index) => {
                 let index =
                     i32::try_from(index).map_err(|error| Error::USizeInvalidFixnum { error })?;
@@ -166,10 +458,20 @@ where
                 self.write_fixnum(index)?;
             }
             None => {
-                self.symbol_links.insert(handle);
+                let instance_variables = value.instance_variables();
+                if instance_variables.is_some() {
+                    self.write_byte(VALUE_KIND_INSTANCE_VARIABLES)?;
+                }
+
+                let (index, _) = self.symbol_links.insert_full(handle);
+                self.add_symbol_link(index, handle);
 
                 self.write_byte(VALUE_KIND_SYMBOL)?;
                 self.write_byte_string(value.value())?;
+
+                if let Some(instance_variables) = instance_variables {
+                    self.write_instance_variables(instance_variables)?;
+                }
             }
         }
 
@@ -186,16 +488,39 @@ where
         Ok(())
     }
 
-    /// Write instance variables
+    /// Write instance variables, leaving out any that should be stripped.
     fn write_instance_variables(
         &mut self,
         instance_variables: &[(TypedValueHandle<SymbolValue>, ValueHandle)],
     ) -> Result<(), Error> {
-        let num_vars = i32::try_from(instance_variables.len())
-            .map_err(|error| Error::USizeInvalidFixnum { error })?;
+        let Some(strip_ivars) = self.strip_ivars.clone() else {
+            return self.write_pairs(instance_variables);
+        };
+
+        let instance_variables: Vec<_> = instance_variables
+            .iter()
+            .copied()
+            .filter(|(name, value)| {
+                let name = self
+                    .arena
+                    .get_symbol(*name)
+                    .map_or(&[][..], |name| name.value());
+                !strip_ivars.should_strip(name, *value)
+            })
+            .collect();
+        self.write_pairs(&instance_variables)
+    }
+
+    /// Write a count, then pairs of names and values.
+    fn write_pairs(
+        &mut self,
+        pairs: &[(TypedValueHandle<SymbolValue>, ValueHandle)],
+    ) -> Result<(), Error> {
+        let num_vars =
+            i32::try_from(pairs.len()).map_err(|error| Error::USizeInvalidFixnum { error })?;
         self.write_fixnum(num_vars)?;
 
-        for (name, value) in instance_variables.iter() {
+        for (name, value) in pairs.iter() {
             self.write_value((*name).into())?;
             self.write_value(*value)?;
         }
@@ -203,8 +528,44 @@ where
         Ok(())
     }
 
-    /// Write a value
-    fn write_value(&mut self, handle: ValueHandle) -> Result<(), Error> {
+    /// Write the user class wrapper, if there is a user class.
+    fn write_user_class(
+        &mut self,
+        user_class: Option<TypedValueHandle<SymbolValue>>,
+    ) -> Result<(), Error> {
+        if let Some(user_class) = user_class {
+            self.write_byte(VALUE_KIND_USER_CLASS)?;
+            self.write_value(user_class.into())?;
+        }
+
+        Ok(())
+    }
+
+    /// Write an extended wrapper for each module the value was extended with.
+    fn write_extended_modules(
+        &mut self,
+        extended_modules: &[TypedValueHandle<SymbolValue>],
+    ) -> Result<(), Error> {
+        for module in extended_modules.iter() {
+            self.write_byte(VALUE_KIND_EXTENDED)?;
+            self.write_value((*module).into())?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a value.
+    ///
+    /// Values that were already written by this dumper are written as links.
+    pub fn write_value(&mut self, handle: ValueHandle) -> Result<(), Error> {
+        if let Some(on_node) = self.on_node.as_mut() {
+            on_node(handle, self.written);
+        }
+
+        if self.try_write_value_source(handle)? {
+            return Ok(());
+        }
+
         let value = self
             .arena
             .get(handle)
@@ -223,6 +584,23 @@ where
                 self.write_byte(VALUE_KIND_FIXNUM)?;
                 self.write_fixnum(value.value())?;
             }
+            Value::Bignum(value) => {
+                if self.try_write_value_object_link(handle)? {
+                    return Ok(());
+                }
+
+                let magnitude = value.magnitude();
+                let len = i32::try_from(magnitude.len().div_ceil(2))
+                    .map_err(|error| Error::USizeInvalidFixnum { error })?;
+
+                self.write_byte(VALUE_KIND_BIGNUM)?;
+                self.write_byte(if value.is_positive() { b'+' } else { b'-' })?;
+                self.write_fixnum(len)?;
+                self.write_all(magnitude)?;
+                if magnitude.len() % 2 != 0 {
+                    self.write_byte(0)?;
+                }
+            }
             Value::Float(value) => {
                 if self.try_write_value_object_link(handle)? {
                     return Ok(());
@@ -243,11 +621,22 @@ where
                 let len = i32::try_from(value.len())
                     .map_err(|error| Error::USizeInvalidFixnum { error })?;
 
+                let instance_variables = value.instance_variables();
+                if instance_variables.is_some() {
+                    self.write_byte(VALUE_KIND_INSTANCE_VARIABLES)?;
+                }
+                self.write_extended_modules(value.extended_modules())?;
+                self.write_user_class(value.user_class())?;
+
                 self.write_byte(VALUE_KIND_ARRAY)?;
                 self.write_fixnum(len)?;
                 for value in value.value().iter() {
                     self.write_value(*value)?;
                 }
+
+                if let Some(instance_variables) = instance_variables {
+                    self.write_instance_variables(instance_variables)?;
+                }
             }
             Value::Hash(value) => {
                 if self.try_write_value_object_link(handle)? {
@@ -255,6 +644,14 @@ where
                 }
 
                 let default_value = value.default_value();
+                let instance_variables = value.instance_variables();
+                if instance_variables.is_some() {
+                    self.write_byte(VALUE_KIND_INSTANCE_VARIABLES)?;
+                }
+                self.write_extended_modules(value.extended_modules())?;
+                self.write_user_class(value.user_class())?;
+                self.write_user_class(value.identity_class())?;
+
                 let value = value.value();
 
                 if default_value.is_some() {
@@ -275,34 +672,68 @@ where
                 if let Some(default_value) = default_value {
                     self.write_value(default_value)?;
                 }
+
+                if let Some(instance_variables) = instance_variables {
+                    self.write_instance_variables(instance_variables)?;
+                }
             }
             Value::Object(value) => {
                 if self.try_write_value_object_link(handle)? {
                     return Ok(());
                 }
 
+                self.write_extended_modules(value.extended_modules())?;
                 self.write_byte(VALUE_KIND_OBJECT)?;
                 self.write_value(value.name().into())?;
                 self.write_instance_variables(value.instance_variables())?;
             }
+            Value::Struct(value) => {
+                if self.try_write_value_object_link(handle)? {
+                    return Ok(());
+                }
+
+                self.write_extended_modules(value.extended_modules())?;
+                self.write_byte(VALUE_KIND_STRUCT)?;
+                self.write_value(value.name().into())?;
+                self.write_pairs(value.members())?;
+            }
             Value::String(value) => {
                 if self.try_write_value_object_link(handle)? {
                     return Ok(());
                 }
 
-                match value.instance_variables() {
-                    Some(instance_variables) => {
-                        self.write_byte(VALUE_KIND_INSTANCE_VARIABLES)?;
+                let instance_variables = value.instance_variables();
+                if instance_variables.is_some() {
+                    self.write_byte(VALUE_KIND_INSTANCE_VARIABLES)?;
+                }
+                self.write_extended_modules(value.extended_modules())?;
+                self.write_user_class(value.user_class())?;
 
-                        self.write_byte(VALUE_KIND_STRING)?;
-                        self.write_byte_string(value.value())?;
+                self.write_byte(VALUE_KIND_STRING)?;
+                self.write_byte_string(value.value())?;
 
-                        self.write_instance_variables(instance_variables)?;
-                    }
-                    None => {
-                        self.write_byte(VALUE_KIND_STRING)?;
-                        self.write_byte_string(value.value())?;
-                    }
+                if let Some(instance_variables) = instance_variables {
+                    self.write_instance_variables(instance_variables)?;
+                }
+            }
+            Value::Regexp(value) => {
+                if self.try_write_value_object_link(handle)? {
+                    return Ok(());
+                }
+
+                let instance_variables = value.instance_variables();
+                if instance_variables.is_some() {
+                    self.write_byte(VALUE_KIND_INSTANCE_VARIABLES)?;
+                }
+                self.write_extended_modules(value.extended_modules())?;
+                self.write_user_class(value.user_class())?;
+
+                self.write_byte(VALUE_KIND_REGEXP)?;
+                self.write_byte_string(value.source())?;
+                self.write_byte(value.raw_options())?;
+
+                if let Some(instance_variables) = instance_variables {
+                    self.write_instance_variables(instance_variables)?;
                 }
             }
             Value::UserDefined(value) => {
@@ -313,6 +744,7 @@ where
                 match value.instance_variables() {
                     Some(instance_variables) => {
                         self.write_byte(VALUE_KIND_INSTANCE_VARIABLES)?;
+                        self.write_extended_modules(value.extended_modules())?;
 
                         self.write_byte(VALUE_KIND_USER_DEFINED)?;
                         self.write_value(value.name().into())?;
@@ -321,12 +753,23 @@ where
                         self.write_instance_variables(instance_variables)?;
                     }
                     None => {
+                        self.write_extended_modules(value.extended_modules())?;
                         self.write_byte(VALUE_KIND_USER_DEFINED)?;
                         self.write_value(value.name().into())?;
                         self.write_byte_string(value.value())?;
                     }
                 }
             }
+            Value::UserMarshal(value) => {
+                if self.try_write_value_object_link(handle)? {
+                    return Ok(());
+                }
+
+                self.write_extended_modules(value.extended_modules())?;
+                self.write_byte(VALUE_KIND_USER_MARSHAL)?;
+                self.write_value(value.name().into())?;
+                self.write_value(value.value())?;
+            }
             Value::Class(value) => {
                 if self.try_write_value_object_link(handle)? {
                     return Ok(());
@@ -343,6 +786,11 @@ where
     /// Dump the root node to the writer.
     fn dump(&mut self) -> Result<(), Error> {
         self.write_header()?;
+        self.dump_headerless()
+    }
+
+    /// Dump the root node to the writer, without writing a header.
+    fn dump_headerless(&mut self) -> Result<(), Error> {
         self.write_value(self.arena.root())?;
 
         Ok(())
@@ -350,11 +798,421 @@ where
 }
 
 /// Dump to a writer.
-pub fn dump<W>(writer: W, value_arena: &ValueArena) -> Result<(), Error>
+///
+/// If the arena was loaded with [`LoadOptions::preserve_source`](crate::LoadOptions),
+/// the bytes of unmodified values are copied from the source where possible.
+pub fn dump<W, S>(writer: W, value_arena: &GenericValueArena<S>) -> Result<(), Error>
 where
     W: Write,
+    S: Storage,
 {
     let mut dumper = Dumper::new(writer, value_arena);
     dumper.dump()?;
     Ok(())
 }
+
+/// Dump to a writer, with options.
+pub fn dump_with_options<W, S>(
+    writer: W,
+    value_arena: &GenericValueArena<S>,
+    options: &DumpOptions,
+) -> Result<(), Error>
+where
+    W: Write,
+    S: Storage,
+{
+    let mut dumper = Dumper::with_options(writer, value_arena, options);
+    dumper.dump()?;
+    Ok(())
+}
+
+/// Dump several values to a writer, one after another.
+///
+/// Each value is written as a standalone document with its own header and link tables,
+/// like repeated calls to Ruby's `Marshal.dump` on the same file.
+/// Read them back by calling [`load_and_recover`](crate::load_and_recover)
+/// until the reader is empty.
+pub fn dump_many<'a, W, S, I>(mut writer: W, items: I) -> Result<(), Error>
+where
+    W: Write,
+    S: Storage + 'a,
+    I: IntoIterator<Item = (&'a GenericValueArena<S>, ValueHandle)>,
+{
+    for (value_arena, handle) in items {
+        let mut dumper = Dumper::new(&mut writer, value_arena);
+        dumper.write_header()?;
+        dumper.write_value(handle)?;
+    }
+    Ok(())
+}
+
+/// Dump to a writer, without writing a header.
+///
+/// This is non-standard, and intended for formats that embed Marshal values without the header.
+/// The output cannot be read by Ruby's `Marshal.load`; read it with
+/// [`load_headerless`](crate::load_headerless).
+pub fn dump_headerless<W, S>(writer: W, value_arena: &GenericValueArena<S>) -> Result<(), Error>
+where
+    W: Write,
+    S: Storage,
+{
+    let mut dumper = Dumper::new(writer, value_arena);
+    dumper.dump_headerless()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::load;
+    use crate::load_and_recover;
+    use crate::load_with_options;
+    use crate::LoadOptions;
+    use crate::ObjectValue;
+    use crate::ValueArena;
+
+    #[test]
+    fn repeated_user_defined_is_object_link() {
+        let mut arena = ValueArena::new();
+        let name = arena.create_symbol("Person".into());
+        let user_defined = arena.create_user_defined(name, "Bob".into()).into_raw();
+        let array = arena.create_array(vec![user_defined, user_defined]);
+        arena.replace_root(array);
+
+        let mut data = Vec::new();
+        dump(&mut data, &arena).expect("failed to dump");
+        assert!(data == b"\x04\x08[\x07u:\x0bPerson\x08Bob@\x06");
+
+        let arena = load(&*data).expect("failed to load");
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(data == new_data, "{data:?} != {new_data:?}");
+    }
+
+    #[test]
+    fn set_user_defined_value() {
+        let data = b"\x04\x08[\x07u:\x0bPerson\x08Bob@\x06";
+        let options = LoadOptions {
+            preserve_source: true,
+            ..LoadOptions::default()
+        };
+        let mut arena = load_with_options(&data[..], &options).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+        let user_defined = TypedValueHandle::new_unchecked(array.value()[0]);
+
+        let old = arena.set_user_defined_value(user_defined, "Robert".into());
+        assert!(old.as_deref() == Some(&b"Bob"[..]));
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(new_data == b"\x04\x08[\x07u:\x0bPerson\x0bRobert@\x06");
+    }
+
+    #[test]
+    fn string_ivar_wrapper() {
+        // Marshal.dump("ab".b)
+        let binary = b"\x04\x08\"\x07ab";
+        // Marshal.dump("ab")
+        let utf8 = b"\x04\x08I\"\x07ab\x06:\x06ET";
+
+        for data in [&binary[..], &utf8[..]] {
+            let arena = load(data).expect("failed to load");
+            let mut new_data = Vec::new();
+            dump(&mut new_data, &arena).expect("failed to dump");
+            assert!(data == new_data, "{data:?} != {new_data:?}");
+        }
+
+        // Only strings with instance variables are wrapped.
+        let mut arena = ValueArena::new();
+        let string = arena.create_string("ab".into());
+        arena.replace_root(string);
+        let mut data = Vec::new();
+        dump(&mut data, &arena).expect("failed to dump");
+        assert!(data == binary);
+
+        let encoding = arena.create_symbol("E".into());
+        let encoding_value = arena.create_bool(true).into();
+        match arena.get_mut(string) {
+            Some(Value::String(value)) => {
+                value.set_instance_variables(Some(vec![(encoding, encoding_value)]));
+            }
+            _ => panic!("not a string"),
+        }
+        let mut data = Vec::new();
+        dump(&mut data, &arena).expect("failed to dump");
+        assert!(data == utf8);
+    }
+
+    #[test]
+    fn canonical_symbol_order() {
+        // Two UTF-8 strings, each made with its own E symbol.
+        let mut arena = ValueArena::new();
+        let mut strings = Vec::new();
+        for value in ["a", "b"] {
+            let string = arena.create_string(value.into());
+            let encoding = arena.create_symbol("E".into());
+            let encoding_value = arena.create_bool(true).into();
+            match arena.get_mut(string) {
+                Some(Value::String(value)) => {
+                    value.set_instance_variables(Some(vec![(encoding, encoding_value)]));
+                }
+                _ => panic!("not a string"),
+            }
+            strings.push(string.into_raw());
+        }
+        let array = arena.create_array(strings);
+        arena.replace_root(array);
+
+        // Marshal.dump(["a", "b"])
+        let expected = b"\x04\x08[\x07I\"\x06a\x06:\x06ETI\"\x06b\x06;\x00T";
+
+        let mut data = Vec::new();
+        dump(&mut data, &arena).expect("failed to dump");
+        assert!(data != expected);
+
+        let options = DumpOptions {
+            canonical_symbol_order: true,
+            ..DumpOptions::default()
+        };
+        let mut data = Vec::new();
+        dump_with_options(&mut data, &arena, &options).expect("failed to dump");
+        assert!(data == expected, "{data:?}");
+    }
+
+    #[test]
+    fn strip_ivars() {
+        // Marshal.dump(Foo.new), with @a = 1 and @cached = 2
+        let data = b"\x04\x08o:\x08Foo\x07:\x07@ai\x06:\x0c@cachedi\x07";
+        let options = LoadOptions {
+            preserve_source: true,
+            ..LoadOptions::default()
+        };
+        let arena = load_with_options(&data[..], &options).expect("failed to load");
+
+        let options = DumpOptions {
+            strip_ivars: Some(IvarFilter::new(|name, _| name == b"@cached")),
+            ..DumpOptions::default()
+        };
+        let mut new_data = Vec::new();
+        dump_with_options(&mut new_data, &arena, &options).expect("failed to dump");
+        let expected = b"\x04\x08o:\x08Foo\x06:\x07@ai\x06";
+        assert!(new_data == expected, "{new_data:?}");
+
+        let arena = load(&new_data[..]).expect("failed to load");
+        let object = arena[arena.root()].as_object().expect("not an object");
+        assert!(object.instance_variables().len() == 1);
+    }
+
+    #[test]
+    fn max_bytes() {
+        let mut arena = ValueArena::new();
+        let strings = (0..1000)
+            .map(|_| arena.create_string(vec![b'a'; 100]).into_raw())
+            .collect();
+        let array = arena.create_array(strings);
+        arena.replace_root(array);
+
+        let options = DumpOptions {
+            max_bytes: Some(1024),
+            ..DumpOptions::default()
+        };
+        let mut data = Vec::new();
+        let error =
+            dump_with_options(&mut data, &arena, &options).expect_err("dumped over the limit");
+        assert!(matches!(
+            error,
+            Error::DumpSizeLimitExceeded { limit: 1024 }
+        ));
+        assert!(data.len() <= 1024);
+
+        let mut data = Vec::new();
+        dump(&mut data, &arena).expect("failed to dump");
+        let options = DumpOptions {
+            max_bytes: Some(data.len()),
+            ..DumpOptions::default()
+        };
+        dump_with_options(Vec::new(), &arena, &options).expect("failed to dump at the limit");
+    }
+
+    #[test]
+    fn nan_round_trip() {
+        // Marshal.dump([Float::NAN, Float::INFINITY, -Float::INFINITY])
+        let data = b"\x04\x08[\x08f\x08nanf\x08inff\x09-inf";
+        let arena = load(&data[..]).expect("failed to load");
+
+        // NaN never equals itself, so compare bytes.
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+
+        // Every NaN payload is written the same way.
+        let mut arena = ValueArena::new();
+        for bits in [
+            0x7FF8_0000_0000_0000,
+            0x7FF4_0000_0000_0001,
+            0xFFF8_0000_0000_0000,
+        ] {
+            let float = arena.create_float(f64::from_bits(bits));
+            arena.replace_root(float);
+
+            let mut new_data = Vec::new();
+            dump(&mut new_data, &arena).expect("failed to dump");
+            assert!(new_data == b"\x04\x08f\x08nan", "{new_data:?}");
+        }
+    }
+
+    #[test]
+    fn dumper_matches_dump() {
+        // Marshal.dump([:a, "b", :a])
+        let data = b"\x04\x08[\x08:\x06a\"\x06b;\x00";
+        let arena = load(&data[..]).expect("failed to load");
+
+        let mut dumper = Dumper::new(Vec::new(), &arena);
+        dumper.write_header().expect("failed to write header");
+        dumper
+            .write_value(arena.root())
+            .expect("failed to write value");
+        let new_data = dumper.into_inner();
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+
+        let mut expected = Vec::new();
+        dump(&mut expected, &arena).expect("failed to dump");
+        assert!(expected == new_data);
+
+        // Writing the same value again links to the first copy.
+        let mut dumper = Dumper::new(Vec::new(), &arena);
+        dumper
+            .write_value(arena.root())
+            .expect("failed to write value");
+        dumper
+            .write_value(arena.root())
+            .expect("failed to write value");
+        assert!(dumper.into_inner() == b"[\x08:\x06a\"\x06b;\x00@\x00");
+    }
+
+    #[test]
+    fn preserve_source_reuses_unmodified_spans() {
+        // An object with @a = [1.5] and @b = 1, where the float is written as "1.50".
+        let data = b"\x04\x08o:\x08Foo\x07:\x07@a[\x06f\x091.50:\x07@bi\x06";
+        let options = LoadOptions {
+            preserve_source: true,
+            ..LoadOptions::default()
+        };
+
+        let mut arena = load_with_options(&data[..], &options).expect("failed to load");
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+
+        let root = arena.root();
+        let object = arena[root].as_object().expect("not an object");
+        let name = object.name();
+        let mut instance_variables = object.instance_variables().to_vec();
+        instance_variables[1].1 = arena.create_fixnum(2).into();
+        *arena.get_mut(root).expect("missing root") =
+            ObjectValue::new(name, instance_variables).into();
+
+        // The array is copied, keeping the original float text.
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(new_data == b"\x04\x08o:\x08Foo\x07:\x07@a[\x06f\x091.50:\x07@bi\x07");
+
+        let arena = load(&data[..]).expect("failed to load");
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(new_data == b"\x04\x08o:\x08Foo\x07:\x07@a[\x06f\x081.5:\x07@bi\x06");
+    }
+
+    #[test]
+    fn dump_many_documents() {
+        // ["a", "a"] with a shared string
+        let data = b"\x04\x08[\x07\"\x06a@\x06";
+        let array_arena = load(&data[..]).expect("failed to load");
+        let array = array_arena[array_arena.root()]
+            .as_array()
+            .expect("not an array");
+        let string = array.value()[0];
+
+        let mut fixnum_arena = ValueArena::new();
+        let fixnum = fixnum_arena.create_fixnum(42).into_raw();
+
+        let mut dumped = Vec::new();
+        dump_many(
+            &mut dumped,
+            [
+                (&array_arena, array_arena.root()),
+                (&array_arena, string),
+                (&fixnum_arena, fixnum),
+            ],
+        )
+        .expect("failed to dump");
+        assert!(dumped.starts_with(data));
+
+        let (arena, reader) = load_and_recover(&dumped[..]).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+        assert!(array.len() == 2);
+        assert!(array.value()[0] == array.value()[1]);
+        let (arena, reader) = load_and_recover(reader).expect("failed to load");
+        assert!(arena.get_ref(arena.root()) == &b"a"[..]);
+        let (arena, reader) = load_and_recover(reader).expect("failed to load");
+        assert!(arena.get_ref(arena.root()) == 42);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn on_node_offsets() {
+        // Marshal.dump([:a, "b"])
+        let data = b"\x04\x08[\x07:\x06a\"\x06b";
+        let arena = load(&data[..]).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+
+        let mut offsets = Vec::new();
+        let mut dumper = Dumper::new(Vec::new(), &arena);
+        dumper.set_on_node(|handle, offset| offsets.push((handle, offset)));
+        dumper.write_header().expect("failed to write header");
+        dumper.write_value(arena.root()).expect("failed to dump");
+        assert!(dumper.into_inner() == data);
+
+        let expected = [
+            (arena.root(), 2),
+            (array.value()[0], 4),
+            (array.value()[1], 7),
+        ];
+        assert!(offsets == expected);
+    }
+
+    #[test]
+    fn deterministic_output() {
+        // Enough distinct symbols and shared arrays that a map order would show.
+        let mut arena = ValueArena::new();
+        let mut pairs = Vec::new();
+        for i in 0..64 {
+            let symbol = arena.create_symbol(format!("s{i}").into()).into_raw();
+            let string = arena.create_string(format!("v{i}").into()).into_raw();
+            pairs.push(arena.create_array(vec![symbol, string]).into_raw());
+        }
+        let elements = pairs.iter().chain(pairs.iter()).copied().collect();
+        let root = arena.create_array(elements);
+        arena.replace_root(root);
+
+        for options in [
+            DumpOptions::default(),
+            DumpOptions {
+                canonical_symbol_order: true,
+                ..DumpOptions::default()
+            },
+        ] {
+            let mut first = Vec::new();
+            dump_with_options(&mut first, &arena, &options).expect("failed to dump");
+            let mut second = Vec::new();
+            dump_with_options(&mut second, &arena, &options).expect("failed to dump");
+            assert!(first == second);
+
+            // A separately loaded copy has different handles, but must dump the same.
+            let copy = load(&first[..]).expect("failed to load");
+            let mut third = Vec::new();
+            dump_with_options(&mut third, &copy, &options).expect("failed to dump");
+            assert!(first == third);
+        }
+    }
+}
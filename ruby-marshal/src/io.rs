@@ -0,0 +1,177 @@
+//! The reader and writer traits used for loading and dumping.
+//!
+//! With the `std` feature, these are `std::io::Read` and `std::io::Write`.
+//! Without it, they are minimal traits implemented for byte slices and `Vec<u8>`,
+//! which other readers and writers can implement as well.
+
+use crate::Error;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+pub use std::io::Read;
+#[cfg(feature = "std")]
+pub use std::io::Write;
+
+#[cfg(feature = "std")]
+pub(crate) use std::io::sink;
+
+/// A source of bytes to load from.
+#[cfg(not(feature = "std"))]
+pub trait Read {
+    /// Read some bytes into `buf`, returning how many were read.
+    ///
+    /// This only returns 0 at the end of the input, or if `buf` is empty.
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+}
+
+#[cfg(not(feature = "std"))]
+impl Read for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.len());
+        let (head, tail) = self.split_at(len);
+        buf[..len].copy_from_slice(head);
+        *self = tail;
+
+        len
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<R> Read for &mut R
+where
+    R: Read + ?Sized,
+{
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        (**self).read(buf)
+    }
+}
+
+/// A destination for dumped bytes.
+///
+/// Writes cannot fail, but [`DumpOptions::max_bytes`](crate::DumpOptions::max_bytes)
+/// can bound how much is written.
+#[cfg(not(feature = "std"))]
+pub trait Write {
+    /// Write all of `buf`.
+    fn write_all(&mut self, buf: &[u8]);
+}
+
+#[cfg(not(feature = "std"))]
+impl Write for Vec<u8> {
+    fn write_all(&mut self, buf: &[u8]) {
+        self.extend_from_slice(buf);
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<W> Write for &mut W
+where
+    W: Write + ?Sized,
+{
+    fn write_all(&mut self, buf: &[u8]) {
+        (**self).write_all(buf);
+    }
+}
+
+/// A writer that drops everything written to it.
+#[cfg(not(feature = "std"))]
+pub(crate) struct Sink;
+
+#[cfg(not(feature = "std"))]
+impl Write for Sink {
+    fn write_all(&mut self, _buf: &[u8]) {}
+}
+
+/// Make a writer that drops everything written to it.
+#[cfg(not(feature = "std"))]
+pub(crate) fn sink() -> Sink {
+    Sink
+}
+
+/// Why a reader could not fill a buffer.
+#[derive(Debug)]
+pub(crate) enum ReadError {
+    /// The input ended first.
+    UnexpectedEof,
+
+    /// The reader failed.
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+}
+
+/// Fill `buf` from a reader.
+#[cfg(feature = "std")]
+pub(crate) fn read_exact<R>(reader: &mut R, buf: &mut [u8]) -> Result<(), ReadError>
+where
+    R: Read + ?Sized,
+{
+    reader.read_exact(buf).map_err(|error| {
+        if error.kind() == std::io::ErrorKind::UnexpectedEof {
+            return ReadError::UnexpectedEof;
+        }
+        ReadError::Io(error)
+    })
+}
+
+/// Fill `buf` from a reader.
+#[cfg(not(feature = "std"))]
+pub(crate) fn read_exact<R>(reader: &mut R, mut buf: &mut [u8]) -> Result<(), ReadError>
+where
+    R: Read + ?Sized,
+{
+    while !buf.is_empty() {
+        let len = reader.read(buf);
+        if len == 0 {
+            return Err(ReadError::UnexpectedEof);
+        }
+        buf = &mut buf[len..];
+    }
+
+    Ok(())
+}
+
+/// Read some bytes into `buf`, returning how many were read, or 0 at the end of the input.
+#[cfg(feature = "std")]
+pub(crate) fn read<R>(reader: &mut R, buf: &mut [u8]) -> Result<usize, Error>
+where
+    R: Read + ?Sized,
+{
+    loop {
+        match reader.read(buf) {
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Read some bytes into `buf`, returning how many were read, or 0 at the end of the input.
+#[cfg(not(feature = "std"))]
+pub(crate) fn read<R>(reader: &mut R, buf: &mut [u8]) -> Result<usize, Error>
+where
+    R: Read + ?Sized,
+{
+    Ok(reader.read(buf))
+}
+
+/// Write all of `buf` to a writer.
+#[cfg(feature = "std")]
+pub(crate) fn write_all<W>(writer: &mut W, buf: &[u8]) -> Result<(), Error>
+where
+    W: Write + ?Sized,
+{
+    writer.write_all(buf)?;
+
+    Ok(())
+}
+
+/// Write all of `buf` to a writer.
+#[cfg(not(feature = "std"))]
+pub(crate) fn write_all<W>(writer: &mut W, buf: &[u8]) -> Result<(), Error>
+where
+    W: Write + ?Sized,
+{
+    writer.write_all(buf);
+
+    Ok(())
+}
@@ -1,32 +1,101 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 mod convert;
 mod dump;
+pub mod io;
 mod load;
+mod schema;
+mod text;
 mod value_arena;
 
 pub use self::convert::DisplayByteString;
 pub use self::convert::FromValue;
 pub use self::convert::FromValueContext;
 pub use self::convert::FromValueError;
+pub use self::convert::HashMapFromValueError;
 pub use self::convert::IntoValue;
 pub use self::convert::IntoValueError;
+pub use self::convert::PathSegment;
+pub use self::convert::RubyHash;
+pub use self::convert::RubyTime;
 pub use self::dump::dump;
+pub use self::dump::dump_headerless;
+pub use self::dump::dump_many;
+pub use self::dump::dump_with_options;
+pub use self::dump::DumpOptions;
+pub use self::dump::Dumper;
+pub use self::dump::IvarFilter;
+pub use self::load::annotate;
+pub use self::load::diff_bytes;
 pub use self::load::load;
+pub use self::load::load_and_recover;
+pub use self::load::load_headerless;
+pub use self::load::load_typed;
+pub use self::load::load_with_options;
+pub use self::load::load_with_stats;
+pub use self::load::load_with_storage;
+pub use self::load::read_version;
+pub use self::load::validate_stream;
+pub use self::load::AbortSignal;
+pub use self::load::Annotation;
+pub use self::load::ByteDiff;
+pub use self::load::DuplicateHashKeyPolicy;
+pub use self::load::LoadOptions;
+pub use self::load::LoadStats;
+pub use self::load::StringDecoder;
+pub use self::schema::Schema;
+pub use self::schema::SchemaError;
+pub use self::text::dump_base64;
+pub use self::text::dump_hex;
+pub use self::text::load_base64;
+pub use self::text::load_hex;
 pub use self::value_arena::ArrayValue;
+pub use self::value_arena::BignumValue;
 pub use self::value_arena::BoolValue;
+pub use self::value_arena::Event;
 pub use self::value_arena::FixnumValue;
 pub use self::value_arena::FloatValue;
+pub use self::value_arena::GenericValueArena;
+pub use self::value_arena::HashBuilder;
 pub use self::value_arena::HashValue;
 pub use self::value_arena::NilValue;
 pub use self::value_arena::ObjectValue;
+pub use self::value_arena::RegexpOptions;
+pub use self::value_arena::RegexpValue;
+pub use self::value_arena::SlotMapStorage;
+pub(crate) use self::value_arena::Source;
+pub(crate) use self::value_arena::SourceSpan;
+pub use self::value_arena::Storage;
 pub use self::value_arena::StringValue;
+pub use self::value_arena::StructValue;
 pub use self::value_arena::SymbolValue;
 pub use self::value_arena::TypedValueHandle;
 pub use self::value_arena::UserDefinedValue;
+pub use self::value_arena::UserMarshalValue;
 pub use self::value_arena::ClassValue;
 pub use self::value_arena::Value;
 pub use self::value_arena::ValueArena;
 pub use self::value_arena::ValueHandle;
 pub use self::value_arena::ValueKind;
+pub use self::value_arena::ValueRef;
+
+use alloc::vec::Vec;
+
+// The public API uses the std hash collections when they are available.
+#[cfg(not(feature = "std"))]
+pub(crate) use hashbrown::DefaultHashBuilder as RandomState;
+#[cfg(not(feature = "std"))]
+pub(crate) use hashbrown::HashMap;
+#[cfg(not(feature = "std"))]
+pub(crate) use hashbrown::HashSet;
+#[cfg(feature = "std")]
+pub(crate) use std::collections::hash_map::RandomState;
+#[cfg(feature = "std")]
+pub(crate) use std::collections::HashMap;
+#[cfg(feature = "std")]
+pub(crate) use std::collections::HashSet;
 
 const MAJOR_VERSION: u8 = 4;
 const MINOR_VERSION: u8 = 8;
@@ -35,6 +104,7 @@ const VALUE_KIND_NIL: u8 = b'0';
 const VALUE_KIND_TRUE: u8 = b'T';
 const VALUE_KIND_FALSE: u8 = b'F';
 const VALUE_KIND_FIXNUM: u8 = b'i';
+const VALUE_KIND_BIGNUM: u8 = b'l';
 const VALUE_KIND_FLOAT: u8 = b'f';
 const VALUE_KIND_SYMBOL: u8 = b':';
 const VALUE_KIND_SYMBOL_LINK: u8 = b';';
@@ -44,9 +114,14 @@ const VALUE_KIND_ARRAY: u8 = b'[';
 const VALUE_KIND_HASH: u8 = b'{';
 const VALUE_KIND_HASH_DEFAULT: u8 = b'}';
 const VALUE_KIND_OBJECT: u8 = b'o';
+const VALUE_KIND_STRUCT: u8 = b'S';
 const VALUE_KIND_STRING: u8 = b'"';
+const VALUE_KIND_REGEXP: u8 = b'/';
 const VALUE_KIND_USER_DEFINED: u8 = b'u';
+const VALUE_KIND_USER_MARSHAL: u8 = b'U';
 const VALUE_KIND_CLASS: u8 = b'c';
+const VALUE_KIND_USER_CLASS: u8 = b'C';
+const VALUE_KIND_EXTENDED: u8 = b'e';
 
 /// The library error type
 #[derive(Debug)]
@@ -61,6 +136,7 @@ pub enum Error {
     },
 
     /// An I/O Error
+    #[cfg(feature = "std")]
     Io { error: std::io::Error },
 
     /// An invalid value kind was encountered
@@ -76,16 +152,18 @@ pub enum Error {
     InvalidFixnumSize { size: u8 },
 
     /// The Fixnum is not a valid usize
-    FixnumInvalidUSize { error: std::num::TryFromIntError },
+    FixnumInvalidUSize { error: core::num::TryFromIntError },
 
     /// The usize is not a valid Fixnum
-    USizeInvalidFixnum { error: std::num::TryFromIntError },
+    USizeInvalidFixnum { error: core::num::TryFromIntError },
 
     /// Float string is cannot be decoded as utf-8
-    InvalidFloatUtf8 { error: std::str::Utf8Error },
+    InvalidFloatUtf8 { error: core::str::Utf8Error },
 
     /// Float cannot be parsed
-    InvalidFloat { error: <f64 as std::str::FromStr>::Err },
+    InvalidFloat {
+        error: <f64 as core::str::FromStr>::Err,
+    },
 
     /// Missing a symbol link
     MissingSymbolLink { index: usize },
@@ -104,12 +182,87 @@ pub enum Error {
         /// The duplicated variable
         name: Vec<u8>,
     },
+
+    /// Too many values were created while loading
+    ValueLimitExceeded {
+        /// The maximum number of values
+        limit: usize,
+    },
+
+    /// A byte string was longer than allowed by [`LoadOptions::max_byte_string_len`],
+    /// [`LoadOptions::max_string_length`] or [`LoadOptions::max_symbol_length`]
+    LengthLimitExceeded {
+        /// The kind of the value the byte string belongs to
+        kind: u8,
+
+        /// The length of the byte string, in bytes
+        len: usize,
+
+        /// The maximum length that applied, in bytes
+        limit: usize,
+    },
+
+    /// A Bignum sign byte was neither '+' nor '-'
+    InvalidBignumSign { sign: u8 },
+
+    /// A hash contained a duplicate key
+    DuplicateHashKey {
+        /// The index of the entry with the duplicate key
+        index: usize,
+    },
+
+    /// A user defined payload does not start with a Marshal header
+    NotNestedMarshal,
+
+    /// The stream was empty
+    EmptyInput,
+
+    /// Dumping would write more bytes than allowed
+    DumpSizeLimitExceeded {
+        /// The maximum number of bytes
+        limit: usize,
+    },
+
+    /// A nested stream ended before its payload did,
+    /// or a stream ended before its input did with [`LoadOptions::strict_eof`] set
+    TrailingBytes {
+        /// The number of bytes left over
+        len: usize,
+    },
+
+    /// The stream ended in the middle of a value
+    UnexpectedEof {
+        /// The offset of the read that ran past the end of the stream
+        position: usize,
+
+        /// The kind byte of the innermost value being read, if any
+        while_reading: Option<u8>,
+    },
+
+    /// The loaded value could not be converted
+    FromValue { error: FromValueError },
+
+    /// Loading was stopped by [`LoadOptions::should_abort`]
+    Aborted,
+
+    /// Values were nested deeper than allowed
+    DepthLimitExceeded {
+        /// The maximum depth
+        limit: usize,
+    },
+
+    /// Base64 or hex text had an invalid character, or ended in the middle of a byte
+    InvalidEncodedText {
+        /// The offset of the invalid character, or the length of the text if it ended early
+        position: usize,
+    },
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::InvalidVersion { major, minor } => write!(f, "invalid version {major}.{minor}"),
+            #[cfg(feature = "std")]
             Self::Io { .. } => write!(f, "I/O error"),
             Self::InvalidValueKind { kind } => write!(f, "invalid value kind {kind}"),
             Self::InvalidValueHandle { .. } => write!(f, "invalid value handle"),
@@ -128,31 +281,88 @@ impl std::fmt::Display for Error {
             Self::DuplicateInstanceVariable { name } => {
                 write!(f, "duplicate instance variable \"{name:?}\"")
             }
+            Self::ValueLimitExceeded { limit } => write!(f, "value limit of {limit} exceeded"),
+            Self::LengthLimitExceeded { kind, len, limit } => write!(
+                f,
+                "length {len} of value kind {:?} exceeds the limit of {limit}",
+                char::from(*kind)
+            ),
+            Self::InvalidBignumSign { sign } => write!(f, "invalid bignum sign {sign}"),
+            Self::DuplicateHashKey { index } => write!(f, "duplicate hash key at entry {index}"),
+            Self::NotNestedMarshal => {
+                write!(f, "user defined payload is not a Marshal stream")
+            }
+            Self::TrailingBytes { len } => write!(f, "{len} trailing bytes after stream"),
+            Self::EmptyInput => write!(f, "the stream is empty"),
+            Self::DumpSizeLimitExceeded { limit } => {
+                write!(f, "dump size limit of {limit} bytes exceeded")
+            }
+            Self::UnexpectedEof {
+                position,
+                while_reading,
+            } => {
+                write!(f, "unexpected end of stream at byte {position}")?;
+                if let Some(kind) = while_reading {
+                    write!(f, " while reading value kind {:?}", char::from(*kind))?;
+                }
+                Ok(())
+            }
+            Self::FromValue { .. } => write!(f, "failed to convert the loaded value"),
+            Self::Aborted => write!(f, "loading was aborted"),
+            Self::DepthLimitExceeded { limit } => write!(f, "depth limit of {limit} exceeded"),
+            Self::InvalidEncodedText { position } => {
+                write!(f, "invalid encoded text at byte {position}")
+            }
         }
     }
 }
 
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+impl core::error::Error for Error {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
         match self {
+            #[cfg(feature = "std")]
             Self::Io { error } => Some(error),
             Self::FixnumInvalidUSize { error } => Some(error),
             Self::USizeInvalidFixnum { error } => Some(error),
+            Self::FromValue { error } => Some(error),
             _ => None,
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Self {
         Error::Io { error }
     }
 }
 
+impl From<FromValueError> for Error {
+    fn from(error: FromValueError) -> Self {
+        Error::FromValue { error }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::io::Read;
+
+    /// Load and dump a fixture, and fail with a span by span diff if the bytes change.
+    fn assert_roundtrip(path: &std::path::Path) {
+        let data = std::fs::read(path).expect("failed to read fixture");
+        let mut data_reader = &data[..];
+        let value_arena = load(&mut data_reader).expect("failed to load");
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+
+        assert!(data_reader.is_empty(), "{path:?} has trailing bytes");
+        if data != new_data {
+            let diffs = diff_bytes(&data, &new_data);
+            let diffs: Vec<String> = diffs.iter().map(|diff| diff.to_string()).collect();
+            panic!("{path:?} did not roundtrip:\n{}", diffs.join("\n"));
+        }
+    }
 
     #[test]
     fn kitchen_sink() {
@@ -162,21 +372,381 @@ mod test {
             if file_type.is_dir() {
                 continue;
             }
-            let entry_path = entry.path();
 
-            let data = std::fs::read(&entry_path).expect("failed to read entry");
-            let mut data_reader = std::io::Cursor::new(&data);
+            assert_roundtrip(&entry.path());
+        }
+    }
+
+    #[test]
+    fn mutually_recursive_objects() {
+        // a = A.new; b = B.new
+        // a.instance_variable_set(:@b, b); b.instance_variable_set(:@a, a)
+        // Marshal.dump(a)
+        let data = b"\x04\x08o:\x06A\x06:\x07@bo:\x06B\x06:\x07@a@\x00";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let a = value_arena[value_arena.root()]
+            .as_object()
+            .expect("not an object");
+        let (_, b_handle) = a.instance_variables()[0];
+        let b = value_arena[b_handle].as_object().expect("not an object");
+        let (_, a_handle) = b.instance_variables()[0];
+        assert!(a_handle == value_arena.root());
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+    }
+
+    #[test]
+    fn unusual_ivar_names() {
+        // Marshal.dump(Foo.new), with @__meta = nil and an ivar named "@1\xff" set to true.
+        // Ruby refuses to set the second name directly, but loads it from a stream.
+        let data = b"\x04\x08o:\x08Foo\x07:\x0c@__meta0:\x08@1\xffT";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let object = value_arena[value_arena.root()]
+            .as_object()
+            .expect("not an object");
+        let names: Vec<&[u8]> = object
+            .instance_variables()
+            .iter()
+            .filter_map(|(name, _)| value_arena.get_symbol(*name))
+            .map(|name| name.value())
+            .collect();
+        assert!(names == [&b"@__meta"[..], b"@1\xff"]);
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+
+        validate_stream(&data[..], &LoadOptions::default()).expect("failed to validate");
+    }
+
+    #[test]
+    fn user_marshal() {
+        // t = Time.at(0).utc; Marshal.dump(ActiveSupport::TimeWithZone.new(t, zone)),
+        // where marshal_dump returns [t, "Etc/UTC", t]
+        let data = b"\x04\x08U:\x20ActiveSupport::TimeWithZone[\x08\
+            Iu:\x09Time\x0d\x80\x00\x1c\xc0\x00\x00\x00\x00\x06:\x09zoneI\"\x08UTC\x06:\x06EF\
+            I\"\x0cEtc/UTC\x06;\x08F@\x07";
+        let value_arena = load(&data[..]).expect("failed to load");
 
-            println!("entry_path = {:?}", entry_path);
-            let value_arena = load(&mut data_reader).expect("failed to load");
+        let ctx = FromValueContext::new(&value_arena);
+        let user_marshal: &UserMarshalValue = ctx
+            .from_value(value_arena.root())
+            .expect("not a user marshal value");
+        let name: &SymbolValue = ctx
+            .from_value(user_marshal.name().into())
+            .expect("not a symbol");
+        assert!(name.value() == b"ActiveSupport::TimeWithZone");
+        let array = value_arena[user_marshal.value()]
+            .as_array()
+            .expect("not an array");
+        assert!(array.len() == 3);
+        assert!(array.value()[0] == array.value()[2]);
 
-            let mut new_data = Vec::new();
-            dump(&mut new_data, &value_arena).expect("failed to dump");
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+        validate_stream(&data[..], &LoadOptions::default()).expect("failed to validate");
 
-            let read_end_result = data_reader.read(&mut [0]);
-            let is_eof = matches!(read_end_result, Ok(0));
-            assert!(is_eof);
-            assert!(data == new_data, "{data:?} != {new_data:?}");
+        // o = Wrapper.new; Marshal.dump(o), where marshal_dump returns [o]
+        // The value gets its object link before its data is read.
+        let data = b"\x04\x08U:\x0cWrapper[\x06@\x00";
+        let value_arena = load(&data[..]).expect("failed to load");
+        let user_marshal: &UserMarshalValue = FromValueContext::new(&value_arena)
+            .from_value(value_arena.root())
+            .expect("not a user marshal value");
+        let array = value_arena[user_marshal.value()]
+            .as_array()
+            .expect("not an array");
+        assert!(array.value()[0] == value_arena.root());
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+    }
+
+    #[test]
+    fn self_referential_struct() {
+        // S = Struct.new(:b, :a); s = S.new; s.b = s; s.a = 1; Marshal.dump(s)
+        // Members are not in name order, and the struct gets its object link
+        // before its members are read.
+        let data = b"\x04\x08S:\x06S\x07:\x06b@\x00:\x06ai\x06";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let value = value_arena[value_arena.root()]
+            .as_struct()
+            .expect("not a struct");
+        let names: Vec<&[u8]> = value
+            .members()
+            .iter()
+            .map(|(name, _)| {
+                value_arena
+                    .get_symbol(*name)
+                    .expect("missing symbol")
+                    .value()
+            })
+            .collect();
+        assert!(names == [&b"b"[..], b"a"]);
+        assert!(value.members()[0].1 == value_arena.root());
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+        validate_stream(&data[..], &LoadOptions::default()).expect("failed to validate");
+    }
+
+    #[test]
+    fn float_object_link() {
+        // An array holding the same float twice, where the second is an object link.
+        let data = b"\x04\x08[\x07f\x081.5@\x06";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let array = value_arena[value_arena.root()]
+            .as_array()
+            .expect("not an array");
+        assert!(array.value()[0] == array.value()[1]);
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+
+        // Fixnums are never object links.
+        let data = b"\x04\x08[\x07i\x06i\x06";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+    }
+
+    #[test]
+    fn hash_subclass_with_default_and_instance_variables() {
+        // class MyHash < Hash; end
+        // hash = MyHash.new(0)
+        // hash[:a] = 1
+        // hash.instance_variable_set(:@meta, 7)
+        // Marshal.dump(hash)
+        let data = b"\x04\x08IC:\x0bMyHash}\x06:\x06ai\x06i\x00\x06:\x0a@metai\x0c";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let hash = match &value_arena[value_arena.root()] {
+            Value::Hash(hash) => hash,
+            value => panic!("expected a hash, got {:?}", value.kind()),
+        };
+        let user_class = hash.user_class().expect("missing user class");
+        assert!(value_arena.get_symbol(user_class).unwrap().value() == b"MyHash");
+        assert!(hash.default_value().is_some());
+        assert!(hash.instance_variables().map(|v| v.len()) == Some(1));
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+    }
+
+    #[test]
+    fn hash_compare_by_identity() {
+        // Marshal.dump({ a: 1 }.compare_by_identity)
+        let data = b"\x04\x08C:\x09Hash{\x06:\x06ai\x06";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let hash = value_arena[value_arena.root()]
+            .as_hash()
+            .expect("not a hash");
+        assert!(hash.compare_by_identity());
+        assert!(hash.user_class().is_none());
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+
+        // class MyHash < Hash; end
+        // Marshal.dump(MyHash.new.compare_by_identity)
+        let data = b"\x04\x08C:\x0bMyHashC:\x09Hash{\x00";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let hash = value_arena[value_arena.root()]
+            .as_hash()
+            .expect("not a hash");
+        assert!(hash.compare_by_identity());
+        let user_class = hash.user_class().expect("missing user class");
+        assert!(value_arena.get_symbol(user_class).unwrap().value() == b"MyHash");
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+    }
+
+    #[test]
+    fn array_subclass_with_instance_variable_elements() {
+        // class MyArray < Array; end
+        // string = "a"
+        // Marshal.dump(MyArray[string, "b", string])
+        let data = b"\x04\x08C:\x0cMyArray[\x08I\"\x06a\x06:\x06ETI\"\x06b\x06;\x06T@\x06";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let array = match &value_arena[value_arena.root()] {
+            Value::Array(array) => array,
+            value => panic!("expected an array, got {:?}", value.kind()),
+        };
+        let user_class = array.user_class().expect("missing user class");
+        assert!(value_arena.get_symbol(user_class).unwrap().value() == b"MyArray");
+        assert!(array.instance_variables().is_none());
+
+        let elements = array.value();
+        assert!(elements[0] == elements[2]);
+        for handle in elements {
+            let string = value_arena[*handle].as_string().expect("expected a string");
+            assert!(string.user_class().is_none());
+            assert!(string.instance_variables().map(|v| v.len()) == Some(1));
+        }
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+    }
+
+    #[test]
+    fn stacked_wrappers() {
+        // module M; end
+        // class MyString < String; end
+        // string = MyString.new("abc").extend(M)
+        // string.instance_variable_set(:@x, 1)
+        // Marshal.dump([string, string])
+        let data = b"\x04\x08[\x07Ie:\x06MC:\x0dMyString\"\x08abc\x07:\x06ET:\x07@xi\x06@\x06";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let array = value_arena[value_arena.root()]
+            .as_array()
+            .expect("not an array");
+        assert!(array.value()[0] == array.value()[1]);
+        let string = value_arena[array.value()[0]]
+            .as_string()
+            .expect("not a string");
+        let user_class = string.user_class().expect("missing user class");
+        assert!(value_arena.get_symbol(user_class).unwrap().value() == b"MyString");
+        let extended_modules: Vec<&[u8]> = string
+            .extended_modules()
+            .iter()
+            .map(|module| value_arena.get_symbol(*module).unwrap().value())
+            .collect();
+        assert!(extended_modules == [b"M"]);
+        assert!(string.instance_variables().map(|v| v.len()) == Some(2));
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+        validate_stream(&data[..], &LoadOptions::default()).expect("failed to validate");
+
+        // Marshal.dump(Foo.new), where marshal_dump returns MyString.new("abc")
+        let data = b"\x04\x08U:\x08FooIC:\x0dMyString\"\x08abc\x06:\x06ET";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let user_marshal: &UserMarshalValue = FromValueContext::new(&value_arena)
+            .from_value(value_arena.root())
+            .expect("not a user marshal value");
+        let string = value_arena[user_marshal.value()]
+            .as_string()
+            .expect("not a string");
+        assert!(string.user_class().is_some());
+        assert!(string.instance_variables().map(|v| v.len()) == Some(1));
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+    }
+
+    #[test]
+    fn extended_modules() {
+        // module A; end
+        // module B; end
+        // Marshal.dump(Struct.new(:a).new(Object.new.extend(A).extend(B)))
+        // Ruby writes the most recently extended module first.
+        let data = b"\x04\x08S:\x08Foo\x06:\x06ae:\x06Be:\x06Ao:\x0bObject\x00";
+        let value_arena = load(&data[..]).expect("failed to load");
+
+        let value_struct = value_arena[value_arena.root()]
+            .as_struct()
+            .expect("not a struct");
+        let object = value_arena[value_struct.members()[0].1]
+            .as_object()
+            .expect("not an object");
+        let extended_modules: Vec<&[u8]> = object
+            .extended_modules()
+            .iter()
+            .map(|module| value_arena.get_symbol(*module).unwrap().value())
+            .collect();
+        assert!(extended_modules == [b"B", b"A"]);
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+
+        let events = value_arena.to_events(value_arena.root());
+        assert!(
+            events
+                == [
+                    Event::Struct { len: 1 },
+                    Event::Symbol(b"Foo".to_vec()),
+                    Event::Symbol(b"a".to_vec()),
+                    Event::Extended,
+                    Event::Symbol(b"B".to_vec()),
+                    Event::Extended,
+                    Event::Symbol(b"A".to_vec()),
+                    Event::Object { len: 0 },
+                    Event::Symbol(b"Object".to_vec()),
+                ],
+            "{events:?}"
+        );
+
+        // Symbols cannot be extended.
+        let data = b"\x04\x08e:\x06M:\x06a";
+        let error = load(&data[..]).expect_err("extended a symbol");
+        assert!(matches!(error, Error::NotAnObject));
+        let error =
+            validate_stream(&data[..], &LoadOptions::default()).expect_err("extended a symbol");
+        assert!(matches!(error, Error::NotAnObject));
+    }
+
+    #[test]
+    fn many_symbol_links() {
+        // Encode a small non-negative fixnum, as Ruby does.
+        fn fixnum(value: usize) -> Vec<u8> {
+            match value {
+                0 => vec![0],
+                1..=122 => vec![u8::try_from(value + 5).unwrap()],
+                123..=0xFF => vec![1, u8::try_from(value).unwrap()],
+                _ => vec![2, (value & 0xFF) as u8, (value >> 8) as u8],
+            }
+        }
+
+        // Marshal.dump((0...500).to_h { |i| [:"key#{i}", :"key#{i}"] })
+        let mut data = b"\x04\x08{".to_vec();
+        data.extend(fixnum(500));
+        for i in 0..500 {
+            let name = format!("key{i}");
+            data.push(b':');
+            data.extend(fixnum(name.len()));
+            data.extend(name.as_bytes());
+            data.push(b';');
+            data.extend(fixnum(i));
         }
+
+        let value_arena = load(&data[..]).expect("failed to load");
+        let hash = value_arena[value_arena.root()]
+            .as_hash()
+            .expect("not a hash");
+        assert!(hash.value().len() == 500);
+        for (i, (key, value)) in hash.value().iter().enumerate() {
+            let symbol = value_arena[*key].as_symbol().expect("not a symbol");
+            assert!(symbol.value() == format!("key{i}").as_bytes());
+            assert!(key == value);
+        }
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data == new_data, "{data:?} != {new_data:?}");
     }
 }
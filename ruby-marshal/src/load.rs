@@ -1,20 +1,29 @@
-use crate::ArrayValue;
+mod builder;
+
+use self::builder::ArenaBuilder;
+use self::builder::Builder;
+use self::builder::Children;
+use self::builder::InstanceVariables;
+use self::builder::KindBuilder;
+use crate::io;
+use crate::io::Read;
+use crate::io::ReadError;
+use crate::DisplayByteString;
 use crate::Error;
-use crate::FixnumValue;
-use crate::FloatValue;
-use crate::HashValue;
-use crate::ObjectValue;
-use crate::StringValue;
-use crate::SymbolValue;
-use crate::TypedValueHandle;
-use crate::UserDefinedValue;
-use crate::ClassValue;
-use crate::Value;
+use crate::FromValue;
+use crate::FromValueContext;
+use crate::GenericValueArena;
+use crate::HashMap;
+use crate::SourceSpan;
+use crate::Storage;
 use crate::ValueArena;
 use crate::ValueHandle;
+use crate::ValueKind;
 use crate::MAJOR_VERSION;
 use crate::MINOR_VERSION;
 use crate::VALUE_KIND_ARRAY;
+use crate::VALUE_KIND_BIGNUM;
+use crate::VALUE_KIND_EXTENDED;
 use crate::VALUE_KIND_FALSE;
 use crate::VALUE_KIND_FIXNUM;
 use crate::VALUE_KIND_FLOAT;
@@ -24,66 +33,551 @@ use crate::VALUE_KIND_INSTANCE_VARIABLES;
 use crate::VALUE_KIND_NIL;
 use crate::VALUE_KIND_OBJECT;
 use crate::VALUE_KIND_OBJECT_LINK;
+use crate::VALUE_KIND_REGEXP;
 use crate::VALUE_KIND_STRING;
+use crate::VALUE_KIND_STRUCT;
 use crate::VALUE_KIND_SYMBOL;
 use crate::VALUE_KIND_SYMBOL_LINK;
 use crate::VALUE_KIND_TRUE;
+use crate::VALUE_KIND_USER_CLASS;
 use crate::VALUE_KIND_USER_DEFINED;
+use crate::VALUE_KIND_USER_MARSHAL;
 use crate::VALUE_KIND_CLASS;
-use std::io::Read;
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// What to do when a hash contains a duplicate key.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateHashKeyPolicy {
+    /// Keep every entry.
+    ///
+    /// This preserves the stream exactly.
+    #[default]
+    KeepBoth,
+
+    /// Keep the first key, with the value of the last entry, like Ruby's `Hash` does.
+    ///
+    /// This drops entries, so dumping the result will not reproduce the original bytes.
+    LastWins,
+
+    /// Fail with [`Error::DuplicateHashKey`].
+    Error,
+}
+
+/// A check for whether loading should stop early, for [`LoadOptions::should_abort`].
+#[derive(Clone)]
+pub struct AbortSignal(Arc<dyn Fn() -> bool + Send + Sync>);
+
+impl AbortSignal {
+    /// Make a new [`AbortSignal`] from a function that returns true to stop loading.
+    pub fn new<F>(should_abort: F) -> Self
+    where
+        F: Fn() -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(should_abort))
+    }
+
+    /// Check whether loading should stop.
+    pub fn should_abort(&self) -> bool {
+        (self.0)()
+    }
+}
+
+impl core::fmt::Debug for AbortSignal {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("AbortSignal(..)")
+    }
+}
+
+/// A transcoder from one string encoding to UTF-8, for [`LoadOptions::string_decoders`].
+#[derive(Clone)]
+pub struct StringDecoder(Arc<DecodeFn>);
+
+type DecodeFn = dyn Fn(&[u8]) -> Option<String> + Send + Sync;
+
+impl StringDecoder {
+    /// Make a new [`StringDecoder`] from a function that returns None if the bytes are invalid.
+    pub fn new<F>(decode: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<String> + Send + Sync + 'static,
+    {
+        Self(Arc::new(decode))
+    }
+
+    /// Decode the bytes to UTF-8.
+    pub fn decode(&self, bytes: &[u8]) -> Option<String> {
+        (self.0)(bytes)
+    }
+}
+
+impl core::fmt::Debug for StringDecoder {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("StringDecoder(..)")
+    }
+}
+
+/// The default for [`LoadOptions::max_depth`].
+const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// The most bytes to reserve up front when reading a byte string.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The size of the buffer used to skip byte strings without keeping them.
+const SKIP_CHUNK_SIZE: usize = 512;
+
+/// Options for loading.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// The maximum number of values that may be created while loading.
+    ///
+    /// Defaults to unlimited.
+    pub max_values: Option<usize>,
+
+    /// Whether to keep the loaded bytes and the span of each value.
+    ///
+    /// When set, [`dump`](crate::dump) copies the bytes of values that were not modified
+    /// verbatim instead of re-encoding them.
+    /// This costs memory proportional to the size of the stream.
+    ///
+    /// Defaults to false.
+    pub preserve_source: bool,
+
+    /// What to do when a hash contains a duplicate key.
+    ///
+    /// Keys are duplicates if they are equal according to [`ValueArena::values_equal`].
+    ///
+    /// Defaults to [`DuplicateHashKeyPolicy::KeepBoth`].
+    pub on_duplicate_hash_key: DuplicateHashKeyPolicy,
+
+    /// Whether to record the handle of every created value, in creation order.
+    ///
+    /// The handles are available from [`ValueArena::creation_order`].
+    ///
+    /// Defaults to false.
+    pub record_creation_order: bool,
+
+    /// The number of values to reserve space for before loading.
+    ///
+    /// The object link table is reserved to the same size.
+    /// This avoids reallocating while loading large streams of a known size.
+    ///
+    /// Defaults to 0.
+    pub initial_capacity: usize,
+
+    /// The maximum length of a single string, in bytes.
+    ///
+    /// This is checked along with [`max_byte_string_len`](Self::max_byte_string_len),
+    /// and the smaller limit applies.
+    ///
+    /// Defaults to unlimited.
+    pub max_string_length: Option<usize>,
+
+    /// The maximum length of a single symbol, in bytes.
+    ///
+    /// Real symbols are short, so a long one usually means the stream is corrupt.
+    /// This is checked along with [`max_byte_string_len`](Self::max_byte_string_len),
+    /// and the smaller limit applies.
+    ///
+    /// Defaults to unlimited.
+    pub max_symbol_length: Option<usize>,
+
+    /// Whether to fail with [`Error::TrailingBytes`] if the input continues after the root value.
+    ///
+    /// This only applies to functions that do not hand the reader back.
+    ///
+    /// Defaults to false.
+    pub strict_eof: bool,
+
+    /// Whether [`strict_eof`](Self::strict_eof) tolerates trailing newlines and spaces.
+    ///
+    /// Tools that treat dump files as text sometimes append a newline.
+    ///
+    /// Defaults to false.
+    pub allow_trailing_whitespace: bool,
+
+    /// A check for whether to stop loading, made after each value is read.
+    ///
+    /// Loading fails with [`Error::Aborted`] once it returns true.
+    /// This can enforce a deadline or react to a shutdown flag.
+    ///
+    /// Defaults to None.
+    pub should_abort: Option<AbortSignal>,
+
+    /// Whether to transcode strings in other encodings to UTF-8.
+    ///
+    /// A string is transcoded if its `encoding` instance variable names an encoding in
+    /// [`string_decoders`](Self::string_decoders),
+    /// and it is then given the `E` instance variable instead.
+    /// Transcoded strings share one `E` symbol, which is the stream's own if it was read already.
+    /// Strings the decoder rejects are left as they are.
+    /// Dumping the result will not reproduce the original bytes.
+    ///
+    /// Defaults to false.
+    pub normalize_strings_to_utf8: bool,
+
+    /// The decoders for [`normalize_strings_to_utf8`](Self::normalize_strings_to_utf8),
+    /// keyed by encoding name, like `Shift_JIS`.
+    ///
+    /// Defaults to empty.
+    pub string_decoders: HashMap<Vec<u8>, StringDecoder>,
+
+    /// The maximum nesting of values, as counted by [`LoadStats::max_depth`].
+    ///
+    /// Values are read recursively, so this stops a deeply nested stream from overflowing
+    /// the stack.
+    ///
+    /// Defaults to 256.
+    pub max_depth: Option<usize>,
+
+    /// The maximum length of any length-prefixed byte string, in bytes.
+    ///
+    /// This covers the bytes of strings, symbols, regexps, floats, classes,
+    /// user defined payloads, and bignum magnitudes, and is checked before the bytes are read.
+    /// [`max_string_length`](Self::max_string_length) and
+    /// [`max_symbol_length`](Self::max_symbol_length) can set a lower limit for strings and
+    /// symbols.
+    /// Going over any of these limits fails with [`Error::LengthLimitExceeded`].
+    ///
+    /// Defaults to unlimited.
+    pub max_byte_string_len: Option<usize>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            max_values: None,
+            preserve_source: false,
+            on_duplicate_hash_key: DuplicateHashKeyPolicy::default(),
+            record_creation_order: false,
+            initial_capacity: 0,
+            max_string_length: None,
+            max_symbol_length: None,
+            strict_eof: false,
+            allow_trailing_whitespace: false,
+            should_abort: None,
+            normalize_strings_to_utf8: false,
+            string_decoders: HashMap::new(),
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+            max_byte_string_len: None,
+        }
+    }
+}
+
+impl LoadOptions {
+    /// Get the maximum length of a byte string of a value of the given kind, in bytes.
+    ///
+    /// This is the smaller of [`max_byte_string_len`](Self::max_byte_string_len)
+    /// and the limit for the kind, if any.
+    fn length_limit(&self, kind: u8) -> Option<usize> {
+        let kind_limit = match kind {
+            VALUE_KIND_STRING => self.max_string_length,
+            VALUE_KIND_SYMBOL => self.max_symbol_length,
+            _ => None,
+        };
+
+        match (self.max_byte_string_len, kind_limit) {
+            (Some(limit), Some(kind_limit)) => Some(limit.min(kind_limit)),
+            (limit, kind_limit) => limit.or(kind_limit),
+        }
+    }
+}
+
+/// A span of a Marshal stream, annotated with what it encodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    /// The byte range of the span.
+    pub range: core::ops::Range<usize>,
+
+    /// The kind byte of the innermost value being read, if any.
+    pub kind: Option<u8>,
+
+    /// A human-readable description of the span.
+    pub description: String,
+}
+
+/// A difference between two Marshal streams, as found by [`diff_bytes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteDiff {
+    /// The span of the first stream, or None if it ended first.
+    pub a: Option<Annotation>,
+
+    /// The span of the second stream, or None if it ended first.
+    pub b: Option<Annotation>,
+}
+
+impl core::fmt::Display for ByteDiff {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if let Some(annotation) = self.a.as_ref().or(self.b.as_ref()) {
+            write!(f, "at byte {}", annotation.range.start)?;
+            if let Some(kind) = annotation.kind {
+                write!(f, " while reading value kind {:?}", char::from(kind))?;
+            }
+            write!(f, ": ")?;
+        }
+
+        let description = |annotation: &Option<Annotation>| match annotation {
+            Some(annotation) => format!("{:?}", annotation.description),
+            None => "end of stream".to_string(),
+        };
+        write!(f, "{} != {}", description(&self.a), description(&self.b))
+    }
+}
+
+/// Statistics about a load, from [`load_with_stats`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoadStats {
+    /// The number of bytes read, including the header.
+    pub bytes_read: usize,
+
+    /// The number of values created.
+    pub values: usize,
+
+    /// The number of entries in the symbol table.
+    pub symbols: usize,
+
+    /// The number of entries in the object table.
+    pub objects: usize,
+
+    /// The deepest nesting of values reached.
+    ///
+    /// The root is at depth 1, and wrappers like instance variables count as a level.
+    pub max_depth: usize,
+
+    /// The number of symbol links that were resolved.
+    pub symbol_links_resolved: usize,
+
+    /// The number of object links that were resolved.
+    pub object_links_resolved: usize,
+}
 
 #[derive(Debug)]
-struct Loader<R> {
+struct Loader<'a, R, B = ArenaBuilder<'a>>
+where
+    B: Builder,
+{
     reader: R,
 
-    arena: ValueArena,
+    builder: B,
+    options: &'a LoadOptions,
 
-    symbol_links: Vec<TypedValueHandle<SymbolValue>>,
-    object_links: Vec<ValueHandle>,
-}
+    symbol_links: Vec<B::Symbol>,
+    object_links: Vec<B::Handle>,
+
+    position: usize,
+    kind: Option<u8>,
+    annotations: Option<Vec<Annotation>>,
 
-impl<R> Loader<R> {
-    /// Make a new [`Loader`] around a reader.
-    fn new(reader: R) -> Self {
-        let arena = ValueArena::new();
+    /// The current nesting of values being read.
+    depth: usize,
+    stats: LoadStats,
+}
 
+impl<'a, R, B> Loader<'a, R, B>
+where
+    B: Builder,
+{
+    /// Make a new [`Loader`] around a reader, that passes the values it reads to a [`Builder`].
+    fn with_builder(reader: R, options: &'a LoadOptions, builder: B) -> Self {
         Self {
             reader,
-            arena,
+            builder,
+            options,
             symbol_links: Vec::new(),
-            object_links: Vec::new(),
+            object_links: Vec::with_capacity(options.initial_capacity),
+
+            position: 0,
+            kind: None,
+            annotations: None,
+
+            depth: 0,
+            stats: LoadStats::default(),
+        }
+    }
+
+    /// Record an annotation from `start` to the current position, if annotating.
+    fn annotate<F>(&mut self, start: usize, description: F)
+    where
+        F: FnOnce() -> String,
+    {
+        if let Some(annotations) = self.annotations.as_mut() {
+            annotations.push(Annotation {
+                range: start..self.position,
+                kind: self.kind,
+                description: description(),
+            });
+        }
+    }
+}
+
+impl<'a, R, S> Loader<'a, R, ArenaBuilder<'a, S>>
+where
+    R: Read,
+    S: Storage,
+{
+    /// Make a new [`Loader`] around a reader, that loads into a new arena.
+    fn new(reader: R, options: &'a LoadOptions) -> Self {
+        Self::with_builder(reader, options, ArenaBuilder::new(options))
+    }
+
+    /// Load from the reader and get the value, along with the reader.
+    fn load(mut self) -> Result<(GenericValueArena<S>, R), Error> {
+        self.read_header()?;
+        self.load_headerless()
+    }
+
+    /// Load from the reader and get the value, along with the reader, without reading a header.
+    fn load_headerless(mut self) -> Result<(GenericValueArena<S>, R), Error> {
+        let root = self.read_value()?;
+
+        Ok(self.finish(root))
+    }
+
+    /// Get the statistics of the load so far.
+    fn stats(&self) -> LoadStats {
+        LoadStats {
+            bytes_read: self.position,
+            values: self.builder.len(),
+            symbols: self.symbol_links.len(),
+            objects: self.object_links.len(),
+            ..self.stats.clone()
         }
     }
+
+    /// Set the root of the arena, and get the arena along with the reader.
+    fn finish(self, root: ValueHandle) -> (GenericValueArena<S>, R) {
+        let arena = self
+            .builder
+            .finish(root, self.symbol_links, self.object_links);
+
+        (arena, self.reader)
+    }
 }
 
-impl<R> Loader<R>
+impl<'a, R, B> Loader<'a, R, B>
 where
     R: Read,
+    B: Builder,
 {
+    /// Turn an error from the reader into an [`Error`].
+    ///
+    /// The end of the stream becomes [`Error::UnexpectedEof`], with the current position and kind.
+    fn read_error(&self, error: ReadError) -> Error {
+        match error {
+            ReadError::UnexpectedEof => Error::UnexpectedEof {
+                position: self.position,
+                while_reading: self.kind,
+            },
+            #[cfg(feature = "std")]
+            ReadError::Io(error) => error.into(),
+        }
+    }
+
     /// Read a byte
     fn read_byte(&mut self) -> Result<u8, Error> {
         let mut byte = 0;
-        self.reader.read_exact(std::slice::from_mut(&mut byte))?;
+        io::read_exact(&mut self.reader, core::slice::from_mut(&mut byte))
+            .map_err(|error| self.read_error(error))?;
+        self.position += 1;
+        self.builder.record_bytes(core::slice::from_ref(&byte));
         Ok(byte)
     }
 
-    /// Read a byte string.
+    /// Read a byte string of a value of the given kind.
     ///
     /// A byte string is a fixnum length, then that number of bytes.
-    fn read_byte_string(&mut self) -> Result<Vec<u8>, Error> {
+    /// The length is checked against the limits before any of the bytes are read.
+    /// If the builder does not keep bytes, they are skipped and this is empty.
+    fn read_byte_string(&mut self, kind: u8) -> Result<Vec<u8>, Error> {
+        let len = self.read_byte_string_len(kind)?;
+
+        self.read_or_skip_bytes(len)
+    }
+
+    /// Read the length of a byte string of a value of the given kind,
+    /// failing if it is over the limit.
+    fn read_byte_string_len(&mut self, kind: u8) -> Result<usize, Error> {
         let len = self.read_fixnum_value()?;
         let len = usize::try_from(len).map_err(|error| Error::FixnumInvalidUSize { error })?;
+        self.check_length(kind, len)?;
 
-        let mut value = vec![0; len];
-        self.reader.read_exact(&mut value)?;
+        Ok(len)
+    }
+
+    /// Fail with [`Error::LengthLimitExceeded`] if `len` bytes are over the limit for a value
+    /// of the given kind.
+    fn check_length(&self, kind: u8, len: usize) -> Result<(), Error> {
+        match self.options.length_limit(kind) {
+            Some(limit) if len > limit => Err(Error::LengthLimitExceeded { kind, len, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read a number of raw bytes.
+    ///
+    /// The buffer grows as bytes arrive, so a large length in a short stream
+    /// fails without allocating all of it.
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        let start = self.position;
+        let mut value = Vec::with_capacity(len.min(READ_CHUNK_SIZE));
+        while value.len() < len {
+            let chunk_start = value.len();
+            value.resize(chunk_start + (len - chunk_start).min(READ_CHUNK_SIZE), 0);
+            io::read_exact(&mut self.reader, &mut value[chunk_start..])
+                .map_err(|error| self.read_error(error))?;
+        }
+        self.position += len;
+        self.builder.record_bytes(&value);
+
+        if len > 0 {
+            self.annotate(start, || {
+                format!("{len} bytes \"{}\"", DisplayByteString(&value))
+            });
+        }
 
         Ok(value)
     }
 
+    /// Skip a number of raw bytes, without buffering them.
+    fn skip_bytes(&mut self, len: usize) -> Result<(), Error> {
+        let start = self.position;
+        let mut buffer = [0; SKIP_CHUNK_SIZE];
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = remaining.min(SKIP_CHUNK_SIZE);
+            io::read_exact(&mut self.reader, &mut buffer[..chunk])
+                .map_err(|error| self.read_error(error))?;
+            remaining -= chunk;
+        }
+        self.position += len;
+
+        if len > 0 {
+            self.annotate(start, || format!("{len} bytes"));
+        }
+
+        Ok(())
+    }
+
+    /// Read a number of raw bytes if the builder keeps them, or skip them.
+    fn read_or_skip_bytes(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        if B::KEEPS_BYTES {
+            self.read_bytes(len)
+        } else {
+            self.skip_bytes(len)?;
+            Ok(Vec::new())
+        }
+    }
+
     /// Read and validate the header.
     fn read_header(&mut self) -> Result<(), Error> {
-        let major_version = self.read_byte()?;
+        let major_version = match self.read_byte() {
+            Err(Error::UnexpectedEof { .. }) => return Err(Error::EmptyInput),
+            result => result?,
+        };
         let minor_version = self.read_byte()?;
+        self.annotate(0, || format!("header {major_version}.{minor_version}"));
 
         if major_version != MAJOR_VERSION || minor_version > MINOR_VERSION {
             return Err(Error::InvalidVersion {
@@ -97,6 +591,15 @@ where
 
     /// Read a fixnum value
     fn read_fixnum_value(&mut self) -> Result<i32, Error> {
+        let start = self.position;
+        let value = self.read_fixnum_value_bytes()?;
+        self.annotate(start, || format!("fixnum {value}"));
+
+        Ok(value)
+    }
+
+    /// Read the bytes of a fixnum value, decoding it.
+    fn read_fixnum_value_bytes(&mut self) -> Result<i32, Error> {
         let len = self.read_byte()?;
         if len == 0 {
             return Ok(0);
@@ -109,7 +612,7 @@ where
                 return Ok(i32::from(byte) - 5);
             }
 
-            if usize::from(byte) > std::mem::size_of::<i32>() {
+            if usize::from(byte) > core::mem::size_of::<i32>() {
                 return Err(Error::InvalidFixnumSize { size: byte });
             }
 
@@ -126,7 +629,7 @@ where
             }
 
             let byte = -(byte as i8) as u8;
-            if usize::from(byte) > std::mem::size_of::<i32>() {
+            if usize::from(byte) > core::mem::size_of::<i32>() {
                 return Err(Error::InvalidFixnumSize { size: byte });
             }
 
@@ -141,16 +644,49 @@ where
     }
 
     /// Read a fixnum.
-    fn read_fixnum(&mut self) -> Result<TypedValueHandle<FixnumValue>, Error> {
+    fn read_fixnum(&mut self) -> Result<B::Handle, Error> {
         let value = self.read_fixnum_value()?;
-        Ok(self.arena.create_fixnum(value))
+        Ok(self.builder.create_fixnum(value))
+    }
+
+    /// Read a bignum.
+    ///
+    /// A bignum is a sign byte, a fixnum number of 16-bit words, then the little-endian magnitude.
+    fn read_bignum(&mut self) -> Result<B::Handle, Error> {
+        let start = self.position;
+        let sign = self.read_byte()?;
+        self.annotate(start, || format!("sign {:?}", char::from(sign)));
+        let positive = match sign {
+            b'+' => true,
+            b'-' => false,
+            _ => return Err(Error::InvalidBignumSign { sign }),
+        };
+
+        let len = self.read_fixnum_value()?;
+        let len = usize::try_from(len).map_err(|error| Error::FixnumInvalidUSize { error })?;
+        let len = len.saturating_mul(2);
+        self.check_length(VALUE_KIND_BIGNUM, len)?;
+        let magnitude = self.read_or_skip_bytes(len)?;
+
+        let handle = self.builder.create_bignum(positive, magnitude);
+        self.object_links.push(handle);
+
+        Ok(handle)
     }
 
     /// Read a float value
     fn read_float_value(&mut self) -> Result<f64, Error> {
-        let float = self.read_byte_string()?;
+        // Floats are always read, as they must be parsed to be checked.
+        let len = self.read_byte_string_len(VALUE_KIND_FLOAT)?;
+        let float = self.read_bytes(len)?;
+
+        // Ruby 1.8 follows the digits with a NUL and extra mantissa bytes, which are ignored.
+        let float = match float.iter().position(|byte| *byte == 0) {
+            Some(end) => &float[..end],
+            None => &float[..],
+        };
 
-        match float.as_slice() {
+        match float {
             b"nan" => {
                 Ok(f64::NAN)
             },
@@ -161,7 +697,7 @@ where
                 Ok(f64::NEG_INFINITY)
             },
             _ => {
-                Ok(std::str::from_utf8(&float)
+                Ok(core::str::from_utf8(float)
                     .map_err(|error| Error::InvalidFloatUtf8 { error })?
                     .parse::<f64>()
                     .map_err(|error| Error::InvalidFloat { error })?
@@ -171,27 +707,48 @@ where
     }
 
     /// Read a float.
-    fn read_float(&mut self) -> Result<TypedValueHandle<FloatValue>, Error> {
+    fn read_float(&mut self) -> Result<B::Handle, Error> {
         let value = self.read_float_value()?;
-        let handle = self.arena.create_float(value);
+        let handle = self.builder.create_float(value);
 
-        self.object_links.push(handle.into());
+        self.object_links.push(handle);
 
         Ok(handle)
     }
 
     /// Read a symbol.
-    fn read_symbol(&mut self) -> Result<TypedValueHandle<SymbolValue>, Error> {
-        let symbol = self.read_byte_string()?;
-        let handle = self.arena.create_symbol(symbol);
+    fn read_symbol(&mut self) -> Result<B::Symbol, Error> {
+        let symbol = self.read_byte_string(VALUE_KIND_SYMBOL)?;
+        let handle = self.builder.create_symbol(symbol);
 
         self.symbol_links.push(handle);
 
         Ok(handle)
     }
 
+    /// Read a symbol wrapped with instance variables, after the wrapper's kind byte.
+    ///
+    /// Ruby writes these for symbols with non-ASCII names, which carry their encoding.
+    fn read_symbol_with_instance_variables(&mut self) -> Result<B::Symbol, Error> {
+        let (kind, old_kind) = self.read_kind()?;
+        if kind != VALUE_KIND_SYMBOL {
+            return Err(Error::UnexpectedValueKind {
+                expected: VALUE_KIND_SYMBOL,
+                actual: kind,
+            });
+        }
+        let handle = self.read_symbol()?;
+        self.kind = old_kind;
+
+        let instance_variables = self.read_instance_variables()?;
+        self.builder
+            .set_instance_variables(handle.into(), instance_variables)?;
+
+        Ok(handle)
+    }
+
     /// Read a symbol link.
-    fn read_symbol_link(&mut self) -> Result<TypedValueHandle<SymbolValue>, Error> {
+    fn read_symbol_link(&mut self) -> Result<B::Symbol, Error> {
         let index = self.read_fixnum_value()?;
         let index = usize::try_from(index).map_err(|error| Error::FixnumInvalidUSize { error })?;
 
@@ -199,12 +756,13 @@ where
             .symbol_links
             .get(index)
             .ok_or(Error::MissingSymbolLink { index })?;
+        self.stats.symbol_links_resolved += 1;
 
         Ok(*value)
     }
 
     /// Read an object link
-    fn read_object_link(&mut self) -> Result<ValueHandle, Error> {
+    fn read_object_link(&mut self) -> Result<B::Handle, Error> {
         let index = self.read_fixnum_value()?;
         let index = usize::try_from(index).map_err(|error| Error::FixnumInvalidUSize { error })?;
 
@@ -212,20 +770,19 @@ where
             .object_links
             .get(index)
             .ok_or(Error::MissingObjectLink { index })?;
+        self.stats.object_links_resolved += 1;
 
         Ok(*value)
     }
 
     /// Read instance variables.
-    fn read_instance_variables(
-        &mut self,
-    ) -> Result<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>, Error> {
+    fn read_instance_variables(&mut self) -> Result<InstanceVariables<B>, Error> {
         let num_pairs = self.read_fixnum_value()?;
         let num_pairs =
             usize::try_from(num_pairs).map_err(|error| Error::FixnumInvalidUSize { error })?;
 
         // TODO: Consider making this a map.
-        let mut instance_variables = Vec::with_capacity(num_pairs);
+        let mut instance_variables = B::List::with_capacity(num_pairs);
         for _ in 0..num_pairs {
             let symbol = self.read_value_symbol_like()?;
             let value = self.read_value()?;
@@ -237,35 +794,34 @@ where
     }
 
     /// Read an array
-    fn read_array(&mut self) -> Result<TypedValueHandle<ArrayValue>, Error> {
-        let handle = self.arena.create_nil().into_raw();
+    fn read_array(&mut self) -> Result<B::Handle, Error> {
+        let handle = self.builder.reserve(ValueKind::Array);
         self.object_links.push(handle);
 
         let len = self.read_fixnum_value()?;
         let len = usize::try_from(len).map_err(|error| Error::FixnumInvalidUSize { error })?;
-        let mut array_value = Vec::with_capacity(len);
+        let mut array_value = B::List::with_capacity(len);
 
         for _ in 0..len {
             let value = self.read_value()?;
             array_value.push(value);
         }
 
-        *self.arena.get_mut(handle).unwrap() = ArrayValue::new(array_value).into();
+        self.builder.finish_array(handle, array_value);
 
-        Ok(TypedValueHandle::new_unchecked(handle))
+        Ok(handle)
     }
 
     /// Read a hash.
-    fn read_hash(&mut self, has_default_value: bool) -> Result<TypedValueHandle<HashValue>, Error> {
-        let handle = self.arena.create_nil().into_raw();
+    fn read_hash(&mut self, has_default_value: bool) -> Result<B::Handle, Error> {
+        let handle = self.builder.reserve(ValueKind::Hash);
         self.object_links.push(handle);
 
         let num_pairs = self.read_fixnum_value()?;
         let num_pairs =
             usize::try_from(num_pairs).map_err(|error| Error::FixnumInvalidUSize { error })?;
 
-        // TODO: Consider making this a map.
-        let mut pairs = Vec::with_capacity(num_pairs);
+        let mut pairs = B::List::with_capacity(num_pairs);
         for _ in 0..num_pairs {
             let key = self.read_value()?;
             let value = self.read_value()?;
@@ -279,131 +835,1420 @@ where
             None
         };
 
-        *self.arena.get_mut(handle).unwrap() = HashValue::new(pairs, default_value).into();
+        self.builder.finish_hash(handle, pairs, default_value)?;
 
-        Ok(TypedValueHandle::new_unchecked(handle))
+        Ok(handle)
     }
 
     /// Read an object
-    fn read_object(&mut self) -> Result<TypedValueHandle<ObjectValue>, Error> {
-        let handle = self.arena.create_nil().into_raw();
+    fn read_object(&mut self) -> Result<B::Handle, Error> {
+        let handle = self.builder.reserve(ValueKind::Object);
         self.object_links.push(handle);
 
         let name = self.read_value_symbol_like()?;
         let instance_variables = self.read_instance_variables()?;
 
-        *self.arena.get_mut(handle).unwrap() = ObjectValue::new(name, instance_variables).into();
+        self.builder.finish_object(handle, name, instance_variables);
 
-        Ok(TypedValueHandle::new_unchecked(handle))
+        Ok(handle)
+    }
+
+    /// Read a struct
+    fn read_struct(&mut self) -> Result<B::Handle, Error> {
+        let handle = self.builder.reserve(ValueKind::Struct);
+        self.object_links.push(handle);
+
+        let name = self.read_value_symbol_like()?;
+        let members = self.read_instance_variables()?;
+
+        self.builder.finish_struct(handle, name, members);
+
+        Ok(handle)
     }
 
     /// Read a string
-    fn read_string(&mut self) -> Result<TypedValueHandle<StringValue>, Error> {
-        let data = self.read_byte_string()?;
+    fn read_string(&mut self) -> Result<B::Handle, Error> {
+        let data = self.read_byte_string(VALUE_KIND_STRING)?;
 
-        let handle = self.arena.create_string(data);
-        self.object_links.push(handle.into());
+        let handle = self.builder.create_string(data);
+        self.object_links.push(handle);
+
+        Ok(handle)
+    }
+
+    /// Read a regexp
+    fn read_regexp(&mut self) -> Result<B::Handle, Error> {
+        let source = self.read_byte_string(VALUE_KIND_REGEXP)?;
+        let options = self.read_byte()?;
+
+        let handle = self.builder.create_regexp(source, options);
+        self.object_links.push(handle);
 
         Ok(handle)
     }
 
     /// Read a user defined
-    fn read_user_defined(&mut self) -> Result<TypedValueHandle<UserDefinedValue>, Error> {
+    fn read_user_defined(&mut self) -> Result<B::Handle, Error> {
         let name = self.read_value_symbol_like()?;
-        let value = self.read_byte_string()?;
+        let value = self.read_byte_string(VALUE_KIND_USER_DEFINED)?;
 
-        let handle = self.arena.create_user_defined(name, value);
-        self.object_links.push(handle.into());
+        let handle = self.builder.create_user_defined(name, value);
+        self.object_links.push(handle);
+
+        Ok(handle)
+    }
+
+    /// Read a user marshal value.
+    fn read_user_marshal(&mut self) -> Result<B::Handle, Error> {
+        let handle = self.builder.reserve(ValueKind::UserMarshal);
+        self.object_links.push(handle);
+
+        let name = self.read_value_symbol_like()?;
+        let value = self.read_value()?;
+
+        self.builder.finish_user_marshal(handle, name, value);
 
         Ok(handle)
     }
 
     /// Read a class.
-    fn read_class(&mut self) -> Result<TypedValueHandle<ClassValue>, Error> {
-        let class = self.read_byte_string()?;
-        let handle = self.arena.create_class(class);
+    fn read_class(&mut self) -> Result<B::Handle, Error> {
+        let class = self.read_byte_string(VALUE_KIND_CLASS)?;
+        let handle = self.builder.create_class(class);
 
-        self.object_links.push(handle.into());
+        self.object_links.push(handle);
 
         Ok(handle)
     }
 
+    /// Check that the number of created values is within the limit.
+    fn check_value_limit(&self) -> Result<(), Error> {
+        if let Some(limit) = self.options.max_values {
+            if self.builder.len() > limit {
+                return Err(Error::ValueLimitExceeded { limit });
+            }
+        }
+
+        self.check_abort()
+    }
+
+    /// Go one level deeper, failing if that is past the depth limit.
+    ///
+    /// The caller should decrement the depth once the value is read.
+    fn enter_value(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        self.stats.max_depth = self.stats.max_depth.max(self.depth);
+        match self.options.max_depth {
+            Some(limit) if self.depth > limit => Err(Error::DepthLimitExceeded { limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail with [`Error::Aborted`] if the abort signal is set.
+    fn check_abort(&self) -> Result<(), Error> {
+        match &self.options.should_abort {
+            Some(should_abort) if should_abort.should_abort() => Err(Error::Aborted),
+            _ => Ok(()),
+        }
+    }
+
     /// Read the next value, failing if it is not a symbol-like value.
-    fn read_value_symbol_like(&mut self) -> Result<TypedValueHandle<SymbolValue>, Error> {
+    fn read_value_symbol_like(&mut self) -> Result<B::Symbol, Error> {
+        let (kind, old_kind) = self.read_kind()?;
+        let handle = match kind {
+            VALUE_KIND_SYMBOL => self.read_symbol()?,
+            VALUE_KIND_SYMBOL_LINK => self.read_symbol_link()?,
+            VALUE_KIND_INSTANCE_VARIABLES => self.read_symbol_with_instance_variables()?,
+            _ => {
+                return Err(Error::UnexpectedValueKind {
+                    expected: VALUE_KIND_SYMBOL,
+                    actual: kind,
+                })
+            }
+        };
+        self.kind = old_kind;
+
+        Ok(handle)
+    }
+
+    /// Read a value kind byte, making it the current kind.
+    ///
+    /// # Returns
+    /// Returns the kind and the previous kind, which should be restored after the value is read.
+    fn read_kind(&mut self) -> Result<(u8, Option<u8>), Error> {
+        let start = self.position;
         let kind = self.read_byte()?;
-        match kind {
-            VALUE_KIND_SYMBOL => self.read_symbol(),
-            VALUE_KIND_SYMBOL_LINK => self.read_symbol_link(),
-            _ => Err(Error::UnexpectedValueKind {
-                expected: VALUE_KIND_SYMBOL,
-                actual: kind,
-            }),
-        }
+        let old_kind = self.kind.replace(kind);
+        self.annotate(start, || format!("value kind {:?}", char::from(kind)));
+
+        Ok((kind, old_kind))
     }
 
     /// Read the next value.
-    fn read_value(&mut self) -> Result<ValueHandle, Error> {
-        let kind = self.read_byte()?;
-        match kind {
-            VALUE_KIND_NIL => Ok(self.arena.create_nil().into()),
-            VALUE_KIND_TRUE => Ok(self.arena.create_bool(true).into()),
-            VALUE_KIND_FALSE => Ok(self.arena.create_bool(false).into()),
-            VALUE_KIND_FIXNUM => Ok(self.read_fixnum()?.into()),
-            VALUE_KIND_FLOAT => Ok(self.read_float()?.into()),
-            VALUE_KIND_SYMBOL => Ok(self.read_symbol()?.into()),
-            VALUE_KIND_SYMBOL_LINK => Ok(self.read_symbol_link()?.into()),
-            VALUE_KIND_OBJECT_LINK => Ok(self.read_object_link()?),
+    fn read_value(&mut self) -> Result<B::Handle, Error> {
+        let start = self.position;
+        let symbol_links_start = self.symbol_links.len();
+        let object_links_start = self.object_links.len();
+        self.enter_value()?;
+
+        let (kind, old_kind) = self.read_kind()?;
+        let handle = match kind {
+            VALUE_KIND_NIL => self.builder.create_nil(),
+            VALUE_KIND_TRUE => self.builder.create_bool(true),
+            VALUE_KIND_FALSE => self.builder.create_bool(false),
+            VALUE_KIND_FIXNUM => self.read_fixnum()?,
+            VALUE_KIND_BIGNUM => self.read_bignum()?,
+            VALUE_KIND_FLOAT => self.read_float()?,
+            VALUE_KIND_SYMBOL => self.read_symbol()?.into(),
+            VALUE_KIND_SYMBOL_LINK => self.read_symbol_link()?.into(),
+            VALUE_KIND_OBJECT_LINK => self.read_object_link()?,
             VALUE_KIND_INSTANCE_VARIABLES => {
                 let value = self.read_value()?;
-
                 let instance_variables = self.read_instance_variables()?;
+                self.builder
+                    .set_instance_variables(value, instance_variables)?;
 
-                match self
-                    .arena
-                    .get_mut(value)
-                    .ok_or(Error::InvalidValueHandle { handle: value })?
-                {
-                    Value::String(value) => {
-                        value.set_instance_variables(Some(instance_variables));
-                    }
-                    Value::UserDefined(value) => {
-                        value.set_instance_variables(Some(instance_variables));
-                    }
-                    _ => return Err(Error::NotAnObject),
-                }
+                value
+            }
+            VALUE_KIND_USER_CLASS => {
+                let name = self.read_value_symbol_like()?;
+                let value = self.read_value()?;
+                self.builder.set_user_class(value, name)?;
+
+                value
+            }
+            VALUE_KIND_EXTENDED => {
+                let module = self.read_value_symbol_like()?;
+                let value = self.read_value()?;
+                self.builder.extend_value(value, module)?;
 
-                Ok(value)
+                value
             }
-            VALUE_KIND_ARRAY => Ok(self.read_array()?.into()),
-            VALUE_KIND_HASH => Ok(self.read_hash(false)?.into()),
-            VALUE_KIND_HASH_DEFAULT => Ok(self.read_hash(true)?.into()),
-            VALUE_KIND_OBJECT => Ok(self.read_object()?.into()),
-            VALUE_KIND_STRING => Ok(self.read_string()?.into()),
-            VALUE_KIND_USER_DEFINED => Ok(self.read_user_defined()?.into()),
-            VALUE_KIND_CLASS => Ok(self.read_class()?.into()),
-            _ => Err(Error::InvalidValueKind { kind }),
+            VALUE_KIND_ARRAY => self.read_array()?,
+            VALUE_KIND_HASH => self.read_hash(false)?,
+            VALUE_KIND_HASH_DEFAULT => self.read_hash(true)?,
+            VALUE_KIND_OBJECT => self.read_object()?,
+            VALUE_KIND_STRUCT => self.read_struct()?,
+            VALUE_KIND_STRING => self.read_string()?,
+            VALUE_KIND_REGEXP => self.read_regexp()?,
+            VALUE_KIND_USER_DEFINED => self.read_user_defined()?,
+            VALUE_KIND_USER_MARSHAL => self.read_user_marshal()?,
+            VALUE_KIND_CLASS => self.read_class()?,
+            _ => return Err(Error::InvalidValueKind { kind }),
+        };
+
+        self.kind = old_kind;
+        self.depth -= 1;
+        self.check_value_limit()?;
+
+        // Links refer to values that already have a span.
+        if kind != VALUE_KIND_SYMBOL_LINK && kind != VALUE_KIND_OBJECT_LINK {
+            self.builder.record_span(
+                handle,
+                SourceSpan {
+                    bytes: start..self.position,
+                    symbol_links: symbol_links_start..self.symbol_links.len(),
+                    object_links: object_links_start..self.object_links.len(),
+                },
+            );
         }
-    }
 
-    /// Load from the reader and get the value.
-    fn load(mut self) -> Result<ValueArena, Error> {
-        self.read_header()?;
-        let root = self.read_value()?;
-        let _old_root = self.arena.replace_root(root);
+        Ok(handle)
+    }
+}
 
-        // TODO: Delete old root.
+/// Fail with [`Error::TrailingBytes`] if the reader is not at its end,
+/// ignoring `\n`, `\r` and spaces if `allow_whitespace` is set.
+fn check_eof<R>(mut reader: R, allow_whitespace: bool) -> Result<(), Error>
+where
+    R: Read,
+{
+    let mut buffer = [0; SKIP_CHUNK_SIZE];
+    let mut len = 0;
+    loop {
+        let chunk_len = io::read(&mut reader, &mut buffer)?;
+        if chunk_len == 0 {
+            break;
+        }
 
-        Ok(self.arena)
+        let chunk = &buffer[..chunk_len];
+        len += if allow_whitespace {
+            chunk
+                .iter()
+                .filter(|byte| !matches!(byte, b'\n' | b'\r' | b' '))
+                .count()
+        } else {
+            chunk.len()
+        };
     }
+    if len != 0 {
+        return Err(Error::TrailingBytes { len });
+    }
+
+    Ok(())
 }
 
 /// Load from a reader.
+///
+/// Nothing is read past the end of the value, so the reader may be a pipe or socket
+/// that stays open afterwards.
+/// Reader errors other than running out of data, like [`std::io::ErrorKind::WouldBlock`],
+/// are returned as [`Error::Io`], and loading cannot be resumed after them.
 pub fn load<R>(reader: R) -> Result<ValueArena, Error>
 where
     R: Read,
 {
-    let loader = Loader::new(reader);
-    let value_arena = loader.load()?;
+    load_with_options(reader, &LoadOptions::default())
+}
+
+/// Read the version from the header of a stream, without reading anything after it.
+///
+/// The version is not checked, so this also works for streams [`load`] would reject.
+///
+/// # Returns
+/// Returns the major and minor version.
+pub fn read_version<R>(mut reader: R) -> Result<(u8, u8), Error>
+where
+    R: Read,
+{
+    let mut header = [0; 2];
+    if io::read(&mut reader, &mut header[..1])? == 0 {
+        return Err(Error::EmptyInput);
+    }
+    io::read_exact(&mut reader, &mut header[1..]).map_err(|error| match error {
+        ReadError::UnexpectedEof => Error::UnexpectedEof {
+            position: 1,
+            while_reading: None,
+        },
+        #[cfg(feature = "std")]
+        ReadError::Io(error) => error.into(),
+    })?;
+
+    Ok((header[0], header[1]))
+}
+
+/// Load from a reader and convert the root value, dropping the arena.
+///
+/// The whole arena is still built, as links can refer to any earlier value,
+/// so this is a convenience over [`load`] and [`FromValueContext::from_value`].
+pub fn load_typed<T, R>(reader: R) -> Result<T, Error>
+where
+    T: for<'a> FromValue<'a>,
+    R: Read,
+{
+    let arena = load(reader)?;
+    let ctx = FromValueContext::new(&arena);
+    let value = ctx.from_value(arena.root())?;
+
+    Ok(value)
+}
+
+/// Load from a reader that has no header.
+///
+/// This is non-standard, and intended for formats that embed Marshal values without the header.
+/// Data produced by Ruby's `Marshal.dump` must be read with [`load`] instead.
+pub fn load_headerless<R>(reader: R) -> Result<ValueArena, Error>
+where
+    R: Read,
+{
+    let options = LoadOptions::default();
+    let loader: Loader<'_, R> = Loader::new(reader, &options);
+    let (value_arena, _reader) = loader.load_headerless()?;
+
+    Ok(value_arena)
+}
+
+/// Parse a value from a reader, annotating each span of the stream with what it encodes.
+///
+/// Annotations are ordered by position, and cover every byte read without gaps.
+/// This is intended for debugging and reverse-engineering.
+///
+/// # Returns
+/// Returns the annotations, and the error if the stream could not be parsed.
+/// On an error, the annotations cover the stream up to the span that failed.
+pub fn annotate<R>(reader: R) -> (Vec<Annotation>, Option<Error>)
+where
+    R: Read,
+{
+    let options = LoadOptions::default();
+    let mut loader: Loader<'_, R> = Loader::new(reader, &options);
+    loader.annotations = Some(Vec::new());
+
+    let result = loader
+        .read_header()
+        .and_then(|()| loader.read_value().map(|_| ()));
+
+    (loader.annotations.unwrap_or_default(), result.err())
+}
+
+/// Compare two Marshal streams span by span, as annotated by [`annotate`].
+///
+/// Every span whose description differs is reported, up to the first span where the streams
+/// stop lining up, because its kind or length differs or one stream ends.
+/// Nothing after that point is compared, as the spans no longer correspond.
+///
+/// A stream that fails to parse is compared up to the failure, which is reported as a span
+/// covering the rest of the stream, described by the error.
+///
+/// # Returns
+/// Returns an empty list if the streams are identical.
+pub fn diff_bytes(a: &[u8], b: &[u8]) -> Vec<ByteDiff> {
+    let (a, a_failed) = annotate_for_diff(a);
+    let (b, b_failed) = annotate_for_diff(b);
+
+    let mut diffs = Vec::new();
+    for (index, (a_span, b_span)) in a.iter().zip(b.iter()).enumerate() {
+        if a_span.description == b_span.description {
+            continue;
+        }
+
+        diffs.push(ByteDiff {
+            a: Some(a_span.clone()),
+            b: Some(b_span.clone()),
+        });
+        // Nothing lines up after a failure, even if the spans happen to be the same size.
+        let failed = (a_failed && index + 1 == a.len()) || (b_failed && index + 1 == b.len());
+        if failed || a_span.kind != b_span.kind || a_span.range.len() != b_span.range.len() {
+            return diffs;
+        }
+    }
+    if a.len() != b.len() {
+        diffs.push(ByteDiff {
+            a: a.get(b.len()).cloned(),
+            b: b.get(a.len()).cloned(),
+        });
+    }
+
+    diffs
+}
+
+/// Annotate a stream for [`diff_bytes`].
+///
+/// If the stream fails to parse, the last annotation spans the rest of it and describes the error.
+///
+/// # Returns
+/// Returns the annotations, and whether the stream failed to parse.
+fn annotate_for_diff(data: &[u8]) -> (Vec<Annotation>, bool) {
+    let (mut annotations, error) = annotate(data);
+    let Some(error) = error else {
+        return (annotations, false);
+    };
+
+    let last = annotations.last();
+    let start = last.map_or(0, |annotation| annotation.range.end);
+    let kind = match error {
+        Error::UnexpectedEof { while_reading, .. } => while_reading,
+        _ => last.and_then(|annotation| annotation.kind),
+    };
+    annotations.push(Annotation {
+        range: start..data.len(),
+        kind,
+        description: error.to_string(),
+    });
+
+    (annotations, true)
+}
+
+/// Check that a reader holds a well-formed stream within the limits of the options, without
+/// loading it.
+///
+/// This parses the stream as [`load_with_options`] would, but only keeps the kind of each link
+/// table entry, and skips byte strings without buffering them, so it uses far less memory.
+/// [`LoadOptions::on_duplicate_hash_key`] is not checked, since that needs the keys.
+/// Trailing input is rejected as by [`load_with_options`] if [`LoadOptions::strict_eof`] is set.
+pub fn validate_stream<R>(reader: R, options: &LoadOptions) -> Result<(), Error>
+where
+    R: Read,
+{
+    let mut loader = Loader::with_builder(reader, options, KindBuilder::default());
+    loader.read_header()?;
+    loader.read_value()?;
+
+    if options.strict_eof {
+        check_eof(loader.reader, options.allow_trailing_whitespace)?;
+    }
+
+    Ok(())
+}
+
+/// Load from a reader, with options.
+pub fn load_with_options<R>(reader: R, options: &LoadOptions) -> Result<ValueArena, Error>
+where
+    R: Read,
+{
+    load_with_storage(reader, options)
+}
+
+/// Load from a reader, with options, into an arena with a custom [`Storage`].
+pub fn load_with_storage<R, S>(
+    reader: R,
+    options: &LoadOptions,
+) -> Result<GenericValueArena<S>, Error>
+where
+    R: Read,
+    S: Storage,
+{
+    let loader = Loader::new(reader, options);
+    let (value_arena, reader) = loader.load()?;
+
+    if options.strict_eof {
+        check_eof(reader, options.allow_trailing_whitespace)?;
+    }
 
     Ok(value_arena)
 }
+
+/// Load one value from a reader, and get the reader back.
+///
+/// The reader is positioned just after the value, so any data following it can still be read.
+pub fn load_and_recover<R>(reader: R) -> Result<(ValueArena, R), Error>
+where
+    R: Read,
+{
+    let options = LoadOptions::default();
+    let loader = Loader::new(reader, &options);
+
+    loader.load()
+}
+
+/// Load from a reader, and get statistics about the load.
+pub fn load_with_stats<R>(reader: R) -> Result<(ValueArena, LoadStats), Error>
+where
+    R: Read,
+{
+    let options = LoadOptions::default();
+    let mut loader: Loader<'_, R> = Loader::new(reader, &options);
+    loader.read_header()?;
+    let root = loader.read_value()?;
+    let stats = loader.stats();
+    let (value_arena, _reader) = loader.finish(root);
+
+    Ok((value_arena, stats))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dump;
+    use crate::dump_headerless;
+    use crate::RegexpOptions;
+    use crate::StringValue;
+    use crate::SymbolValue;
+    use crate::Value;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn degenerate_roots() {
+        for data in [
+            &b"\x04\x08:\x08foo"[..],
+            b"\x04\x080",
+            b"\x04\x08T",
+            b"\x04\x08i\x2f",
+        ] {
+            let value_arena = load(data).expect("failed to load");
+
+            let mut new_data = Vec::new();
+            dump(&mut new_data, &value_arena).expect("failed to dump");
+            assert!(data == new_data, "{data:?} != {new_data:?}");
+        }
+    }
+
+    #[test]
+    fn root_symbol_is_not_object_link() {
+        let options = LoadOptions::default();
+        let mut loader: Loader<'_, _> = Loader::new(&b"\x04\x08:\x08foo"[..], &options);
+        loader.read_header().expect("failed to read header");
+        let root = loader.read_value().expect("failed to read value");
+
+        assert!(loader.object_links.is_empty());
+        assert!(loader.symbol_links.len() == 1);
+        assert!(loader.symbol_links[0].into_raw() == root);
+    }
+
+    #[test]
+    fn max_values() {
+        let data = b"\x04\x08[\x08i\x06i\x07i\x08";
+
+        let options = LoadOptions {
+            max_values: Some(3),
+            ..LoadOptions::default()
+        };
+        let error = load_with_options(&data[..], &options).expect_err("loaded over the limit");
+        assert!(matches!(error, Error::ValueLimitExceeded { limit: 3 }));
+
+        let options = LoadOptions {
+            max_values: Some(4),
+            ..LoadOptions::default()
+        };
+        load_with_options(&data[..], &options).expect("failed to load");
+    }
+
+    #[test]
+    fn load_with_stats() {
+        // s = "x"; Marshal.dump([:a, :a, s, s])
+        let data = b"\x04\x08[\x09:\x06a;\x00I\"\x06x\x06:\x06ET@\x06";
+        let (_arena, stats) = super::load_with_stats(&data[..]).expect("failed to load");
+        let expected = LoadStats {
+            bytes_read: data.len(),
+            values: 5,
+            symbols: 2,
+            objects: 2,
+            max_depth: 3,
+            symbol_links_resolved: 1,
+            object_links_resolved: 1,
+        };
+        assert!(stats == expected, "{stats:?} != {expected:?}");
+    }
+
+    #[test]
+    fn should_abort() {
+        let data = b"\x04\x08[\x08i\x06i\x07i\x08";
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let options = LoadOptions {
+            should_abort: Some(AbortSignal::new({
+                let calls = calls.clone();
+                move || calls.fetch_add(1, Ordering::SeqCst) >= 1
+            })),
+            ..LoadOptions::default()
+        };
+        let error = load_with_options(&data[..], &options).expect_err("loaded after aborting");
+        assert!(matches!(error, Error::Aborted));
+        assert!(calls.load(Ordering::SeqCst) == 2);
+
+        calls.store(0, Ordering::SeqCst);
+        let error =
+            super::validate_stream(&data[..], &options).expect_err("validated after aborting");
+        assert!(matches!(error, Error::Aborted));
+    }
+
+    #[test]
+    fn normalize_strings_to_utf8() {
+        // Marshal.dump("あ".encode("Shift_JIS"))
+        let data = b"\x04\x08I\"\x07\x82\xa0\x06:\x0dencoding\"\x0eShift_JIS";
+
+        let mut options = LoadOptions {
+            normalize_strings_to_utf8: true,
+            ..LoadOptions::default()
+        };
+        options.string_decoders.insert(
+            b"Shift_JIS".to_vec(),
+            StringDecoder::new(|bytes| match bytes {
+                b"\x82\xa0" => Some("\u{3042}".to_string()),
+                _ => None,
+            }),
+        );
+        let arena = load_with_options(&data[..], &options).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let string: &StringValue = ctx.from_value(arena.root()).expect("not a string");
+        assert!(string.value() == "\u{3042}".as_bytes());
+        let instance_variables = string.instance_variables().expect("missing ivars");
+        assert!(instance_variables.len() == 1);
+        let (key, value) = instance_variables[0];
+        let key: &SymbolValue = ctx.from_value(key.into()).expect("not a symbol");
+        assert!(key.value() == b"E");
+        let value: bool = ctx.from_value(value).expect("not a bool");
+        assert!(value);
+
+        options.normalize_strings_to_utf8 = false;
+        let arena = load_with_options(&data[..], &options).expect("failed to load");
+        let string: &StringValue = FromValueContext::new(&arena)
+            .from_value(arena.root())
+            .expect("not a string");
+        assert!(string.value() == b"\x82\xa0");
+    }
+
+    #[test]
+    fn normalize_strings_share_encoding_symbol() {
+        let mut options = LoadOptions {
+            normalize_strings_to_utf8: true,
+            ..LoadOptions::default()
+        };
+        options.string_decoders.insert(
+            b"Shift_JIS".to_vec(),
+            StringDecoder::new(|bytes| match bytes {
+                b"\x82\xa0" => Some("\u{3042}".to_string()),
+                _ => None,
+            }),
+        );
+
+        // s = "あ".encode("Shift_JIS"); Marshal.dump([s, s.dup])
+        let data = b"\x04\x08[\x07I\"\x07\x82\xa0\x06:\x0dencoding\"\x0eShift_JIS\
+            I\"\x07\x82\xa0\x06;\x00@\x07";
+        let arena = load_with_options(&data[..], &options).expect("failed to load");
+        // The now unused `encoding` symbol, and one `E` symbol.
+        assert!(arena.symbol_count() == 2);
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        let expected = b"\x04\x08[\x07I\"\x08\xe3\x81\x82\x06:\x06ETI\"\x08\xe3\x81\x82\x06;\x00T";
+        assert!(new_data == expected[..], "{new_data:?} != {expected:?}");
+
+        // Marshal.dump(["x", "あ".encode("Shift_JIS")])
+        let data = b"\x04\x08[\x07I\"\x06x\x06:\x06ETI\"\x07\x82\xa0\x06:\x0dencoding\
+            \"\x0eShift_JIS";
+        let arena = load_with_options(&data[..], &options).expect("failed to load");
+        assert!(arena.symbol_count() == 2);
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        let expected = b"\x04\x08[\x07I\"\x06x\x06:\x06ETI\"\x08\xe3\x81\x82\x06;\x00T";
+        assert!(new_data == expected[..], "{new_data:?} != {expected:?}");
+    }
+
+    #[test]
+    fn linked_encoding_name() {
+        // s = "あ".encode("Shift_JIS"); Marshal.dump([s, s.dup])
+        // Ruby writes each encoding name once, and links to it after that.
+        let data = b"\x04\x08[\x07I\"\x07\x82\xa0\x06:\x0dencoding\"\x0eShift_JIS\
+            I\"\x07\x82\xa0\x06;\x00@\x07";
+        let arena = load(&data[..]).expect("failed to load");
+        let ctx = FromValueContext::new(&arena);
+        let strings: Vec<&StringValue> = ctx.from_value(arena.root()).expect("not strings");
+        let encodings: Vec<ValueHandle> = strings
+            .iter()
+            .map(|string| string.instance_variables().expect("missing ivars")[0].1)
+            .collect();
+        assert!(encodings[0] == encodings[1]);
+        let encoding: &StringValue = ctx.from_value(encodings[0]).expect("not a string");
+        assert!(encoding.value() == b"Shift_JIS");
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+        super::validate_stream(&data[..], &LoadOptions::default()).expect("failed to validate");
+
+        let mut options = LoadOptions {
+            normalize_strings_to_utf8: true,
+            ..LoadOptions::default()
+        };
+        options.string_decoders.insert(
+            b"Shift_JIS".to_vec(),
+            StringDecoder::new(|bytes| (bytes == b"\x82\xa0").then(|| "\u{3042}".to_string())),
+        );
+        let arena = load_with_options(&data[..], &options).expect("failed to load");
+        let strings: Vec<&StringValue> = FromValueContext::new(&arena)
+            .from_value(arena.root())
+            .expect("not strings");
+        for string in strings {
+            assert!(string.value() == "\u{3042}".as_bytes());
+        }
+    }
+
+    #[test]
+    fn max_depth() {
+        // Marshal.dump([[[]]])
+        let data = b"\x04\x08[\x06[\x06[\x00";
+        let options = LoadOptions {
+            max_depth: Some(2),
+            ..LoadOptions::default()
+        };
+        let error = load_with_options(&data[..], &options).expect_err("loaded past the limit");
+        assert!(matches!(error, Error::DepthLimitExceeded { limit: 2 }));
+        let error = super::validate_stream(&data[..], &options).expect_err("validated");
+        assert!(matches!(error, Error::DepthLimitExceeded { limit: 2 }));
+
+        let options = LoadOptions {
+            max_depth: Some(3),
+            ..LoadOptions::default()
+        };
+        load_with_options(&data[..], &options).expect("failed to load at the limit");
+        super::validate_stream(&data[..], &options).expect("failed to validate at the limit");
+
+        // A stream nested far past the default limit fails cleanly instead of overflowing.
+        let mut data = b"\x04\x08".to_vec();
+        data.extend(core::iter::repeat_n(b"[\x06", 100_000).flatten());
+        data.extend(b"0");
+        let error = load(&data[..]).expect_err("loaded past the default limit");
+        assert!(matches!(error, Error::DepthLimitExceeded { limit: 256 }));
+        let error = super::validate_stream(&data[..], &LoadOptions::default())
+            .expect_err("validated past the default limit");
+        assert!(matches!(error, Error::DepthLimitExceeded { limit: 256 }));
+    }
+
+    #[test]
+    fn max_byte_string_len() {
+        // A string that claims to be 2GB long, in a 9 byte stream.
+        let data = b"\x04\x08\"\x04\xff\xff\xff\x7fa";
+
+        let error = load(&data[..]).expect_err("loaded a truncated string");
+        assert!(matches!(error, Error::UnexpectedEof { position: 8, .. }));
+
+        let options = LoadOptions {
+            max_byte_string_len: Some(1024),
+            ..LoadOptions::default()
+        };
+        let error = load_with_options(&data[..], &options).expect_err("loaded over the limit");
+        assert!(matches!(
+            error,
+            Error::LengthLimitExceeded {
+                kind: b'"',
+                len: 0x7fff_ffff,
+                limit: 1024
+            }
+        ));
+        let error = super::validate_stream(&data[..], &options).expect_err("validated");
+        assert!(matches!(error, Error::LengthLimitExceeded { .. }));
+
+        // Marshal.dump(2**70), whose magnitude is 5 words, or 10 bytes
+        let data = b"\x04\x08l+\x0a\x00\x00\x00\x00\x00\x00\x00\x00\x40\x00";
+        let options = LoadOptions {
+            max_byte_string_len: Some(8),
+            ..LoadOptions::default()
+        };
+        let error = load_with_options(&data[..], &options).expect_err("loaded over the limit");
+        assert!(matches!(
+            error,
+            Error::LengthLimitExceeded {
+                kind: b'l',
+                len: 10,
+                limit: 8
+            }
+        ));
+        let error = super::validate_stream(&data[..], &options).expect_err("validated");
+        assert!(matches!(
+            error,
+            Error::LengthLimitExceeded { kind: b'l', .. }
+        ));
+    }
+
+    #[test]
+    fn length_limits() {
+        // Marshal.dump(["abcd", :abc])
+        let data = b"\x04\x08[\x07I\"\x09abcd\x06:\x06ET:\x08abc";
+
+        let options = LoadOptions {
+            max_string_length: Some(3),
+            ..LoadOptions::default()
+        };
+        let error = load_with_options(&data[..], &options).expect_err("loaded over the limit");
+        assert!(matches!(
+            error,
+            Error::LengthLimitExceeded {
+                kind: b'"',
+                len: 4,
+                limit: 3
+            }
+        ));
+        let error = super::validate_stream(&data[..], &options).expect_err("validated");
+        assert!(matches!(
+            error,
+            Error::LengthLimitExceeded { kind: b'"', .. }
+        ));
+
+        let options = LoadOptions {
+            max_symbol_length: Some(2),
+            ..LoadOptions::default()
+        };
+        let error = load_with_options(&data[..], &options).expect_err("loaded over the limit");
+        assert!(matches!(
+            error,
+            Error::LengthLimitExceeded {
+                kind: b':',
+                len: 3,
+                limit: 2
+            }
+        ));
+        let error = super::validate_stream(&data[..], &options).expect_err("validated");
+        assert!(matches!(
+            error,
+            Error::LengthLimitExceeded { kind: b':', .. }
+        ));
+
+        let options = LoadOptions {
+            max_string_length: Some(4),
+            max_symbol_length: Some(3),
+            ..LoadOptions::default()
+        };
+        load_with_options(&data[..], &options).expect("failed to load");
+        super::validate_stream(&data[..], &options).expect("failed to validate");
+
+        // The smaller of the general and per-kind limits applies.
+        let options = LoadOptions {
+            max_string_length: Some(10),
+            max_byte_string_len: Some(3),
+            ..LoadOptions::default()
+        };
+        let error = load_with_options(&data[..], &options).expect_err("loaded over the limit");
+        assert!(matches!(
+            error,
+            Error::LengthLimitExceeded {
+                kind: b'"',
+                len: 4,
+                limit: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn validate_stream() {
+        let valid: [&[u8]; 4] = [
+            b"\x04\x08[\x08i\x06i\x07i\x08",
+            b"\x04\x08[\x08:\x06a\"\x06b;\x00",
+            b"\x04\x08IC:\x0bMyHash}\x06:\x06ai\x06i\x00\x06:\x0a@metai\x0c",
+            b"\x04\x08[\x07I\"\x06a\x06:\x06ET@\x06",
+        ];
+        for data in valid {
+            load(data).expect("failed to load");
+            super::validate_stream(data, &LoadOptions::default()).expect("failed to validate");
+        }
+
+        let invalid: [&[u8]; 5] = [
+            b"\x04\x08[\x07:\x06a;\x06",
+            b"\x04\x08[\x07i\x06@\x06",
+            b"\x04\x08Ii\x06\x00",
+            b"\x04\x08\"\x07a",
+            b"\x05\x08[\x00",
+        ];
+        for data in invalid {
+            load(data).expect_err("loaded an invalid stream");
+            super::validate_stream(data, &LoadOptions::default())
+                .expect_err("validated an invalid stream");
+        }
+
+        let options = LoadOptions {
+            max_values: Some(3),
+            ..LoadOptions::default()
+        };
+        let error =
+            super::validate_stream(valid[0], &options).expect_err("validated over the limit");
+        assert!(matches!(error, Error::ValueLimitExceeded { limit: 3 }));
+
+        // A string claiming to be 1 GiB is rejected without allocating it.
+        let error = super::validate_stream(&b"\x04\x08\"\x04\xff\xff\xff\x3f"[..], &options)
+            .expect_err("validated a truncated string");
+        assert!(matches!(
+            error,
+            Error::UnexpectedEof {
+                position: 8,
+                while_reading: Some(b'"'),
+            }
+        ));
+    }
+
+    #[test]
+    fn float_mantissa_suffix() {
+        // [1.1, 2.5], written the way Ruby 1.8 does, with a NUL and mantissa bytes after 1.1.
+        let data = b"\x04\x08[\x07f\x0e1.1\x00\x99\x99\x99\x99\x99f\x082.5";
+        let arena = load(&data[..]).expect("failed to load");
+
+        let array = arena[arena.root()].as_array().expect("not an array");
+        let values: Vec<f64> = array
+            .value()
+            .iter()
+            .map(|handle| match &arena[*handle] {
+                Value::Float(value) => value.value(),
+                value => panic!("expected a float, got {:?}", value.kind()),
+            })
+            .collect();
+        assert!(values == [1.1, 2.5]);
+    }
+
+    #[test]
+    fn read_version() {
+        let mut reader = &b"\x04\x08[\x06i\x06"[..];
+        assert!(super::read_version(&mut reader).expect("failed to read version") == (4, 8));
+        assert!(reader == b"[\x06i\x06");
+
+        let version = super::read_version(&b"\x03\x00"[..]).expect("failed to read version");
+        assert!(version == (3, 0));
+
+        let error = super::read_version(&b"\x04"[..]).expect_err("read a truncated header");
+        assert!(matches!(error, Error::UnexpectedEof { position: 1, .. }));
+    }
+
+    #[test]
+    fn empty_input() {
+        let error = load(&[][..]).expect_err("loaded an empty stream");
+        assert!(matches!(error, Error::EmptyInput));
+
+        let error = super::read_version(&[][..]).expect_err("read an empty stream");
+        assert!(matches!(error, Error::EmptyInput));
+
+        // A truncated header is corrupt, not empty.
+        let error = load(&b"\x04"[..]).expect_err("loaded a truncated header");
+        assert!(matches!(error, Error::UnexpectedEof { position: 1, .. }));
+    }
+
+    #[test]
+    fn initial_capacity() {
+        // Marshal.dump([[1, 2], "a", :b])
+        let data = b"\x04\x08[\x08[\x07i\x06i\x07I\"\x06a\x06:\x06ET:\x06b";
+        let options = LoadOptions {
+            initial_capacity: 1024,
+            ..LoadOptions::default()
+        };
+        let arena = load_with_options(&data[..], &options).expect("failed to load");
+        assert!(arena.capacity() >= 1024);
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(new_data == data);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn pipe_reader() {
+        /// A reader that returns one byte per read, then blocks instead of ending.
+        struct Pipe<'a> {
+            data: &'a [u8],
+        }
+
+        impl Read for Pipe<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.data.is_empty() {
+                    return Err(std::io::ErrorKind::WouldBlock.into());
+                }
+                if buf.is_empty() {
+                    return Ok(0);
+                }
+                buf[0] = self.data[0];
+                self.data = &self.data[1..];
+                Ok(1)
+            }
+        }
+
+        // Marshal.dump(["ab", 300])
+        let data = b"\x04\x08[\x07\"\x07abi\x02\x2c\x01";
+        let arena = load(Pipe { data }).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+        assert!(arena.get_ref(array.value()[1]) == 300);
+        super::validate_stream(Pipe { data }, &LoadOptions::default()).expect("failed to validate");
+
+        // Blocking in the middle of a value is not the end of the stream.
+        let error = load(Pipe { data: &data[..5] }).expect_err("loaded a partial stream");
+        assert!(
+            matches!(&error, Error::Io { error } if error.kind() == std::io::ErrorKind::WouldBlock)
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        // Marshal.dump([Foo.new]), where Foo has @a = "xy".
+        let data = b"\x04\x08[\x06o:\x08Foo\x06:\x07@aI\"\x07xy\x06:\x06ET";
+
+        // The offset each truncation is reported at, and the kind of the value being read.
+        let expected: [(usize, Option<u8>); 24] = [
+            (1, None),
+            (2, None),
+            (3, Some(b'[')),
+            (4, Some(b'[')),
+            (5, Some(b'o')),
+            (6, Some(b':')),
+            (7, Some(b':')),
+            (7, Some(b':')),
+            (7, Some(b':')),
+            (10, Some(b'o')),
+            (11, Some(b'o')),
+            (12, Some(b':')),
+            (13, Some(b':')),
+            (13, Some(b':')),
+            (15, Some(b'o')),
+            (16, Some(b'I')),
+            (17, Some(b'"')),
+            (18, Some(b'"')),
+            (18, Some(b'"')),
+            (20, Some(b'I')),
+            (21, Some(b'I')),
+            (22, Some(b':')),
+            (23, Some(b':')),
+            (24, Some(b'I')),
+        ];
+        for (end, expected) in (1..data.len()).zip(expected) {
+            let truncated = &data[..end];
+            for error in [
+                load(truncated).expect_err("loaded a truncated stream"),
+                super::validate_stream(truncated, &LoadOptions::default())
+                    .expect_err("validated a truncated stream"),
+            ] {
+                let actual = match error {
+                    Error::UnexpectedEof {
+                        position,
+                        while_reading,
+                    } => (position, while_reading),
+                    error => panic!("truncated at {end}, got {error:?}"),
+                };
+                assert!(actual == expected, "truncated at {end}, got {actual:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn load_and_recover() {
+        let data = b"\x04\x08[\x06i\x06\x04\x08:\x06a";
+        let (arena, reader) = super::load_and_recover(&data[..]).expect("failed to load");
+        assert!(arena.get_ref(arena[arena.root()].as_array().unwrap().value()[0]) == 1);
+
+        let (header, reader) = reader.split_at(2);
+        assert!(header == [4, 8]);
+
+        let arena = load_headerless(reader).expect("failed to load");
+        assert!(arena.get_ref(arena.root()) == "a");
+    }
+
+    #[test]
+    fn non_ascii_names() {
+        // Café = Struct.new(:a); Marshal.dump(Café.new(1))
+        let data = b"\x04\x08SI:\x0aCaf\xc3\xa9\x06:\x06ET\x06:\x06ai\x06";
+        let arena = load(&data[..]).expect("failed to load");
+        let value = arena[arena.root()].as_struct().expect("not a struct");
+        assert!(arena.get_ref(value.name()) == "Café");
+
+        // Marshal.dump({ "é": :"é" })
+        let hash = b"\x04\x08{\x06I:\x07\xc3\xa9\x06:\x06ET;\x00";
+
+        for data in [&data[..], &hash[..]] {
+            super::validate_stream(data, &LoadOptions::default()).expect("failed to validate");
+
+            let arena = load(data).expect("failed to load");
+            let mut new_data = Vec::new();
+            dump(&mut new_data, &arena).expect("failed to dump");
+            assert!(data == new_data, "{data:?} != {new_data:?}");
+        }
+    }
+
+    #[test]
+    fn regexp() {
+        // Marshal.dump(/x/im)
+        let data = b"\x04\x08I/\x06x\x05\x06:\x06EF";
+        let arena = load(&data[..]).expect("failed to load");
+        let regexp = arena[arena.root()].as_regexp().expect("not a regexp");
+        assert!(regexp.source() == b"x");
+        assert!(regexp.raw_options() == RegexpOptions::IGNORECASE | RegexpOptions::MULTILINE);
+        assert!(regexp.instance_variables().map(|v| v.len()) == Some(1));
+
+        let options = regexp.options();
+        assert!(options.ignore_case());
+        assert!(!options.extended());
+        assert!(options.multiline());
+        assert!(options.to_rust_regex_flags() == "ims");
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(data == &new_data[..], "{data:?} != {new_data:?}");
+
+        super::validate_stream(&data[..], &LoadOptions::default()).expect("failed to validate");
+
+        // class R < Regexp; end
+        // Marshal.dump(R.new("x"))
+        let data = b"\x04\x08IC:\x06R/\x06x\x00\x06:\x06EF";
+        let mut arena = load(&data[..]).expect("failed to load");
+        let regexp = arena[arena.root()].as_regexp().expect("not a regexp");
+        let user_class = regexp.user_class().expect("missing user class");
+        assert!(arena.get_symbol(user_class).unwrap().value() == b"R");
+        assert!(regexp.options().to_rust_regex_flags() == "m");
+        assert!(arena.referenced_class_names(arena.root()) == [b"R"]);
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(data == &new_data[..], "{data:?} != {new_data:?}");
+
+        super::validate_stream(&data[..], &LoadOptions::default()).expect("failed to validate");
+
+        assert!(arena.retag_class(b"R", b"S") == 1);
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(new_data == b"\x04\x08IC:\x06S/\x06x\x00\x06:\x06EF");
+    }
+
+    #[test]
+    fn symbol_link_out_of_range() {
+        // The table has one symbol, so index 1 is exactly the table length.
+        let error = load(&b"\x04\x08[\x07:\x06a;\x06"[..]).expect_err("loaded a missing link");
+        assert!(matches!(error, Error::MissingSymbolLink { index: 1 }));
+
+        let error = load(&b"\x04\x08[\x07:\x06a;\x07"[..]).expect_err("loaded a missing link");
+        assert!(matches!(error, Error::MissingSymbolLink { index: 2 }));
+
+        load(&b"\x04\x08[\x07:\x06a;\x00"[..]).expect("failed to load");
+
+        let error = load(&b"\x04\x08[\x07:\x06a;\xfa"[..]).expect_err("loaded a negative link");
+        assert!(matches!(error, Error::FixnumInvalidUSize { .. }));
+    }
+
+    #[test]
+    fn symbol_link_empty_table() {
+        // A corrupt stream that links to a symbol before any are defined,
+        // both as a value and as the class name of an object.
+        for data in [&b"\x04\x08;\x00"[..], &b"\x04\x08o;\x00\x00"[..]] {
+            let error = load(data).expect_err("loaded a missing link");
+            assert!(matches!(error, Error::MissingSymbolLink { index: 0 }));
+
+            let error = super::validate_stream(data, &LoadOptions::default())
+                .expect_err("validated a missing link");
+            assert!(matches!(error, Error::MissingSymbolLink { index: 0 }));
+        }
+    }
+
+    #[test]
+    fn symbol_link_shares_handle() {
+        // Marshal.dump([:Point, :Point, Point.new])
+        let data = b"\x04\x08[\x08:\x0aPoint;\x00o;\x00\x00";
+        let arena = load(&data[..]).expect("failed to load");
+        assert!(arena.symbol_count() == 1);
+
+        let array = arena[arena.root()].as_array().expect("not an array");
+        let array = array.value();
+        assert!(array[0] == array[1]);
+
+        let object = arena[array[2]].as_object().expect("not an object");
+        assert!(object.name().into_raw() == array[0]);
+    }
+
+    #[test]
+    fn annotate_covers_stream() {
+        // Marshal.dump([:foo, "bar", :foo, 300])
+        let data = b"\x04\x08[\x09:\x08foo\"\x08bar;\x00i\x02\x2c\x01";
+        let (annotations, error) = annotate(&data[..]);
+        assert!(error.is_none(), "{error:?}");
+
+        let mut position = 0;
+        for annotation in annotations.iter() {
+            assert!(annotation.range.start == position, "{annotation:?}");
+            assert!(annotation.range.end > annotation.range.start);
+            position = annotation.range.end;
+        }
+        assert!(position == data.len());
+
+        assert!(annotations[0].description == "header 4.8");
+        assert!(annotations[0].kind.is_none());
+
+        let symbol_bytes = &annotations[5];
+        assert!(symbol_bytes.range == (6..9));
+        assert!(symbol_bytes.kind == Some(b':'));
+        assert!(symbol_bytes.description == "3 bytes \"foo\"");
+
+        let fixnum = annotations.last().unwrap();
+        assert!(fixnum.range == (17..20));
+        assert!(fixnum.kind == Some(b'i'));
+        assert!(fixnum.description == "fixnum 300");
+    }
+
+    #[test]
+    fn annotate_truncated() {
+        // Marshal.dump([1, "bar"]), cut off in the middle of the string
+        let data = b"\x04\x08[\x07i\x06\"\x08ba";
+        let (annotations, error) = annotate(&data[..]);
+
+        assert!(matches!(
+            error,
+            Some(Error::UnexpectedEof {
+                position: 8,
+                while_reading: Some(b'"'),
+            })
+        ));
+        let descriptions: Vec<&str> = annotations
+            .iter()
+            .map(|annotation| annotation.description.as_str())
+            .collect();
+        assert!(
+            descriptions
+                == [
+                    "header 4.8",
+                    "value kind '['",
+                    "fixnum 2",
+                    "value kind 'i'",
+                    "fixnum 1",
+                    "value kind '\"'",
+                    "fixnum 3",
+                ],
+            "{descriptions:?}"
+        );
+        assert!(annotations.last().unwrap().range == (7..8));
+    }
+
+    #[test]
+    fn diff_bytes() {
+        // Marshal.dump([:a, :b, :a])
+        let a = b"\x04\x08[\x08:\x06a:\x06b;\x00";
+        // Marshal.dump([:a, :b, :b])
+        let b = b"\x04\x08[\x08:\x06a:\x06b;\x06";
+        // Marshal.dump([:a, :b])
+        let c = b"\x04\x08[\x07:\x06a:\x06b";
+
+        assert!(super::diff_bytes(a, a).is_empty());
+
+        let diffs = super::diff_bytes(a, b);
+        assert!(diffs.len() == 1);
+        let span = diffs[0].a.as_ref().expect("missing span");
+        assert!(span.range == (11..12));
+        assert!(span.kind == Some(b';'));
+        assert!(
+            diffs[0].to_string()
+                == "at byte 11 while reading value kind ';': \"fixnum 0\" != \"fixnum 1\""
+        );
+
+        let diffs = super::diff_bytes(c, a);
+        assert!(diffs.len() == 2);
+        assert!(
+            diffs[0].to_string()
+                == "at byte 3 while reading value kind '[': \"fixnum 2\" != \"fixnum 3\""
+        );
+        assert!(diffs[1].a.is_none());
+        assert!(
+            diffs[1].to_string()
+                == "at byte 10 while reading value kind ';': end of stream != \"value kind ';'\""
+        );
+
+        // The first stream, cut off before its last element
+        let d = &a[..10];
+        let diffs = super::diff_bytes(a, d);
+        assert!(diffs.len() == 1, "{diffs:?}");
+        let span = diffs[0].b.as_ref().expect("missing span");
+        assert!(span.range == (10..10));
+        assert!(span.kind == Some(b'['));
+        assert!(
+            diffs[0].to_string()
+                == "at byte 10 while reading value kind ';': \"value kind ';'\" != \
+                    \"unexpected end of stream at byte 10 while reading value kind '['\""
+        );
+    }
+
+    #[test]
+    fn duplicate_hash_key() {
+        // A hash of { a: 1, a: 2 }, which Ruby itself would never write.
+        let data = b"\x04\x08{\x07:\x06ai\x06;\x00i\x07";
+
+        let value_arena = load(&data[..]).expect("failed to load");
+        let hash = value_arena[value_arena.root()]
+            .as_hash()
+            .expect("not a hash");
+        assert!(hash.value().len() == 2);
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+
+        let options = LoadOptions {
+            on_duplicate_hash_key: DuplicateHashKeyPolicy::LastWins,
+            ..LoadOptions::default()
+        };
+        let value_arena = load_with_options(&data[..], &options).expect("failed to load");
+        let hash = value_arena[value_arena.root()]
+            .as_hash()
+            .expect("not a hash");
+        assert!(hash.value().len() == 1);
+        let value = &value_arena[hash.value()[0].1];
+        assert!(matches!(value, Value::Fixnum(value) if value.value() == 2));
+
+        let options = LoadOptions {
+            on_duplicate_hash_key: DuplicateHashKeyPolicy::Error,
+            ..LoadOptions::default()
+        };
+        let error = load_with_options(&data[..], &options).expect_err("loaded a duplicate key");
+        assert!(matches!(error, Error::DuplicateHashKey { index: 1 }));
+
+        // Distinct keys are unaffected.
+        let data = b"\x04\x08{\x07:\x06ai\x06:\x06bi\x07";
+        let value_arena = load_with_options(&data[..], &options).expect("failed to load");
+        let hash = value_arena[value_arena.root()]
+            .as_hash()
+            .expect("not a hash");
+        assert!(hash.value().len() == 2);
+    }
+
+    #[test]
+    fn bignum_round_trip() {
+        // Marshal.dump([2**128, -2**200])
+        let mut data = b"\x04\x08[\x07l+\x0e".to_vec();
+        data.extend([0; 16]);
+        data.extend(b"\x01\x00l-\x12");
+        data.extend([0; 25]);
+        data.push(1);
+
+        let value_arena = load(&data[..]).expect("failed to load");
+        let array = value_arena[value_arena.root()]
+            .as_array()
+            .expect("not an array");
+
+        let positive = match &value_arena[array.value()[0]] {
+            Value::Bignum(value) => value,
+            _ => panic!("not a bignum"),
+        };
+        assert!(positive.is_positive());
+        assert!(positive.magnitude().len() == 18);
+        assert!(positive.magnitude()[16] == 1);
+
+        let negative = match &value_arena[array.value()[1]] {
+            Value::Bignum(value) => value,
+            _ => panic!("not a bignum"),
+        };
+        assert!(!negative.is_positive());
+        assert!(negative.magnitude().len() == 26);
+
+        #[cfg(feature = "bigint")]
+        {
+            let positive = positive.to_bigint().to_string();
+            assert!(positive == "340282366920938463463374607431768211456");
+
+            let negative = negative.to_bigint().to_string();
+            assert!(negative == "-1606938044258990275541962092341162602522202993782792835301376");
+        }
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data == new_data, "{data:?} != {new_data:?}");
+
+        let error = load(&b"\x04\x08l*\x06\x01\x00"[..]).expect_err("loaded an invalid sign");
+        assert!(matches!(error, Error::InvalidBignumSign { sign: b'*' }));
+    }
+
+    #[test]
+    fn load_typed() {
+        // Marshal.dump([1, 2, 300])
+        let data = b"\x04\x08[\x08i\x06i\x07i\x02\x2c\x01";
+        let value: Vec<i64> = super::load_typed(&data[..]).expect("failed to load");
+        assert!(value == [1, 2, 300]);
+
+        // Marshal.dump([:a])
+        let data = b"\x04\x08[\x06:\x06a";
+        let error = super::load_typed::<Vec<i64>, _>(&data[..]).expect_err("loaded a symbol");
+        assert!(matches!(error, Error::FromValue { .. }));
+    }
+
+    #[test]
+    fn headerless_round_trip() {
+        let data = b"[\x07:\x08foo;\x00";
+
+        let value_arena = load_headerless(&data[..]).expect("failed to load");
+        let mut new_data = Vec::new();
+        dump_headerless(&mut new_data, &value_arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+
+        let mut data = Vec::new();
+        dump(&mut data, &value_arena).expect("failed to dump");
+        assert!(data[2..] == new_data);
+
+        load(&new_data[..]).expect_err("loaded without a header");
+    }
+
+    #[test]
+    fn trailing_whitespace() {
+        // Marshal.dump(1) + "\n"
+        let data = b"\x04\x08i\x06\n";
+        load_with_options(&data[..], &LoadOptions::default()).expect("failed to load");
+        super::validate_stream(&data[..], &LoadOptions::default()).expect("failed to validate");
+
+        let options = LoadOptions {
+            strict_eof: true,
+            ..LoadOptions::default()
+        };
+        let error = load_with_options(&data[..], &options).expect_err("loaded trailing bytes");
+        assert!(matches!(error, Error::TrailingBytes { len: 1 }));
+        let error = super::validate_stream(&data[..], &options).expect_err("validated");
+        assert!(matches!(error, Error::TrailingBytes { len: 1 }));
+
+        let options = LoadOptions {
+            strict_eof: true,
+            allow_trailing_whitespace: true,
+            ..LoadOptions::default()
+        };
+        load_with_options(&data[..], &options).expect("failed to load");
+        super::validate_stream(&data[..], &options).expect("failed to validate");
+        let data = b"\x04\x08i\x06\r\nx";
+        let error = load_with_options(&data[..], &options).expect_err("loaded trailing bytes");
+        assert!(matches!(error, Error::TrailingBytes { len: 1 }));
+        let error = super::validate_stream(&data[..], &options).expect_err("validated");
+        assert!(matches!(error, Error::TrailingBytes { len: 1 }));
+    }
+}
@@ -0,0 +1,661 @@
+use super::DuplicateHashKeyPolicy;
+use super::LoadOptions;
+use crate::ArrayValue;
+use crate::Error;
+use crate::GenericValueArena;
+use crate::HashMap;
+use crate::HashValue;
+use crate::ObjectValue;
+use crate::SlotMapStorage;
+use crate::Source;
+use crate::SourceSpan;
+use crate::Storage;
+use crate::StructValue;
+use crate::SymbolValue;
+use crate::TypedValueHandle;
+use crate::UserMarshalValue;
+use crate::Value;
+use crate::ValueHandle;
+use crate::ValueKind;
+use alloc::vec::Vec;
+
+/// A list of the children of a value, which a [`Builder`] may not need to keep.
+pub(super) trait Children<T> {
+    /// Make a new empty list, with room for the given number of children.
+    fn with_capacity(capacity: usize) -> Self;
+
+    /// Add a child to the end of the list.
+    fn push(&mut self, value: T);
+}
+
+impl<T> Children<T> for Vec<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        Vec::with_capacity(capacity)
+    }
+
+    fn push(&mut self, value: T) {
+        Vec::push(self, value);
+    }
+}
+
+/// A list that drops its children.
+impl<T> Children<T> for () {
+    fn with_capacity(_capacity: usize) -> Self {}
+
+    fn push(&mut self, _value: T) {}
+}
+
+/// A destination for the values read by a [`Loader`](super::Loader).
+///
+/// The loader does all of the parsing and limit checks, and hands each value it reads to the
+/// builder, which decides what to keep.
+pub(super) trait Builder {
+    /// A handle to a value.
+    type Handle: Copy;
+
+    /// A handle to a symbol.
+    type Symbol: Copy + Into<Self::Handle>;
+
+    /// A list of the children of a value.
+    type List<T>: Children<T>;
+
+    /// Whether the bytes of byte strings are needed.
+    ///
+    /// If not, they are skipped without buffering, and the builder gets empty byte strings.
+    /// Floats are always read, since they must be parsed.
+    const KEEPS_BYTES: bool;
+
+    /// Get the number of values created so far.
+    fn len(&self) -> usize;
+
+    /// Create a nil.
+    fn create_nil(&mut self) -> Self::Handle;
+
+    /// Create a bool.
+    fn create_bool(&mut self, value: bool) -> Self::Handle;
+
+    /// Create a fixnum.
+    fn create_fixnum(&mut self, value: i32) -> Self::Handle;
+
+    /// Create a bignum from its little-endian magnitude.
+    fn create_bignum(&mut self, positive: bool, magnitude: Vec<u8>) -> Self::Handle;
+
+    /// Create a float.
+    fn create_float(&mut self, value: f64) -> Self::Handle;
+
+    /// Create a symbol.
+    fn create_symbol(&mut self, value: Vec<u8>) -> Self::Symbol;
+
+    /// Create a string.
+    fn create_string(&mut self, value: Vec<u8>) -> Self::Handle;
+
+    /// Create a regexp.
+    fn create_regexp(&mut self, source: Vec<u8>, options: u8) -> Self::Handle;
+
+    /// Create a user defined value.
+    fn create_user_defined(&mut self, name: Self::Symbol, value: Vec<u8>) -> Self::Handle;
+
+    /// Create a class.
+    fn create_class(&mut self, name: Vec<u8>) -> Self::Handle;
+
+    /// Create a placeholder for a value of the given kind, which is filled in once its children
+    /// are read.
+    ///
+    /// This lets the children link to the value.
+    fn reserve(&mut self, kind: ValueKind) -> Self::Handle;
+
+    /// Fill in a placeholder with an array.
+    fn finish_array(&mut self, handle: Self::Handle, values: Self::List<Self::Handle>);
+
+    /// Fill in a placeholder with a hash.
+    ///
+    /// This applies [`LoadOptions::on_duplicate_hash_key`], if the builder can.
+    fn finish_hash(
+        &mut self,
+        handle: Self::Handle,
+        pairs: Self::List<(Self::Handle, Self::Handle)>,
+        default_value: Option<Self::Handle>,
+    ) -> Result<(), Error>;
+
+    /// Fill in a placeholder with an object.
+    fn finish_object(
+        &mut self,
+        handle: Self::Handle,
+        name: Self::Symbol,
+        instance_variables: Self::List<(Self::Symbol, Self::Handle)>,
+    );
+
+    /// Fill in a placeholder with a struct.
+    fn finish_struct(
+        &mut self,
+        handle: Self::Handle,
+        name: Self::Symbol,
+        members: Self::List<(Self::Symbol, Self::Handle)>,
+    );
+
+    /// Fill in a placeholder with a user marshal value.
+    fn finish_user_marshal(
+        &mut self,
+        handle: Self::Handle,
+        name: Self::Symbol,
+        value: Self::Handle,
+    );
+
+    /// Set the instance variables of a value.
+    ///
+    /// This fails with [`Error::NotAnObject`] if the value cannot have instance variables.
+    fn set_instance_variables(
+        &mut self,
+        handle: Self::Handle,
+        instance_variables: Self::List<(Self::Symbol, Self::Handle)>,
+    ) -> Result<(), Error>;
+
+    /// Set the user class of a value.
+    ///
+    /// This fails with [`Error::NotAnObject`] if the value cannot have a user class.
+    fn set_user_class(&mut self, handle: Self::Handle, name: Self::Symbol) -> Result<(), Error>;
+
+    /// Add a module that a value was extended with, before the modules already added.
+    ///
+    /// Modules are added innermost first, as the value is read before its outer wrappers end.
+    /// This fails with [`Error::NotAnObject`] if the value cannot be extended.
+    fn extend_value(&mut self, handle: Self::Handle, module: Self::Symbol) -> Result<(), Error>;
+
+    /// Record bytes that were read from the stream.
+    fn record_bytes(&mut self, _bytes: &[u8]) {}
+
+    /// Record the span of a value that was read.
+    fn record_span(&mut self, _handle: Self::Handle, _span: SourceSpan) {}
+}
+
+/// The instance variables of a value, as kept by a [`Builder`].
+pub(super) type InstanceVariables<B> =
+    <B as Builder>::List<(<B as Builder>::Symbol, <B as Builder>::Handle)>;
+
+/// A [`Builder`] that creates the values in an arena.
+#[derive(Debug)]
+pub(super) struct ArenaBuilder<'a, S = SlotMapStorage> {
+    pub(super) arena: GenericValueArena<S>,
+    options: &'a LoadOptions,
+
+    source: Option<Source>,
+    /// Values changed while loading, like hashes whose duplicate keys were collapsed,
+    /// so their source no longer matches.
+    modified_values: Vec<ValueHandle>,
+    /// The `E` symbol shared by strings transcoded to UTF-8.
+    utf8_encoding_symbol: Option<TypedValueHandle<SymbolValue>>,
+}
+
+impl<'a, S> ArenaBuilder<'a, S>
+where
+    S: Storage,
+{
+    /// Make a new [`ArenaBuilder`] with a new arena.
+    pub(super) fn new(options: &'a LoadOptions) -> Self {
+        let mut arena = GenericValueArena::new();
+        if options.record_creation_order {
+            arena.record_creation_order();
+        }
+        arena.reserve(options.initial_capacity);
+
+        Self {
+            arena,
+            options,
+            source: options.preserve_source.then(Source::default),
+            modified_values: Vec::new(),
+            utf8_encoding_symbol: None,
+        }
+    }
+
+    /// Set the root of the arena, and get the arena.
+    ///
+    /// The link tables are kept with the source, if it was preserved.
+    pub(super) fn finish(
+        mut self,
+        root: ValueHandle,
+        symbol_links: Vec<TypedValueHandle<SymbolValue>>,
+        object_links: Vec<ValueHandle>,
+    ) -> GenericValueArena<S> {
+        let _old_root = self.arena.replace_root(root);
+
+        // TODO: Delete old root.
+
+        if let Some(mut source) = self.source.take() {
+            source.symbol_links = symbol_links;
+            source.object_links = object_links;
+            self.arena.set_source(source);
+
+            for handle in self.modified_values {
+                self.arena.mark_dirty(handle);
+            }
+        }
+
+        self.arena
+    }
+
+    /// Transcode a string to UTF-8 if it has a registered non-UTF-8 encoding.
+    ///
+    /// The `encoding` instance variable is replaced with `E` if the string was transcoded.
+    fn normalize_string_encoding(
+        &mut self,
+        value: ValueHandle,
+        instance_variables: &mut [(TypedValueHandle<SymbolValue>, ValueHandle)],
+    ) {
+        let Some(Value::String(string)) = self.arena.get(value) else {
+            return;
+        };
+        let Some(index) = instance_variables.iter().position(|(key, _)| {
+            self.arena
+                .get_symbol(*key)
+                .is_some_and(|key| key.value() == b"encoding")
+        }) else {
+            return;
+        };
+        let Some(Value::String(encoding)) = self.arena.get(instance_variables[index].1) else {
+            return;
+        };
+        let Some(decoder) = self.options.string_decoders.get(encoding.value()) else {
+            return;
+        };
+        let Some(decoded) = decoder.decode(string.value()) else {
+            return;
+        };
+
+        if let Some(Value::String(string)) = self.arena.get_mut(value) {
+            string.set_value(decoded.into_bytes());
+        }
+        let key = *self
+            .utf8_encoding_symbol
+            .get_or_insert_with(|| self.arena.create_symbol(b"E".to_vec()));
+        let is_utf8 = self.arena.create_bool(true).into_raw();
+        instance_variables[index] = (key, is_utf8);
+        self.modified_values.push(value);
+    }
+
+    /// Fill in a placeholder with a value.
+    fn fill(&mut self, handle: ValueHandle, value: Value) {
+        *self.arena.get_mut(handle).unwrap() = value;
+    }
+}
+
+impl<S> Builder for ArenaBuilder<'_, S>
+where
+    S: Storage,
+{
+    type Handle = ValueHandle;
+    type Symbol = TypedValueHandle<SymbolValue>;
+    type List<T> = Vec<T>;
+
+    const KEEPS_BYTES: bool = true;
+
+    fn len(&self) -> usize {
+        // The arena always starts with a nil root.
+        self.arena.len() - 1
+    }
+
+    fn create_nil(&mut self) -> ValueHandle {
+        self.arena.create_nil().into()
+    }
+
+    fn create_bool(&mut self, value: bool) -> ValueHandle {
+        self.arena.create_bool(value).into()
+    }
+
+    fn create_fixnum(&mut self, value: i32) -> ValueHandle {
+        self.arena.create_fixnum(value).into()
+    }
+
+    fn create_bignum(&mut self, positive: bool, magnitude: Vec<u8>) -> ValueHandle {
+        self.arena.create_bignum(positive, magnitude).into()
+    }
+
+    fn create_float(&mut self, value: f64) -> ValueHandle {
+        self.arena.create_float(value).into()
+    }
+
+    fn create_symbol(&mut self, value: Vec<u8>) -> TypedValueHandle<SymbolValue> {
+        let is_encoding = value == b"E";
+        let handle = self.arena.create_symbol(value);
+        // Transcoded strings share the stream's own `E` symbol, if it comes first.
+        if is_encoding && self.utf8_encoding_symbol.is_none() {
+            self.utf8_encoding_symbol = Some(handle);
+        }
+
+        handle
+    }
+
+    fn create_string(&mut self, value: Vec<u8>) -> ValueHandle {
+        self.arena.create_string(value).into()
+    }
+
+    fn create_regexp(&mut self, source: Vec<u8>, options: u8) -> ValueHandle {
+        self.arena.create_regexp(source, options).into()
+    }
+
+    fn create_user_defined(
+        &mut self,
+        name: TypedValueHandle<SymbolValue>,
+        value: Vec<u8>,
+    ) -> ValueHandle {
+        self.arena.create_user_defined(name, value).into()
+    }
+
+    fn create_class(&mut self, name: Vec<u8>) -> ValueHandle {
+        self.arena.create_class(name).into()
+    }
+
+    fn reserve(&mut self, _kind: ValueKind) -> ValueHandle {
+        self.arena.create_nil().into()
+    }
+
+    fn finish_array(&mut self, handle: ValueHandle, values: Vec<ValueHandle>) {
+        self.fill(handle, ArrayValue::new(values).into());
+    }
+
+    fn finish_hash(
+        &mut self,
+        handle: ValueHandle,
+        pairs: Vec<(ValueHandle, ValueHandle)>,
+        default_value: Option<ValueHandle>,
+    ) -> Result<(), Error> {
+        let policy = self.options.on_duplicate_hash_key;
+        let pairs = if policy == DuplicateHashKeyPolicy::KeepBoth {
+            pairs
+        } else {
+            // TODO: Consider making this a map.
+            let mut unique: Vec<(ValueHandle, ValueHandle)> = Vec::with_capacity(pairs.len());
+            let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+            for (index, (key, value)) in pairs.into_iter().enumerate() {
+                let bucket = buckets.entry(self.arena.content_hash(key)).or_default();
+                let duplicate = bucket
+                    .iter()
+                    .copied()
+                    .find(|i| self.arena.values_equal(unique[*i].0, key));
+                match (duplicate, policy) {
+                    (Some(_), DuplicateHashKeyPolicy::Error) => {
+                        return Err(Error::DuplicateHashKey { index });
+                    }
+                    (Some(i), _) => {
+                        unique[i].1 = value;
+                        if self.modified_values.last() != Some(&handle) {
+                            self.modified_values.push(handle);
+                        }
+                    }
+                    (None, _) => {
+                        bucket.push(unique.len());
+                        unique.push((key, value));
+                    }
+                }
+            }
+
+            unique
+        };
+
+        self.fill(handle, HashValue::new(pairs, default_value).into());
+
+        Ok(())
+    }
+
+    fn finish_object(
+        &mut self,
+        handle: ValueHandle,
+        name: TypedValueHandle<SymbolValue>,
+        instance_variables: Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>,
+    ) {
+        self.fill(handle, ObjectValue::new(name, instance_variables).into());
+    }
+
+    fn finish_struct(
+        &mut self,
+        handle: ValueHandle,
+        name: TypedValueHandle<SymbolValue>,
+        members: Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>,
+    ) {
+        self.fill(handle, StructValue::new(name, members).into());
+    }
+
+    fn finish_user_marshal(
+        &mut self,
+        handle: ValueHandle,
+        name: TypedValueHandle<SymbolValue>,
+        value: ValueHandle,
+    ) {
+        self.fill(handle, UserMarshalValue::new(name, value).into());
+    }
+
+    fn set_instance_variables(
+        &mut self,
+        handle: ValueHandle,
+        mut instance_variables: Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>,
+    ) -> Result<(), Error> {
+        if self.options.normalize_strings_to_utf8 {
+            self.normalize_string_encoding(handle, &mut instance_variables);
+        }
+
+        match self
+            .arena
+            .get_mut(handle)
+            .ok_or(Error::InvalidValueHandle { handle })?
+        {
+            Value::Array(value) => {
+                value.set_instance_variables(Some(instance_variables));
+            }
+            Value::Hash(value) => {
+                value.set_instance_variables(Some(instance_variables));
+            }
+            Value::String(value) => {
+                value.set_instance_variables(Some(instance_variables));
+            }
+            Value::Regexp(value) => {
+                value.set_instance_variables(Some(instance_variables));
+            }
+            Value::UserDefined(value) => {
+                value.set_instance_variables(Some(instance_variables));
+            }
+            Value::Symbol(value) => {
+                value.set_instance_variables(Some(instance_variables));
+            }
+            _ => return Err(Error::NotAnObject),
+        }
+
+        Ok(())
+    }
+
+    fn set_user_class(
+        &mut self,
+        handle: ValueHandle,
+        name: TypedValueHandle<SymbolValue>,
+    ) -> Result<(), Error> {
+        let is_hash_class = self
+            .arena
+            .get_symbol(name)
+            .is_some_and(|name| name.value() == b"Hash");
+
+        match self
+            .arena
+            .get_mut(handle)
+            .ok_or(Error::InvalidValueHandle { handle })?
+        {
+            Value::Array(value) => {
+                value.set_user_class(Some(name));
+            }
+            // Ruby writes Hash itself as an inner user class for compare_by_identity.
+            Value::Hash(value)
+                if is_hash_class
+                    && value.user_class().is_none()
+                    && !value.compare_by_identity() =>
+            {
+                value.set_identity_class(Some(name));
+            }
+            Value::Hash(value) => {
+                value.set_user_class(Some(name));
+            }
+            Value::String(value) => {
+                value.set_user_class(Some(name));
+            }
+            Value::Regexp(value) => {
+                value.set_user_class(Some(name));
+            }
+            _ => return Err(Error::NotAnObject),
+        }
+
+        Ok(())
+    }
+
+    fn extend_value(
+        &mut self,
+        handle: ValueHandle,
+        module: TypedValueHandle<SymbolValue>,
+    ) -> Result<(), Error> {
+        self.arena
+            .get_mut(handle)
+            .ok_or(Error::InvalidValueHandle { handle })?
+            .extended_modules_mut()
+            .ok_or(Error::NotAnObject)?
+            .insert(0, module);
+
+        Ok(())
+    }
+
+    fn record_bytes(&mut self, bytes: &[u8]) {
+        if let Some(source) = self.source.as_mut() {
+            source.bytes.extend_from_slice(bytes);
+        }
+    }
+
+    fn record_span(&mut self, handle: ValueHandle, span: SourceSpan) {
+        if let Some(source) = self.source.as_mut() {
+            source.spans.insert(handle, span);
+        }
+    }
+}
+
+/// A [`Builder`] that only keeps the kind of each value, for
+/// [`validate_stream`](super::validate_stream).
+///
+/// Byte strings are skipped without being buffered, and children are not kept,
+/// so the only memory used is the link tables.
+#[derive(Debug, Default)]
+pub(super) struct KindBuilder {
+    /// The number of values that loading would have created.
+    values: usize,
+}
+
+impl KindBuilder {
+    /// Count a new value of the given kind.
+    fn create(&mut self, kind: ValueKind) -> ValueKind {
+        self.values += 1;
+        kind
+    }
+}
+
+impl Builder for KindBuilder {
+    type Handle = ValueKind;
+    type Symbol = ValueKind;
+    type List<T> = ();
+
+    const KEEPS_BYTES: bool = false;
+
+    fn len(&self) -> usize {
+        self.values
+    }
+
+    fn create_nil(&mut self) -> ValueKind {
+        self.create(ValueKind::Nil)
+    }
+
+    fn create_bool(&mut self, _value: bool) -> ValueKind {
+        self.create(ValueKind::Bool)
+    }
+
+    fn create_fixnum(&mut self, _value: i32) -> ValueKind {
+        self.create(ValueKind::Fixnum)
+    }
+
+    fn create_bignum(&mut self, _positive: bool, _magnitude: Vec<u8>) -> ValueKind {
+        self.create(ValueKind::Bignum)
+    }
+
+    fn create_float(&mut self, _value: f64) -> ValueKind {
+        self.create(ValueKind::Float)
+    }
+
+    fn create_symbol(&mut self, _value: Vec<u8>) -> ValueKind {
+        self.create(ValueKind::Symbol)
+    }
+
+    fn create_string(&mut self, _value: Vec<u8>) -> ValueKind {
+        self.create(ValueKind::String)
+    }
+
+    fn create_regexp(&mut self, _source: Vec<u8>, _options: u8) -> ValueKind {
+        self.create(ValueKind::Regexp)
+    }
+
+    fn create_user_defined(&mut self, _name: ValueKind, _value: Vec<u8>) -> ValueKind {
+        self.create(ValueKind::UserDefined)
+    }
+
+    fn create_class(&mut self, _name: Vec<u8>) -> ValueKind {
+        self.create(ValueKind::Class)
+    }
+
+    fn reserve(&mut self, kind: ValueKind) -> ValueKind {
+        self.create(kind)
+    }
+
+    fn finish_array(&mut self, _handle: ValueKind, _values: ()) {}
+
+    fn finish_hash(
+        &mut self,
+        _handle: ValueKind,
+        _pairs: (),
+        _default_value: Option<ValueKind>,
+    ) -> Result<(), Error> {
+        // Duplicate keys cannot be found without the keys.
+        Ok(())
+    }
+
+    fn finish_object(&mut self, _handle: ValueKind, _name: ValueKind, _instance_variables: ()) {}
+
+    fn finish_struct(&mut self, _handle: ValueKind, _name: ValueKind, _members: ()) {}
+
+    fn finish_user_marshal(&mut self, _handle: ValueKind, _name: ValueKind, _value: ValueKind) {}
+
+    fn set_instance_variables(
+        &mut self,
+        handle: ValueKind,
+        _instance_variables: (),
+    ) -> Result<(), Error> {
+        match handle {
+            ValueKind::Array
+            | ValueKind::Hash
+            | ValueKind::String
+            | ValueKind::Regexp
+            | ValueKind::UserDefined
+            | ValueKind::Symbol => Ok(()),
+            _ => Err(Error::NotAnObject),
+        }
+    }
+
+    fn set_user_class(&mut self, handle: ValueKind, _name: ValueKind) -> Result<(), Error> {
+        match handle {
+            ValueKind::Array | ValueKind::Hash | ValueKind::String | ValueKind::Regexp => Ok(()),
+            _ => Err(Error::NotAnObject),
+        }
+    }
+
+    fn extend_value(&mut self, handle: ValueKind, _module: ValueKind) -> Result<(), Error> {
+        match handle {
+            ValueKind::Array
+            | ValueKind::Hash
+            | ValueKind::Object
+            | ValueKind::Struct
+            | ValueKind::String
+            | ValueKind::Regexp
+            | ValueKind::UserDefined
+            | ValueKind::UserMarshal => Ok(()),
+            _ => Err(Error::NotAnObject),
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use crate::DisplayByteString;
+use crate::Value;
+use crate::ValueArena;
+use crate::ValueHandle;
+use crate::ValueKind;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// An error that may occur while validating a value against a [`Schema`].
+#[derive(Debug)]
+pub enum SchemaError {
+    /// A given [`ValueHandle`] was invalid.
+    InvalidValueHandle {
+        /// The invalid handle
+        handle: ValueHandle,
+    },
+
+    /// A value was of an unexpected kind.
+    UnexpectedValueKind {
+        /// The handle of the offending value
+        handle: ValueHandle,
+
+        /// The expected value kind
+        expected: ValueKind,
+
+        /// The actual value kind
+        actual: ValueKind,
+    },
+
+    /// A class name was unexpected.
+    UnexpectedClassName {
+        /// The handle of the offending value
+        handle: ValueHandle,
+
+        /// The expected class name.
+        expected: Vec<u8>,
+
+        /// The actual class name.
+        ///
+        /// This may or may not be UTF-8.
+        actual: Vec<u8>,
+    },
+
+    /// An object was missing an instance variable.
+    MissingInstanceVariable {
+        /// The handle of the offending object
+        handle: ValueHandle,
+
+        /// The instance variable name.
+        name: Vec<u8>,
+    },
+}
+
+impl core::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidValueHandle { .. } => write!(f, "a handle was invalid"),
+            Self::UnexpectedValueKind {
+                expected, actual, ..
+            } => write!(
+                f,
+                "unexpected value kind, expected {expected:?} but got {actual:?}"
+            ),
+            Self::UnexpectedClassName {
+                expected, actual, ..
+            } => write!(
+                f,
+                "unexpected class name, expected \"{}\" but got \"{}\"",
+                DisplayByteString(expected),
+                DisplayByteString(actual)
+            ),
+            Self::MissingInstanceVariable { name, .. } => write!(
+                f,
+                "instance variable \"{}\" is missing",
+                DisplayByteString(name)
+            ),
+        }
+    }
+}
+
+impl core::error::Error for SchemaError {}
+
+/// A description of the expected shape of a Ruby value.
+#[derive(Debug, Clone)]
+pub enum Schema {
+    /// Any value is accepted.
+    Any,
+
+    /// A nil.
+    Nil,
+
+    /// A bool.
+    Bool,
+
+    /// A Fixnum.
+    Fixnum,
+
+    /// A Bignum.
+    Bignum,
+
+    /// A Float.
+    Float,
+
+    /// A Symbol.
+    Symbol,
+
+    /// A String.
+    String,
+
+    /// An Array, where every element matches the given schema.
+    Array(Box<Schema>),
+
+    /// A Hash, where every key and value match the given schemas.
+    Hash {
+        /// The key schema
+        key: Box<Schema>,
+
+        /// The value schema
+        value: Box<Schema>,
+    },
+
+    /// An Object of the given class.
+    Object {
+        /// The class name
+        name: Vec<u8>,
+
+        /// Instance variables that must be present, and their schemas.
+        ///
+        /// Instance variables not listed here are allowed.
+        instance_variables: Vec<(Vec<u8>, Schema)>,
+    },
+
+    /// A User Defined value of the given class.
+    UserDefined {
+        /// The class name
+        name: Vec<u8>,
+    },
+
+    /// A Class.
+    Class,
+}
+
+impl Schema {
+    /// A schema that accepts anything.
+    pub fn any() -> Self {
+        Self::Any
+    }
+
+    /// A schema for a nil.
+    pub fn nil() -> Self {
+        Self::Nil
+    }
+
+    /// A schema for a bool.
+    pub fn bool() -> Self {
+        Self::Bool
+    }
+
+    /// A schema for a Fixnum.
+    pub fn fixnum() -> Self {
+        Self::Fixnum
+    }
+
+    /// A schema for a Bignum.
+    pub fn bignum() -> Self {
+        Self::Bignum
+    }
+
+    /// A schema for a Float.
+    pub fn float() -> Self {
+        Self::Float
+    }
+
+    /// A schema for a Symbol.
+    pub fn symbol() -> Self {
+        Self::Symbol
+    }
+
+    /// A schema for a String.
+    pub fn string() -> Self {
+        Self::String
+    }
+
+    /// A schema for an Array whose elements all match `element`.
+    pub fn array(element: Schema) -> Self {
+        Self::Array(Box::new(element))
+    }
+
+    /// A schema for a Hash whose keys and values match `key` and `value`.
+    pub fn hash(key: Schema, value: Schema) -> Self {
+        Self::Hash {
+            key: Box::new(key),
+            value: Box::new(value),
+        }
+    }
+
+    /// A schema for an Object of the given class, with no required instance variables.
+    pub fn object(name: impl Into<Vec<u8>>) -> Self {
+        Self::Object {
+            name: name.into(),
+            instance_variables: Vec::new(),
+        }
+    }
+
+    /// A schema for a User Defined value of the given class.
+    pub fn user_defined(name: impl Into<Vec<u8>>) -> Self {
+        Self::UserDefined { name: name.into() }
+    }
+
+    /// A schema for a Class.
+    pub fn class() -> Self {
+        Self::Class
+    }
+
+    /// Require an instance variable on an object schema.
+    ///
+    /// # Panics
+    /// Panics if this is not an object schema.
+    pub fn ivar(mut self, name: impl Into<Vec<u8>>, schema: Schema) -> Self {
+        match &mut self {
+            Self::Object {
+                instance_variables, ..
+            } => {
+                instance_variables.push((name.into(), schema));
+            }
+            _ => panic!("not an object schema"),
+        }
+
+        self
+    }
+
+    /// Validate the value denoted by the given handle against this schema.
+    pub(crate) fn validate(
+        &self,
+        arena: &ValueArena,
+        handle: ValueHandle,
+    ) -> Result<(), SchemaError> {
+        let value = arena
+            .get(handle)
+            .ok_or(SchemaError::InvalidValueHandle { handle })?;
+
+        let expected = match self {
+            Self::Any => return Ok(()),
+            Self::Nil => ValueKind::Nil,
+            Self::Bool => ValueKind::Bool,
+            Self::Fixnum => ValueKind::Fixnum,
+            Self::Bignum => ValueKind::Bignum,
+            Self::Float => ValueKind::Float,
+            Self::Symbol => ValueKind::Symbol,
+            Self::String => ValueKind::String,
+            Self::Array(_) => ValueKind::Array,
+            Self::Hash { .. } => ValueKind::Hash,
+            Self::Object { .. } => ValueKind::Object,
+            Self::UserDefined { .. } => ValueKind::UserDefined,
+            Self::Class => ValueKind::Class,
+        };
+        let actual = value.kind();
+        if expected != actual {
+            return Err(SchemaError::UnexpectedValueKind {
+                handle,
+                expected,
+                actual,
+            });
+        }
+
+        match (self, value) {
+            (Self::Array(element), Value::Array(value)) => {
+                for handle in value.value().iter().copied() {
+                    element.validate(arena, handle)?;
+                }
+            }
+            (Self::Hash { key, value }, Value::Hash(hash)) => {
+                for (key_handle, value_handle) in hash.value().iter().copied() {
+                    key.validate(arena, key_handle)?;
+                    value.validate(arena, value_handle)?;
+                }
+            }
+            (
+                Self::Object {
+                    name,
+                    instance_variables,
+                },
+                Value::Object(object),
+            ) => {
+                validate_class_name(arena, handle, name, object.name().into())?;
+
+                for (ivar_name, ivar_schema) in instance_variables.iter() {
+                    let ivar_handle = object
+                        .instance_variables()
+                        .iter()
+                        .find_map(|(key, value)| {
+                            let key = arena.get_symbol(*key)?.value();
+                            (key == ivar_name.as_slice()).then_some(*value)
+                        })
+                        .ok_or_else(|| SchemaError::MissingInstanceVariable {
+                            handle,
+                            name: ivar_name.clone(),
+                        })?;
+
+                    ivar_schema.validate(arena, ivar_handle)?;
+                }
+            }
+            (Self::UserDefined { name }, Value::UserDefined(value)) => {
+                validate_class_name(arena, handle, name, value.name().into())?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Validate that the symbol at `name_handle` matches the `expected` class name.
+fn validate_class_name(
+    arena: &ValueArena,
+    handle: ValueHandle,
+    expected: &[u8],
+    name_handle: ValueHandle,
+) -> Result<(), SchemaError> {
+    let actual = arena
+        .get(name_handle)
+        .and_then(|value| value.as_symbol())
+        .ok_or(SchemaError::InvalidValueHandle {
+            handle: name_handle,
+        })?
+        .value();
+
+    if actual != expected {
+        return Err(SchemaError::UnexpectedClassName {
+            handle,
+            expected: expected.to_vec(),
+            actual: actual.to_vec(),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::load;
+
+    // Marshal.dump([Point.new(1, 2)]), where Point has @x and @y.
+    const POINTS: &[u8] = b"\x04\x08[\x06o:\x0aPoint\x07:\x07@xi\x06:\x07@yi\x07";
+
+    fn point_schema() -> Schema {
+        Schema::object("Point")
+            .ivar("@x", Schema::fixnum())
+            .ivar("@y", Schema::fixnum())
+    }
+
+    #[test]
+    fn matching() {
+        let arena = load(POINTS).expect("failed to load");
+
+        arena
+            .validate_against(arena.root(), &Schema::array(point_schema()))
+            .expect("failed to validate");
+        arena
+            .validate_against(arena.root(), &Schema::any())
+            .expect("failed to validate");
+    }
+
+    #[test]
+    fn mismatching() {
+        let arena = load(POINTS).expect("failed to load");
+
+        let error = arena
+            .validate_against(arena.root(), &point_schema())
+            .expect_err("validated an array as an object");
+        assert!(matches!(
+            error,
+            SchemaError::UnexpectedValueKind {
+                expected: ValueKind::Object,
+                actual: ValueKind::Array,
+                ..
+            }
+        ));
+
+        let schema = Schema::array(Schema::object("Vector"));
+        let error = arena
+            .validate_against(arena.root(), &schema)
+            .expect_err("validated the wrong class name");
+        assert!(matches!(error, SchemaError::UnexpectedClassName { .. }));
+
+        let schema = Schema::array(point_schema().ivar("@z", Schema::fixnum()));
+        let error = arena
+            .validate_against(arena.root(), &schema)
+            .expect_err("validated a missing ivar");
+        assert!(
+            matches!(error, SchemaError::MissingInstanceVariable { ref name, .. } if name == b"@z")
+        );
+
+        let schema = Schema::array(
+            Schema::object("Point")
+                .ivar("@x", Schema::fixnum())
+                .ivar("@y", Schema::string()),
+        );
+        let error = arena
+            .validate_against(arena.root(), &schema)
+            .expect_err("validated an ivar of the wrong type");
+        assert!(matches!(
+            error,
+            SchemaError::UnexpectedValueKind {
+                expected: ValueKind::String,
+                actual: ValueKind::Fixnum,
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::dump;
+use crate::load;
+use crate::Error;
+use crate::GenericValueArena;
+use crate::Storage;
+use crate::ValueArena;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Load from Base64 text, like the output of Ruby's `Base64.encode64`.
+///
+/// Whitespace is skipped, and the trailing padding may be left out.
+pub fn load_base64(text: &str) -> Result<ValueArena, Error> {
+    let data = decode_base64(text)?;
+    load(&data[..])
+}
+
+/// Load from hex text, like the output of Ruby's `unpack1("H*")`.
+///
+/// Whitespace is skipped, and both upper and lower case digits are accepted.
+pub fn load_hex(text: &str) -> Result<ValueArena, Error> {
+    let data = decode_hex(text)?;
+    load(&data[..])
+}
+
+/// Dump to padded Base64 text, without line breaks.
+pub fn dump_base64<S>(value_arena: &GenericValueArena<S>) -> Result<String, Error>
+where
+    S: Storage,
+{
+    let mut data = Vec::new();
+    dump(&mut data, value_arena)?;
+
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let mut bytes = [0; 3];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                text.push(char::from(BASE64_ALPHABET[index as usize]));
+            } else {
+                text.push('=');
+            }
+        }
+    }
+
+    Ok(text)
+}
+
+/// Dump to lower case hex text.
+pub fn dump_hex<S>(value_arena: &GenericValueArena<S>) -> Result<String, Error>
+where
+    S: Storage,
+{
+    let mut data = Vec::new();
+    dump(&mut data, value_arena)?;
+
+    let mut text = String::with_capacity(data.len() * 2);
+    for byte in data {
+        text.push(char::from(HEX_DIGITS[usize::from(byte >> 4)]));
+        text.push(char::from(HEX_DIGITS[usize::from(byte & 0xf)]));
+    }
+
+    Ok(text)
+}
+
+/// Decode Base64 text, skipping whitespace.
+fn decode_base64(text: &str) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::with_capacity(text.len() / 4 * 3);
+    let mut buffer = 0_u32;
+    let mut bits = 0;
+    let mut padded = false;
+    for (position, byte) in text.bytes().enumerate() {
+        if byte.is_ascii_whitespace() {
+            continue;
+        }
+        if byte == b'=' {
+            padded = true;
+            continue;
+        }
+
+        let value = BASE64_ALPHABET
+            .iter()
+            .position(|digit| *digit == byte)
+            .filter(|_| !padded)
+            .ok_or(Error::InvalidEncodedText { position })?;
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    // A single digit in the last group does not make a whole byte.
+    if bits >= 6 {
+        return Err(Error::InvalidEncodedText {
+            position: text.len(),
+        });
+    }
+
+    Ok(data)
+}
+
+/// Decode hex text, skipping whitespace.
+fn decode_hex(text: &str) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::with_capacity(text.len() / 2);
+    let mut high = None;
+    for (position, byte) in text.bytes().enumerate() {
+        if byte.is_ascii_whitespace() {
+            continue;
+        }
+
+        let value = char::from(byte)
+            .to_digit(16)
+            .ok_or(Error::InvalidEncodedText { position })? as u8;
+        match high.take() {
+            Some(high) => data.push((high << 4) | value),
+            None => high = Some(value),
+        }
+    }
+
+    if high.is_some() {
+        return Err(Error::InvalidEncodedText {
+            position: text.len(),
+        });
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Value;
+
+    #[test]
+    fn base64_round_trip() {
+        // Marshal.dump(nil)
+        let arena = load_base64("BAgw\n").expect("failed to load");
+        assert!(matches!(arena[arena.root()], Value::Nil(_)));
+
+        // Marshal.dump([1, "ab"])
+        let data = b"\x04\x08[\x07i\x06I\"\x07ab\x06:\x06ET";
+        let arena = load(&data[..]).expect("failed to load");
+        let text = dump_base64(&arena).expect("failed to dump");
+        assert!(text == "BAhbB2kGSSIHYWIGOgZFVA==", "{text}");
+
+        let arena = load_base64(&text).expect("failed to load");
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(data[..] == new_data, "{data:?} != {new_data:?}");
+
+        let error = load_base64("BA!w").expect_err("loaded invalid base64");
+        assert!(matches!(error, Error::InvalidEncodedText { position: 2 }));
+        let error = load_base64("BAgw=A").expect_err("loaded data after padding");
+        assert!(matches!(error, Error::InvalidEncodedText { position: 5 }));
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let arena = load_hex("04 08 5B 06 69 06").expect("failed to load");
+        let text = dump_hex(&arena).expect("failed to dump");
+        assert!(text == "04085b066906");
+
+        let error = load_hex("04080").expect_err("loaded an odd number of digits");
+        assert!(matches!(error, Error::InvalidEncodedText { position: 5 }));
+    }
+}
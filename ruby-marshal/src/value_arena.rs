@@ -1,39 +1,222 @@
+mod event;
+mod hash_builder;
+mod storage;
 mod value;
 mod value_handle;
+mod value_ref;
 
+pub use self::event::Event;
+pub use self::hash_builder::HashBuilder;
+pub use self::storage::SlotMapStorage;
+pub use self::storage::Storage;
 pub use self::value::ArrayValue;
+pub use self::value::BignumValue;
 pub use self::value::BoolValue;
 pub use self::value::FixnumValue;
 pub use self::value::FloatValue;
 pub use self::value::HashValue;
 pub use self::value::NilValue;
 pub use self::value::ObjectValue;
+pub use self::value::RegexpOptions;
+pub use self::value::RegexpValue;
 pub use self::value::StringValue;
+pub use self::value::StructValue;
 pub use self::value::SymbolValue;
 pub use self::value::UserDefinedValue;
+pub use self::value::UserMarshalValue;
 pub use self::value::ClassValue;
 pub use self::value::Value;
 pub use self::value::ValueKind;
 pub use self::value_handle::TypedValueHandle;
 pub use self::value_handle::ValueHandle;
-use slotmap::SlotMap;
+pub use self::value_ref::ValueRef;
+use crate::DisplayByteString;
+use crate::Dumper;
+use crate::Error;
+use crate::HashMap;
+use crate::HashSet;
+use crate::PathSegment;
+use crate::Schema;
+use crate::SchemaError;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::collections::BTreeSet;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use core::hash::Hash;
+use core::hash::Hasher;
+use core::ops::Range;
+use foldhash::fast::FixedState;
 
-/// An arena of Ruby values.
+/// The number of levels [`ValueArena::content_hash`] descends into a value.
+const CONTENT_HASH_DEPTH: usize = 8;
+
+/// The original bytes of a loaded stream, and where each value was read from.
+#[derive(Debug, Default)]
+pub(crate) struct Source {
+    /// The bytes of the stream, including the header.
+    pub(crate) bytes: Vec<u8>,
+
+    /// The span of each value that was not read as a link.
+    pub(crate) spans: HashMap<ValueHandle, SourceSpan>,
+
+    /// The symbol table of the stream, in order.
+    pub(crate) symbol_links: Vec<TypedValueHandle<SymbolValue>>,
+
+    /// The object table of the stream, in order.
+    pub(crate) object_links: Vec<ValueHandle>,
+}
+
+/// The span of a value in a [`Source`].
+#[derive(Debug, Clone)]
+pub(crate) struct SourceSpan {
+    /// The byte range of the value.
+    pub(crate) bytes: Range<usize>,
+
+    /// The range of symbol table entries the value added.
+    pub(crate) symbol_links: Range<usize>,
+
+    /// The range of object table entries the value added.
+    pub(crate) object_links: Range<usize>,
+}
+
+/// An arena of Ruby values, with the default storage.
+pub type ValueArena = GenericValueArena<SlotMapStorage>;
+
+/// An arena of Ruby values, generic over how they are stored.
 #[derive(Debug)]
-pub struct ValueArena {
-    arena: SlotMap<slotmap::DefaultKey, Value>,
+pub struct GenericValueArena<S = SlotMapStorage> {
+    storage: S,
     root: ValueHandle,
+
+    source: Option<Box<Source>>,
+    dirty: HashSet<ValueHandle>,
+
+    creation_order: Option<Vec<ValueHandle>>,
+
+    /// The `E` symbol shared by strings made with [`GenericValueArena::create_utf8_string`].
+    utf8_encoding_symbol: Option<TypedValueHandle<SymbolValue>>,
 }
 
-impl ValueArena {
-    /// Make a new empty [`ValueArena`].
+impl<S> GenericValueArena<S>
+where
+    S: Storage,
+{
+    /// Make a new empty arena.
     ///
     /// The root node is nil.
     pub fn new() -> Self {
-        let mut arena = SlotMap::new();
-        let root = ValueHandle::new(arena.insert(Value::Nil(NilValue)));
+        let mut storage = S::default();
+        let root = storage.push(Value::Nil(NilValue));
+
+        Self {
+            storage,
+            root,
+            source: None,
+            dirty: HashSet::new(),
+            creation_order: None,
+            utf8_encoding_symbol: None,
+        }
+    }
+
+    /// Get the number of values in the arena, including orphans.
+    pub fn len(&self) -> usize {
+        self.storage.len()
+    }
+
+    /// Check whether the arena has no values.
+    ///
+    /// The arena always holds its root, so this is always false.
+    pub fn is_empty(&self) -> bool {
+        self.storage.is_empty()
+    }
+
+    /// Get the number of symbol values in the arena, including orphans.
+    ///
+    /// Loading creates one symbol value per distinct symbol, so for a loaded arena
+    /// this is the number of distinct symbols in the stream.
+    pub fn symbol_count(&self) -> usize {
+        self.iter()
+            .filter(|(_, value)| matches!(value, Value::Symbol(_)))
+            .count()
+    }
 
-        Self { arena, root }
+    /// Get the number of values the arena can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.storage.capacity()
+    }
+
+    /// Reserve space for at least `additional` more values.
+    pub fn reserve(&mut self, additional: usize) {
+        self.storage.reserve(additional);
+    }
+
+    /// Remove all values, resetting the root to a new nil.
+    ///
+    /// This retains the allocated storage for reuse.
+    /// All previously issued handles are invalidated.
+    pub fn clear(&mut self) {
+        self.storage.clear();
+        self.root = self.storage.push(Value::Nil(NilValue));
+        self.source = None;
+        self.dirty.clear();
+        if let Some(creation_order) = self.creation_order.as_mut() {
+            creation_order.clear();
+        }
+        self.utf8_encoding_symbol = None;
+    }
+
+    /// Start recording the handle of every value created from now on.
+    pub(crate) fn record_creation_order(&mut self) {
+        self.creation_order.get_or_insert_with(Vec::new);
+    }
+
+    /// Get the handles of the values created since the arena started recording, in creation order.
+    ///
+    /// This is only recorded if the arena was loaded with
+    /// [`LoadOptions::record_creation_order`](crate::LoadOptions), and is empty otherwise.
+    /// Values created after loading are appended.
+    /// Arrays, hashes, and objects are created before their children,
+    /// so this is the order their first bytes appear in the stream.
+    pub fn creation_order(&self) -> &[ValueHandle] {
+        self.creation_order.as_deref().unwrap_or_default()
+    }
+
+    /// Insert a new value, recording its creation if needed.
+    fn insert(&mut self, value: Value) -> ValueHandle {
+        let handle = self.storage.push(value);
+        if let Some(creation_order) = self.creation_order.as_mut() {
+            creation_order.push(handle);
+        }
+
+        handle
+    }
+
+    /// Get the source this arena was loaded from, if it was recorded.
+    pub(crate) fn source(&self) -> Option<&Source> {
+        self.source.as_deref()
+    }
+
+    /// Set the source this arena was loaded from, marking every value as unmodified.
+    pub(crate) fn set_source(&mut self, source: Source) {
+        self.source = Some(Box::new(source));
+        self.dirty.clear();
+    }
+
+    /// Mark the value denoted by the given handle as modified.
+    pub(crate) fn mark_dirty(&mut self, handle: ValueHandle) {
+        if self.source.is_some() {
+            self.dirty.insert(handle);
+        }
+    }
+
+    /// Check whether the value denoted by the given handle was modified since it was loaded.
+    pub(crate) fn is_dirty(&self, handle: ValueHandle) -> bool {
+        self.dirty.contains(&handle)
     }
 
     /// Get the root [`ValueHandle`].
@@ -48,7 +231,7 @@ impl ValueArena {
     {
         let mut new_root = new_root.into();
 
-        std::mem::swap(&mut self.root, &mut new_root);
+        core::mem::swap(&mut self.root, &mut new_root);
         new_root
     }
 
@@ -57,15 +240,67 @@ impl ValueArena {
     where
         H: Into<ValueHandle>,
     {
-        self.arena.get(handle.into().index)
+        self.storage.get(handle.into())
+    }
+
+    /// Resolve the given handle into a [`ValueRef`], which can be compared to Rust values.
+    pub fn get_ref<H>(&self, handle: H) -> ValueRef<'_, S>
+    where
+        H: Into<ValueHandle>,
+    {
+        ValueRef::new(self, handle.into())
     }
 
     /// Get a mutable reference to the [`Value`] denoted by the given [`ValueHandle`].
+    ///
+    /// If the arena has a source, the value is marked as modified.
     pub(crate) fn get_mut<H>(&mut self, handle: H) -> Option<&mut Value>
     where
         H: Into<ValueHandle>,
     {
-        self.arena.get_mut(handle.into().index)
+        let handle = handle.into();
+        self.mark_dirty(handle);
+
+        self.storage.get_mut(handle)
+    }
+
+    /// Iterate over every value in the arena, including orphans.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (ValueHandle, &Value)> {
+        self.storage.iter()
+    }
+
+    /// Encode the value denoted by the given handle as a standalone Marshal document.
+    ///
+    /// This includes the header, and is equivalent to dumping an arena with that value as its root.
+    pub fn encode_value(&self, handle: ValueHandle) -> Result<Vec<u8>, Error> {
+        let mut dumper = Dumper::new(Vec::new(), self);
+        dumper.write_header()?;
+        dumper.write_value(handle)?;
+
+        Ok(dumper.into_inner())
+    }
+
+    /// Get the size of [`encode_value`](Self::encode_value) for the given handle,
+    /// without keeping the bytes.
+    ///
+    /// Values shared within the subtree are counted once, as later references are links.
+    pub fn subtree_size(&self, handle: ValueHandle) -> Result<usize, Error> {
+        let mut dumper = Dumper::new(crate::io::sink(), self);
+        dumper.write_header()?;
+        dumper.write_value(handle)?;
+
+        Ok(dumper.bytes_written())
+    }
+
+    /// Check whether dumping this arena would reproduce `original` exactly.
+    ///
+    /// This is useful to check that an arena built by hand matches what Ruby writes.
+    /// Use [`diff_bytes`](crate::diff_bytes) to find where the two diverge.
+    pub fn would_roundtrip(&self, original: &[u8]) -> Result<bool, Error> {
+        let mut data = Vec::new();
+        crate::dump(&mut data, self)?;
+
+        Ok(data == original)
     }
 
     /// Get a reference to the [`SymbolValue`] denoted by the given [`TypedValueHandle`].
@@ -76,50 +311,74 @@ impl ValueArena {
         Some(self.get(handle)?.as_symbol().expect("not a symbol"))
     }
 
+    /// Get the bytes of the String or Symbol denoted by the given handle.
+    ///
+    /// This is for text that may be serialized either way, like names.
+    ///
+    /// # Returns
+    /// Returns None if the value is neither a String nor a Symbol.
+    pub fn as_text_bytes<H>(&self, handle: H) -> Option<&[u8]>
+    where
+        H: Into<ValueHandle>,
+    {
+        match self.get(handle)? {
+            Value::String(value) => Some(value.value()),
+            Value::Symbol(value) => Some(value.value()),
+            _ => None,
+        }
+    }
+
     /// Create an orphan `Nil` value and return the handle.
     pub fn create_nil(&mut self) -> TypedValueHandle<NilValue> {
-        let index = self.arena.insert(Value::Nil(NilValue));
-        let handle = ValueHandle::new(index);
+        let handle = self.insert(Value::Nil(NilValue));
 
         TypedValueHandle::new_unchecked(handle)
     }
 
     /// Create an orphan `Bool` value and return the handle.
     pub fn create_bool(&mut self, value: bool) -> TypedValueHandle<BoolValue> {
-        let index = self.arena.insert(Value::Bool(BoolValue::new(value)));
-        let handle = ValueHandle::new(index);
+        let handle = self.insert(Value::Bool(BoolValue::new(value)));
 
         TypedValueHandle::new_unchecked(handle)
     }
 
     /// Create an orphan `Fixnum` value and return the handle.
     pub fn create_fixnum(&mut self, value: i32) -> TypedValueHandle<FixnumValue> {
-        let index = self.arena.insert(Value::Fixnum(FixnumValue::new(value)));
-        let handle = ValueHandle::new(index);
+        let handle = self.insert(Value::Fixnum(FixnumValue::new(value)));
+
+        TypedValueHandle::new_unchecked(handle)
+    }
+
+    /// Create an orphan `Bignum` value and return the handle.
+    ///
+    /// The magnitude is in little-endian bytes.
+    pub fn create_bignum(
+        &mut self,
+        positive: bool,
+        magnitude: Vec<u8>,
+    ) -> TypedValueHandle<BignumValue> {
+        let handle = self.insert(Value::Bignum(BignumValue::new(positive, magnitude)));
 
         TypedValueHandle::new_unchecked(handle)
     }
 
     /// Create an orphan `Float` value and return the handle.
     pub fn create_float(&mut self, value: f64) -> TypedValueHandle<FloatValue> {
-        let index = self.arena.insert(Value::Float(FloatValue::new(value)));
-        let handle = ValueHandle::new(index);
+        let handle = self.insert(Value::Float(FloatValue::new(value)));
 
         TypedValueHandle::new_unchecked(handle)
     }
 
     /// Create an orphan `Symbol` value and return the handle.
     pub fn create_symbol(&mut self, value: Vec<u8>) -> TypedValueHandle<SymbolValue> {
-        let index = self.arena.insert(Value::Symbol(SymbolValue::new(value)));
-        let handle = ValueHandle::new(index);
+        let handle = self.insert(Value::Symbol(SymbolValue::new(value)));
 
         TypedValueHandle::new_unchecked(handle)
     }
 
     /// Create an orphan `Array` value and return the handle.
     pub fn create_array(&mut self, value: Vec<ValueHandle>) -> TypedValueHandle<ArrayValue> {
-        let index = self.arena.insert(Value::Array(ArrayValue::new(value)));
-        let handle = ValueHandle::new(index);
+        let handle = self.insert(Value::Array(ArrayValue::new(value)));
 
         TypedValueHandle::new_unchecked(handle)
     }
@@ -130,32 +389,105 @@ impl ValueArena {
         value: Vec<(ValueHandle, ValueHandle)>,
         default_value: Option<ValueHandle>,
     ) -> TypedValueHandle<HashValue> {
-        let index = self
-            .arena
-            .insert(Value::Hash(HashValue::new(value, default_value)));
-        let handle = ValueHandle::new(index);
+        let handle = self.insert(Value::Hash(HashValue::new(value, default_value)));
 
         TypedValueHandle::new_unchecked(handle)
     }
 
+    /// Start building an orphan `Hash` value.
+    ///
+    /// This is an alternative to [`create_hash`](Self::create_hash) that adds one entry at a time.
+    pub fn hash(&mut self) -> HashBuilder<'_, S> {
+        HashBuilder::new(self)
+    }
+
     /// Create an orphan `Object` value and return the handle.
     pub fn create_object(
         &mut self,
         name: TypedValueHandle<SymbolValue>,
         instance_variables: Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>,
     ) -> TypedValueHandle<ObjectValue> {
-        let index = self
-            .arena
-            .insert(Value::Object(ObjectValue::new(name, instance_variables)));
-        let handle = ValueHandle::new(index);
+        let handle = self.insert(Value::Object(ObjectValue::new(name, instance_variables)));
+
+        TypedValueHandle::new_unchecked(handle)
+    }
+
+    /// Create an orphan `Exception` object and return the handle.
+    ///
+    /// Ruby stores the message and backtrace of an exception in the `mesg` and `bt`
+    /// instance variables, which have no `@` prefix.
+    /// The strings are UTF-8, and an empty backtrace is written as nil,
+    /// like that of an exception that was never raised.
+    pub fn create_exception(
+        &mut self,
+        class: &str,
+        message: &str,
+        backtrace: &[&str],
+    ) -> TypedValueHandle<ObjectValue> {
+        let name = self.create_symbol(class.into());
+        let message_name = self.create_symbol(b"mesg".to_vec());
+        let message = self.create_utf8_string(message).into_raw();
+        let backtrace_name = self.create_symbol(b"bt".to_vec());
+        let backtrace = if backtrace.is_empty() {
+            self.create_nil().into_raw()
+        } else {
+            let lines = backtrace
+                .iter()
+                .map(|line| self.create_utf8_string(line).into_raw())
+                .collect();
+            self.create_array(lines).into_raw()
+        };
+
+        self.create_object(
+            name,
+            vec![(message_name, message), (backtrace_name, backtrace)],
+        )
+    }
+
+    /// Create an orphan `Struct` value and return the handle.
+    pub fn create_struct(
+        &mut self,
+        name: TypedValueHandle<SymbolValue>,
+        members: Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>,
+    ) -> TypedValueHandle<StructValue> {
+        let handle = self.insert(Value::Struct(StructValue::new(name, members)));
 
         TypedValueHandle::new_unchecked(handle)
     }
 
     /// Create an orphan `String` value and return the handle.
     pub fn create_string(&mut self, value: Vec<u8>) -> TypedValueHandle<StringValue> {
-        let index = self.arena.insert(Value::String(StringValue::new(value)));
-        let handle = ValueHandle::new(index);
+        let handle = self.insert(Value::String(StringValue::new(value)));
+
+        TypedValueHandle::new_unchecked(handle)
+    }
+
+    /// Create an orphan UTF-8 `String` value and return the handle.
+    ///
+    /// This sets the `E` instance variable to true, which Ruby loads as the UTF-8 encoding.
+    pub fn create_utf8_string(&mut self, value: &str) -> TypedValueHandle<StringValue> {
+        let encoding = match self.utf8_encoding_symbol {
+            Some(encoding) => encoding,
+            None => {
+                let encoding = self.create_symbol(b"E".to_vec());
+                self.utf8_encoding_symbol = Some(encoding);
+                encoding
+            }
+        };
+        let is_utf8 = self.create_bool(true).into_raw();
+
+        let mut string = StringValue::new(value.into());
+        string.set_instance_variables(Some(vec![(encoding, is_utf8)]));
+        let handle = self.insert(Value::String(string));
+
+        TypedValueHandle::new_unchecked(handle)
+    }
+
+    /// Create an orphan `Regexp` value and return the handle.
+    ///
+    /// The options are a raw byte, as built from the constants of [`RegexpOptions`].
+    pub fn create_regexp(&mut self, source: Vec<u8>, options: u8) -> TypedValueHandle<RegexpValue> {
+        let handle = self.insert(Value::Regexp(RegexpValue::new(source, options)));
 
         TypedValueHandle::new_unchecked(handle)
     }
@@ -166,33 +498,1818 @@ impl ValueArena {
         name: TypedValueHandle<SymbolValue>,
         value: Vec<u8>,
     ) -> TypedValueHandle<UserDefinedValue> {
-        let index = self
-            .arena
-            .insert(Value::UserDefined(UserDefinedValue::new(name, value)));
-        let handle = ValueHandle::new(index);
+        let handle = self.insert(Value::UserDefined(UserDefinedValue::new(name, value)));
+
+        TypedValueHandle::new_unchecked(handle)
+    }
+
+    /// Create an orphan `UserMarshal` value and return the handle.
+    ///
+    /// The value is what the class's `marshal_dump` returned.
+    pub fn create_user_marshal(
+        &mut self,
+        name: TypedValueHandle<SymbolValue>,
+        value: ValueHandle,
+    ) -> TypedValueHandle<UserMarshalValue> {
+        let handle = self.insert(Value::UserMarshal(UserMarshalValue::new(name, value)));
 
         TypedValueHandle::new_unchecked(handle)
     }
 
     /// Create an orphan `Class` value and return the handle.
     pub fn create_class(&mut self, name: Vec<u8>) -> TypedValueHandle<ClassValue> {
-        let index = self.arena.insert(Value::Class(ClassValue::new(name)));
-        let handle = ValueHandle::new(index);
+        let handle = self.insert(Value::Class(ClassValue::new(name)));
 
         TypedValueHandle::new_unchecked(handle)
     }
+
+    /// Build an arena from a JSON value, as Ruby's `JSON.parse` would.
+    ///
+    /// Null, bools, arrays, and floats map to their Ruby equivalents.
+    /// Integers become fixnums if they fit in 31 bits, as Ruby writes them, or bignums otherwise.
+    /// Strings become UTF-8 strings, and objects become hashes with string keys.
+    #[cfg(feature = "json")]
+    pub fn from_json(value: &serde_json::Value) -> Self {
+        let mut arena = Self::new();
+        let root = arena.create_from_json(value);
+        arena.replace_root(root);
+        arena
+    }
+
+    /// Create an orphan value from a JSON value and return the handle.
+    #[cfg(feature = "json")]
+    fn create_from_json(&mut self, value: &serde_json::Value) -> ValueHandle {
+        match value {
+            serde_json::Value::Null => self.create_nil().into(),
+            serde_json::Value::Bool(value) => self.create_bool(*value).into(),
+            serde_json::Value::Number(value) => {
+                if let Some(value) = value
+                    .as_i64()
+                    .filter(|value| (-(1 << 30)..1 << 30).contains(value))
+                    .and_then(|value| i32::try_from(value).ok())
+                {
+                    return self.create_fixnum(value).into();
+                }
+                let (positive, magnitude) = match (value.as_i64(), value.as_u64()) {
+                    (Some(value), _) => (value >= 0, value.unsigned_abs()),
+                    (None, Some(value)) => (true, value),
+                    (None, None) => {
+                        return self.create_float(value.as_f64().unwrap_or(f64::NAN)).into()
+                    }
+                };
+
+                // Ruby writes the magnitude in 16-bit words.
+                let mut magnitude = magnitude.to_le_bytes().to_vec();
+                let len = magnitude
+                    .iter()
+                    .rposition(|byte| *byte != 0)
+                    .map_or(0, |i| i + 1);
+                magnitude.truncate(len.next_multiple_of(2));
+                self.create_bignum(positive, magnitude).into()
+            }
+            serde_json::Value::String(value) => self.create_utf8_string(value).into(),
+            serde_json::Value::Array(value) => {
+                let value = value
+                    .iter()
+                    .map(|value| self.create_from_json(value))
+                    .collect();
+                self.create_array(value).into()
+            }
+            serde_json::Value::Object(value) => {
+                let value = value
+                    .iter()
+                    .map(|(key, value)| {
+                        let key = self.create_utf8_string(key).into();
+                        (key, self.create_from_json(value))
+                    })
+                    .collect();
+                self.create_hash(value, None).into()
+            }
+        }
+    }
+
+    /// Replace the payload of the `UserDefined` value denoted by the given handle.
+    ///
+    /// The dumper writes the new payload with its own length.
+    ///
+    /// # Panics
+    /// Panics if the value is not a UserDefinedValue.
+    ///
+    /// # Returns
+    /// Returns the old payload, or None if the handle is invalid.
+    pub fn set_user_defined_value(
+        &mut self,
+        handle: TypedValueHandle<UserDefinedValue>,
+        value: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        let old = match self.get_mut(handle)? {
+            Value::UserDefined(user_defined) => user_defined.set_value(value),
+            _ => panic!("not a user defined value"),
+        };
+
+        Some(old)
+    }
+
+    /// Get the handles of all values reachable from the given handle, children before parents.
+    ///
+    /// Each value appears once, and invalid handles are skipped.
+    pub(crate) fn post_order(&self, root: ValueHandle) -> Vec<ValueHandle> {
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![(root, false)];
+
+        while let Some((handle, expanded)) = stack.pop() {
+            if expanded {
+                order.push(handle);
+                continue;
+            }
+
+            let value = match self.get(handle) {
+                Some(value) => value,
+                None => continue,
+            };
+            if !visited.insert(handle) {
+                continue;
+            }
+
+            stack.push((handle, true));
+            let start = stack.len();
+            value.for_each_child(|child| {
+                if !visited.contains(&child) {
+                    stack.push((child, false));
+                }
+            });
+            stack[start..].reverse();
+        }
+
+        order
+    }
+
+    /// Check whether the values reachable from the given handle contain a cycle.
+    pub fn is_cyclic(&self, root: ValueHandle) -> bool {
+        #[derive(Copy, Clone, PartialEq, Eq)]
+        enum Color {
+            Gray,
+            Black,
+        }
+
+        // Unvisited values are implicitly white.
+        let mut colors = HashMap::new();
+        let mut stack = vec![(root, false)];
+        while let Some((handle, expanded)) = stack.pop() {
+            if expanded {
+                colors.insert(handle, Color::Black);
+                continue;
+            }
+            if colors.contains_key(&handle) {
+                continue;
+            }
+            let value = match self.get(handle) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            colors.insert(handle, Color::Gray);
+            stack.push((handle, true));
+
+            let mut cyclic = false;
+            value.for_each_child(|child| match colors.get(&child) {
+                Some(Color::Gray) => cyclic = true,
+                Some(Color::Black) => {}
+                None => stack.push((child, false)),
+            });
+            if cyclic {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Get the maximum nesting depth of the values reachable from the given handle.
+    ///
+    /// A value with no children has a depth of 1.
+    /// Class names and instance variables count as children.
+    /// Cycles are cut at the first repeated value, so a cyclic value's depth is finite.
+    pub fn max_depth(&self, root: ValueHandle) -> usize {
+        let mut depths: HashMap<ValueHandle, usize> = HashMap::new();
+        for handle in self.post_order(root) {
+            // Children that are not yet computed close a cycle.
+            let mut max_child_depth = 0;
+            self[handle].for_each_child(|child| {
+                let depth = depths.get(&child).copied().unwrap_or(0);
+                max_child_depth = max_child_depth.max(depth);
+            });
+            depths.insert(handle, max_child_depth + 1);
+        }
+
+        depths.get(&root).copied().unwrap_or(0)
+    }
+
+    /// Find a shortest path from one value to another.
+    ///
+    /// Paths go through array elements, the values of hash entries, instance variables,
+    /// and struct members, named as in [`FromValueError::AtPath`](crate::FromValueError).
+    /// Hash keys, hash defaults, and names are not followed.
+    ///
+    /// # Returns
+    /// Returns None if `to` cannot be reached from `from`.
+    pub fn path_between(&self, from: ValueHandle, to: ValueHandle) -> Option<Vec<PathSegment>> {
+        let mut parents: HashMap<ValueHandle, (ValueHandle, PathSegment)> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+        let mut visited: HashSet<ValueHandle> = HashSet::from([from]);
+        while let Some(handle) = queue.pop_front() {
+            if handle == to {
+                let mut path = Vec::new();
+                let mut handle = handle;
+                while let Some((parent, segment)) = parents.remove(&handle) {
+                    path.push(segment);
+                    handle = parent;
+                }
+                path.reverse();
+
+                return Some(path);
+            }
+
+            let value = match self.get(handle) {
+                Some(value) => value,
+                None => continue,
+            };
+            let mut visit = |segment, child| {
+                if visited.insert(child) {
+                    parents.insert(child, (handle, segment));
+                    queue.push_back(child);
+                }
+            };
+            match value {
+                Value::Array(value) => {
+                    for (index, child) in value.value().iter().enumerate() {
+                        visit(PathSegment::Index(index), *child);
+                    }
+                }
+                Value::Hash(value) => {
+                    for (index, (key, child)) in value.value().iter().enumerate() {
+                        visit(self.hash_entry_segment(*key, index), *child);
+                    }
+                }
+                Value::Struct(value) => {
+                    for (name, child) in value.members().iter() {
+                        visit(self.name_segment(*name), *child);
+                    }
+                }
+                _ => {}
+            }
+            for (name, child) in value.instance_variables().into_iter().flatten() {
+                visit(self.name_segment(*name), *child);
+            }
+        }
+
+        None
+    }
+
+    /// Get the path segment of the value of a hash entry.
+    pub(crate) fn hash_entry_segment(&self, key: ValueHandle, index: usize) -> PathSegment {
+        match self.as_text_bytes(key) {
+            Some(key) => PathSegment::Key(key.to_vec()),
+            None => PathSegment::Index(index),
+        }
+    }
+
+    /// Get the path segment of an instance variable or struct member.
+    fn name_segment(&self, name: TypedValueHandle<SymbolValue>) -> PathSegment {
+        let name = self.get_symbol(name).map(|name| name.value().to_vec());
+        PathSegment::Key(name.unwrap_or_default())
+    }
+
+    /// Flatten the value denoted by the given handle into events, in the order they are dumped.
+    ///
+    /// Repeated symbols and values become links, numbered as in the dumped stream.
+    /// The events are deterministic, so they suit snapshot tests of parse structure.
+    /// Invalid handles produce no events.
+    pub fn to_events(&self, root: ValueHandle) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut symbol_links = HashMap::new();
+        let mut object_links = HashMap::new();
+        self.to_events_inner(root, &mut symbol_links, &mut object_links, &mut events);
+        events
+    }
+
+    fn to_events_inner(
+        &self,
+        handle: ValueHandle,
+        symbol_links: &mut HashMap<ValueHandle, usize>,
+        object_links: &mut HashMap<ValueHandle, usize>,
+        events: &mut Vec<Event>,
+    ) {
+        let value = match self.get(handle) {
+            Some(value) => value,
+            None => return,
+        };
+
+        if let Value::Symbol(_) = value {
+            if let Some(index) = symbol_links.get(&handle) {
+                events.push(Event::SymbolLink(*index));
+                return;
+            }
+            symbol_links.insert(handle, symbol_links.len());
+        } else if !self.is_immediate(handle) {
+            if let Some(index) = object_links.get(&handle) {
+                events.push(Event::ObjectLink(*index));
+                return;
+            }
+            object_links.insert(handle, object_links.len());
+        }
+
+        let instance_variables = match value {
+            Value::Object(_) => None,
+            value => value.instance_variables(),
+        };
+        if let Some(instance_variables) = instance_variables {
+            events.push(Event::InstanceVariables {
+                len: instance_variables.len(),
+            });
+        }
+        for module in value.extended_modules() {
+            events.push(Event::Extended);
+            self.to_events_inner((*module).into(), symbol_links, object_links, events);
+        }
+        let user_class = match value {
+            Value::Array(value) => value.user_class(),
+            Value::Hash(value) => value.user_class(),
+            Value::String(value) => value.user_class(),
+            Value::Regexp(value) => value.user_class(),
+            _ => None,
+        };
+        if let Some(user_class) = user_class {
+            events.push(Event::UserClass);
+            self.to_events_inner(user_class.into(), symbol_links, object_links, events);
+        }
+        if let Some(identity_class) = value.as_hash().and_then(HashValue::identity_class) {
+            events.push(Event::UserClass);
+            self.to_events_inner(identity_class.into(), symbol_links, object_links, events);
+        }
+
+        let mut children = Vec::new();
+        match value {
+            Value::Nil(_) => events.push(Event::Nil),
+            Value::Bool(value) => events.push(Event::Bool(value.value())),
+            Value::Fixnum(value) => events.push(Event::Fixnum(value.value())),
+            Value::Bignum(value) => events.push(Event::Bignum {
+                is_positive: value.is_positive(),
+                magnitude: value.magnitude().to_vec(),
+            }),
+            Value::Float(value) => events.push(Event::Float(value.value())),
+            Value::Symbol(value) => events.push(Event::Symbol(value.value().to_vec())),
+            Value::Array(value) => {
+                events.push(Event::Array { len: value.len() });
+                children.extend(value.value().iter().copied());
+            }
+            Value::Hash(value) => {
+                events.push(Event::Hash {
+                    len: value.value().len(),
+                    has_default_value: value.default_value().is_some(),
+                });
+                for (key, value) in value.value().iter() {
+                    children.push(*key);
+                    children.push(*value);
+                }
+                children.extend(value.default_value());
+            }
+            Value::Object(value) => {
+                events.push(Event::Object {
+                    len: value.instance_variables().len(),
+                });
+                children.push(value.name().into());
+                for (name, value) in value.instance_variables().iter() {
+                    children.push((*name).into());
+                    children.push(*value);
+                }
+            }
+            Value::Struct(value) => {
+                events.push(Event::Struct {
+                    len: value.members().len(),
+                });
+                children.push(value.name().into());
+                for (name, value) in value.members().iter() {
+                    children.push((*name).into());
+                    children.push(*value);
+                }
+            }
+            Value::String(value) => events.push(Event::String(value.value().to_vec())),
+            Value::Regexp(value) => events.push(Event::Regexp {
+                source: value.source().to_vec(),
+                options: value.raw_options(),
+            }),
+            Value::UserDefined(value) => {
+                events.push(Event::UserDefined(value.value().to_vec()));
+                children.push(value.name().into());
+            }
+            Value::UserMarshal(value) => {
+                events.push(Event::UserMarshal);
+                children.push(value.name().into());
+                children.push(value.value());
+            }
+            Value::Class(value) => events.push(Event::Class(value.name().to_vec())),
+        }
+        for (name, value) in instance_variables.into_iter().flatten() {
+            children.push((*name).into());
+            children.push(*value);
+        }
+
+        for child in children {
+            self.to_events_inner(child, symbol_links, object_links, events);
+        }
+    }
+
+    /// Check whether the value denoted by the given handle is an immediate value.
+    ///
+    /// These are nil, true, false, fixnums, and symbols, which Ruby never writes as object links.
+    /// Symbols have their own links instead.
+    /// Invalid handles are not immediate.
+    pub fn is_immediate(&self, handle: ValueHandle) -> bool {
+        matches!(
+            self.get(handle),
+            Some(Value::Nil(_) | Value::Bool(_) | Value::Fixnum(_) | Value::Symbol(_))
+        )
+    }
+
+    /// Get the values that are reachable from the root through more than one reference.
+    ///
+    /// This includes values that are part of a cycle through themselves,
+    /// as the root counts as a reference to itself.
+    /// These are the values that need an id when converting to a format without sharing.
+    /// Immediate values are never included, as Ruby does not link them.
+    pub fn shared_nodes(&self) -> HashSet<ValueHandle> {
+        let mut references: HashMap<ValueHandle, usize> = HashMap::new();
+        references.insert(self.root, 1);
+        for handle in self.post_order(self.root) {
+            self[handle].for_each_child(|child| *references.entry(child).or_default() += 1);
+        }
+
+        references
+            .into_iter()
+            .filter(|(handle, references)| {
+                *references > 1 && self.get(*handle).is_some() && !self.is_immediate(*handle)
+            })
+            .map(|(handle, _)| handle)
+            .collect()
+    }
+
+    /// Count the values reachable from the given handle, by kind.
+    ///
+    /// The map is ordered by kind, so its output is stable.
+    pub fn kind_histogram(&self, root: ValueHandle) -> BTreeMap<ValueKind, usize> {
+        let mut histogram = BTreeMap::new();
+        for handle in self.post_order(root) {
+            *histogram.entry(self[handle].kind()).or_default() += 1;
+        }
+
+        histogram
+    }
+
+    /// Get the distinct class names referenced by the values reachable from the given handle.
+    ///
+    /// This includes the names of objects, structs, user defined values, and classes,
+    /// and the user classes of arrays, hashes, strings, and regexps.
+    /// The names are sorted, so the output is stable.
+    pub fn referenced_class_names(&self, root: ValueHandle) -> Vec<Vec<u8>> {
+        let mut names = BTreeSet::new();
+        for handle in self.post_order(root) {
+            let name = match &self[handle] {
+                Value::Object(value) => Some(value.name()),
+                Value::Struct(value) => Some(value.name()),
+                Value::UserDefined(value) => Some(value.name()),
+                Value::UserMarshal(value) => Some(value.name()),
+                Value::Array(value) => value.user_class(),
+                Value::Hash(value) => value.user_class(),
+                Value::String(value) => value.user_class(),
+                Value::Regexp(value) => value.user_class(),
+                Value::Class(value) => {
+                    names.insert(value.name().to_vec());
+                    None
+                }
+                _ => None,
+            };
+            if let Some(name) = name.and_then(|name| self.get_symbol(name)) {
+                names.insert(name.value().to_vec());
+            }
+        }
+
+        names.into_iter().collect()
+    }
+
+    /// Call a fallible function with each value reachable from the given handle,
+    /// children before parents, stopping at the first error.
+    ///
+    /// The function may mutate the arena, for example with [`replace_all`](Self::replace_all).
+    /// The values to visit are found before the first call,
+    /// so values it creates are not visited, and values it orphans still are.
+    /// Changes made before an error are kept.
+    pub fn try_map<F, E>(&mut self, root: ValueHandle, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(&mut Self, ValueHandle) -> Result<(), E>,
+    {
+        for handle in self.post_order(root) {
+            f(self, handle)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the user defined values reachable from the root with the given class name,
+    /// along with their payloads.
+    ///
+    /// The values are in post order, children before parents.
+    pub fn user_defined_by_class(&self, name: &[u8]) -> Vec<(ValueHandle, &[u8])> {
+        self.post_order(self.root)
+            .into_iter()
+            .filter_map(|handle| match &self[handle] {
+                Value::UserDefined(value)
+                    if self
+                        .get_symbol(value.name())
+                        .is_some_and(|value_name| value_name.value() == name) =>
+                {
+                    Some((handle, value.value()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Render the values reachable from the given handle as an indented tree, one value per line.
+    ///
+    /// Each value is labeled with an id in order of appearance, its kind, and any scalar contents.
+    /// Values that were already rendered are not expanded again,
+    /// and are marked as either a cycle or shared.
+    /// Symbols are always rendered in full, as they are not objects.
+    pub fn tree_string(&self, root: ValueHandle) -> String {
+        let mut output = String::new();
+        self.write_tree(
+            root,
+            "",
+            "",
+            &mut HashMap::new(),
+            &mut HashSet::new(),
+            &mut output,
+        );
+
+        output
+    }
+
+    fn write_tree(
+        &self,
+        handle: ValueHandle,
+        line_prefix: &str,
+        child_prefix: &str,
+        ids: &mut HashMap<ValueHandle, usize>,
+        ancestors: &mut HashSet<ValueHandle>,
+        output: &mut String,
+    ) {
+        output.push_str(line_prefix);
+
+        let value = match self.get(handle) {
+            Some(value) => value,
+            None => {
+                output.push_str("invalid handle\n");
+                return;
+            }
+        };
+        if let Value::Symbol(value) = value {
+            output.push_str(&format!("Symbol :{}\n", DisplayByteString(value.value())));
+            return;
+        }
+        if let Some(id) = ids.get(&handle) {
+            let mark = if ancestors.contains(&handle) {
+                "cycle"
+            } else {
+                "shared"
+            };
+            output.push_str(&format!("#{id} {:?} ({mark})\n", value.kind()));
+            return;
+        }
+
+        let id = ids.len();
+        ids.insert(handle, id);
+        output.push_str(&format!("#{id} {:?}", value.kind()));
+        match value {
+            Value::Bool(value) => output.push_str(&format!(" {}", value.value())),
+            Value::Fixnum(value) => output.push_str(&format!(" {}", value.value())),
+            Value::Bignum(value) => match value.to_i128() {
+                Some(value) => output.push_str(&format!(" {value}")),
+                None => output.push_str(&format!(" of {} bytes", value.magnitude().len())),
+            },
+            Value::Float(value) => output.push_str(&format!(" {}", value.value())),
+            Value::String(value) => {
+                output.push_str(&format!(" \"{}\"", DisplayByteString(value.value())))
+            }
+            Value::Regexp(value) => {
+                output.push_str(&format!(" /{}/", DisplayByteString(value.source())))
+            }
+            Value::Class(value) => {
+                output.push_str(&format!(" {}", DisplayByteString(value.name())))
+            }
+            _ => {}
+        }
+        output.push('\n');
+
+        let mut children = Vec::new();
+        value.for_each_child(|child| children.push(child));
+
+        ancestors.insert(handle);
+        for (i, child) in children.iter().copied().enumerate() {
+            let (connector, indent) = if i + 1 == children.len() {
+                ("└─ ", "   ")
+            } else {
+                ("├─ ", "│  ")
+            };
+            self.write_tree(
+                child,
+                &format!("{child_prefix}{connector}"),
+                &format!("{child_prefix}{indent}"),
+                ids,
+                ancestors,
+                output,
+            );
+        }
+        ancestors.remove(&handle);
+    }
+
+    /// Compute a hash of the structure of the value denoted by the given handle.
+    ///
+    /// Values that are equal according to [`ValueArena::values_equal`] have equal hashes.
+    /// Only the first few levels of nesting contribute to the hash, so cyclic values are supported.
+    pub fn content_hash(&self, handle: ValueHandle) -> u64 {
+        let mut hasher = FixedState::default().build_hasher();
+        self.content_hash_inner(handle, CONTENT_HASH_DEPTH, &mut hasher);
+        hasher.finish()
+    }
+
+    fn content_hash_inner<H>(&self, handle: ValueHandle, depth: usize, state: &mut H)
+    where
+        H: Hasher,
+    {
+        let value = match self.get(handle) {
+            Some(value) => value,
+            None => return,
+        };
+
+        value.kind().hash(state);
+        match value {
+            Value::Nil(_) => {}
+            Value::Bool(value) => value.value().hash(state),
+            Value::Fixnum(value) => value.value().hash(state),
+            Value::Bignum(value) => {
+                value.is_positive().hash(state);
+                value.magnitude().hash(state);
+            }
+            Value::Float(value) => value.value().to_bits().hash(state),
+            Value::Symbol(value) => value.value().hash(state),
+            Value::Array(value) => {
+                value.len().hash(state);
+                value.user_class().is_some().hash(state);
+                value.instance_variables().map(|v| v.len()).hash(state);
+            }
+            Value::Hash(value) => {
+                value.value().len().hash(state);
+                value.default_value().is_some().hash(state);
+                value.user_class().is_some().hash(state);
+                value.compare_by_identity().hash(state);
+                value.instance_variables().map(|v| v.len()).hash(state);
+            }
+            Value::Object(value) => value.instance_variables().len().hash(state),
+            Value::Struct(value) => value.members().len().hash(state),
+            Value::String(value) => {
+                value.value().hash(state);
+                value.user_class().is_some().hash(state);
+                value.instance_variables().map(|v| v.len()).hash(state);
+            }
+            Value::Regexp(value) => {
+                value.source().hash(state);
+                value.raw_options().hash(state);
+                value.user_class().is_some().hash(state);
+                value.instance_variables().map(|v| v.len()).hash(state);
+            }
+            Value::UserDefined(value) => {
+                value.value().hash(state);
+                value.instance_variables().map(|v| v.len()).hash(state);
+            }
+            Value::UserMarshal(_) => {}
+            Value::Class(value) => value.name().hash(state),
+        }
+        value.extended_modules().len().hash(state);
+
+        if depth > 0 {
+            value.for_each_child(|child| self.content_hash_inner(child, depth - 1, state));
+        }
+    }
+
+    /// Check whether the values denoted by the given handles are structurally equal.
+    ///
+    /// Cyclic values are equal if they cannot be told apart by walking them.
+    /// Floats are compared by their bit patterns, and invalid handles are never equal.
+    pub fn values_equal(&self, a: ValueHandle, b: ValueHandle) -> bool {
+        self.values_equal_inner(a, b, false, &mut HashSet::new())
+    }
+
+    /// Check whether the values denoted by the given handles are structurally equal,
+    /// treating instance variables as sets keyed by name.
+    ///
+    /// Unlike [`ValueArena::values_equal`], values whose instance variables were set in a
+    /// different order compare equal, even though they do not dump to the same bytes.
+    /// Everything else, including the order of struct members, is compared as in `values_equal`.
+    pub fn values_equal_unordered_ivars(&self, a: ValueHandle, b: ValueHandle) -> bool {
+        self.values_equal_inner(a, b, true, &mut HashSet::new())
+    }
+
+    fn values_equal_inner(
+        &self,
+        a: ValueHandle,
+        b: ValueHandle,
+        unordered_ivars: bool,
+        assumed: &mut HashSet<(ValueHandle, ValueHandle)>,
+    ) -> bool {
+        let (a_value, b_value) = match (self.get(a), self.get(b)) {
+            (Some(a_value), Some(b_value)) => (a_value, b_value),
+            _ => return false,
+        };
+        if a == b || !assumed.insert((a, b)) {
+            return true;
+        }
+
+        let same_scalars = match (a_value, b_value) {
+            (Value::Nil(_), Value::Nil(_)) => true,
+            (Value::Bool(a), Value::Bool(b)) => a.value() == b.value(),
+            (Value::Fixnum(a), Value::Fixnum(b)) => a.value() == b.value(),
+            (Value::Bignum(a), Value::Bignum(b)) => {
+                a.is_positive() == b.is_positive() && a.magnitude() == b.magnitude()
+            }
+            (Value::Float(a), Value::Float(b)) => a.value().to_bits() == b.value().to_bits(),
+            (Value::Symbol(a), Value::Symbol(b)) => {
+                a.value() == b.value()
+                    && a.instance_variables().map(|v| v.len())
+                        == b.instance_variables().map(|v| v.len())
+            }
+            (Value::Array(a), Value::Array(b)) => {
+                a.len() == b.len()
+                    && a.user_class().is_some() == b.user_class().is_some()
+                    && a.instance_variables().map(|v| v.len())
+                        == b.instance_variables().map(|v| v.len())
+            }
+            (Value::Hash(a), Value::Hash(b)) => {
+                a.value().len() == b.value().len()
+                    && a.default_value().is_some() == b.default_value().is_some()
+                    && a.user_class().is_some() == b.user_class().is_some()
+                    && a.compare_by_identity() == b.compare_by_identity()
+                    && a.instance_variables().map(|v| v.len())
+                        == b.instance_variables().map(|v| v.len())
+            }
+            (Value::Object(a), Value::Object(b)) => {
+                a.instance_variables().len() == b.instance_variables().len()
+            }
+            (Value::Struct(a), Value::Struct(b)) => a.members().len() == b.members().len(),
+            (Value::String(a), Value::String(b)) => {
+                a.value() == b.value()
+                    && a.user_class().is_some() == b.user_class().is_some()
+                    && a.instance_variables().map(|v| v.len())
+                        == b.instance_variables().map(|v| v.len())
+            }
+            (Value::Regexp(a), Value::Regexp(b)) => {
+                a.source() == b.source()
+                    && a.raw_options() == b.raw_options()
+                    && a.user_class().is_some() == b.user_class().is_some()
+                    && a.instance_variables().map(|v| v.len())
+                        == b.instance_variables().map(|v| v.len())
+            }
+            (Value::UserDefined(a), Value::UserDefined(b)) => {
+                a.value() == b.value()
+                    && a.instance_variables().map(|v| v.len())
+                        == b.instance_variables().map(|v| v.len())
+            }
+            (Value::UserMarshal(_), Value::UserMarshal(_)) => true,
+            (Value::Class(a), Value::Class(b)) => a.name() == b.name(),
+            _ => false,
+        };
+        if !same_scalars || a_value.extended_modules().len() != b_value.extended_modules().len() {
+            return false;
+        }
+
+        let mut a_children = Vec::new();
+        a_value.for_each_child(|child| a_children.push(child));
+        let mut b_children = Vec::new();
+        b_value.for_each_child(|child| b_children.push(child));
+
+        if !unordered_ivars {
+            return a_children
+                .into_iter()
+                .zip(b_children)
+                .all(|(a, b)| self.values_equal_inner(a, b, false, assumed));
+        }
+
+        // Instance variables are always the last children.
+        let a_ivars = a_value.instance_variables().unwrap_or_default();
+        let b_ivars = b_value.instance_variables().unwrap_or_default();
+        let num_fixed = a_children.len() - a_ivars.len() * 2;
+        let same_fixed = a_children[..num_fixed]
+            .iter()
+            .zip(b_children[..num_fixed].iter())
+            .all(|(a, b)| self.values_equal_inner(*a, *b, true, assumed));
+
+        same_fixed
+            && a_ivars.iter().all(|(a_name, a_ivar)| {
+                let a_name = self.get_symbol(*a_name).map(|name| name.value());
+                b_ivars
+                    .iter()
+                    .find(|(b_name, _)| self.get_symbol(*b_name).map(|name| name.value()) == a_name)
+                    .is_some_and(|(_, b_ivar)| {
+                        self.values_equal_inner(*a_ivar, *b_ivar, true, assumed)
+                    })
+            })
+    }
+
+    /// Change the class name of every value of class `from` to `to`.
+    ///
+    /// This covers objects, structs, user defined and user marshal values,
+    /// and the user classes of arrays, hashes, strings, and regexps.
+    /// Symbols used as anything other than a class name are left alone,
+    /// even if they are the same symbol value as a renamed class name.
+    ///
+    /// # Returns
+    /// Returns the number of values that were changed.
+    pub fn retag_class(&mut self, from: &[u8], to: &[u8]) -> usize {
+        let class_name = |value: &Value| match value {
+            Value::Object(value) => Some(value.name()),
+            Value::Struct(value) => Some(value.name()),
+            Value::UserDefined(value) => Some(value.name()),
+            Value::UserMarshal(value) => Some(value.name()),
+            Value::Array(value) => value.user_class(),
+            Value::Hash(value) => value.user_class(),
+            Value::String(value) => value.user_class(),
+            Value::Regexp(value) => value.user_class(),
+            _ => None,
+        };
+
+        let handles: Vec<ValueHandle> = self
+            .iter()
+            .filter(|(_, value)| {
+                class_name(value)
+                    .and_then(|name| self.get_symbol(name))
+                    .is_some_and(|name| name.value() == from)
+            })
+            .map(|(handle, _)| handle)
+            .collect();
+        if handles.is_empty() {
+            return 0;
+        }
+
+        let name = self.create_symbol(to.to_vec());
+        for handle in handles.iter().copied() {
+            match self.get_mut(handle) {
+                Some(Value::Object(value)) => {
+                    value.set_name(name);
+                }
+                Some(Value::Struct(value)) => {
+                    value.set_name(name);
+                }
+                Some(Value::UserDefined(value)) => {
+                    value.set_name(name);
+                }
+                Some(Value::UserMarshal(value)) => {
+                    value.set_name(name);
+                }
+                Some(Value::Array(value)) => {
+                    value.set_user_class(Some(name));
+                }
+                Some(Value::Hash(value)) => {
+                    value.set_user_class(Some(name));
+                }
+                Some(Value::String(value)) => {
+                    value.set_user_class(Some(name));
+                }
+                Some(Value::Regexp(value)) => {
+                    value.set_user_class(Some(name));
+                }
+                _ => {}
+            }
+        }
+
+        handles.len()
+    }
+
+    /// Redirect every reference to `old` to point at `new` instead, throughout the arena.
+    ///
+    /// This rewrites array elements, hash keys, values, and defaults, instance variable values,
+    /// and the root.
+    /// If both handles are symbols, names are rewritten too.
+    ///
+    /// # Returns
+    /// Returns the number of references that were changed.
+    ///
+    /// # Panics
+    /// Panics if `old` is a symbol and `new` is not, as symbols may be used as names.
+    pub fn replace_all(&mut self, old: ValueHandle, new: ValueHandle) -> usize {
+        let old_is_symbol = matches!(self.get(old), Some(Value::Symbol(_)));
+        let new_is_symbol = matches!(self.get(new), Some(Value::Symbol(_)));
+        assert!(
+            !old_is_symbol || new_is_symbol,
+            "a symbol can only be replaced with a symbol"
+        );
+
+        let parents: Vec<ValueHandle> = self
+            .iter()
+            .filter(|(_, value)| {
+                let mut found = false;
+                value.for_each_child(|child| found |= child == old);
+                found
+            })
+            .map(|(handle, _)| handle)
+            .collect();
+
+        let mut count = 0;
+        for handle in parents {
+            if let Some(value) = self.get_mut(handle) {
+                value.map_children(|child| {
+                    if child == old {
+                        count += 1;
+                        new
+                    } else {
+                        child
+                    }
+                });
+            }
+        }
+        if self.root == old {
+            self.root = new;
+            count += 1;
+        }
+
+        count
+    }
+
+    /// Remove every value that is not reachable from the root.
+    ///
+    /// This includes values orphaned by [`replace_root`](Self::replace_root) and
+    /// [`replace_all`](Self::replace_all), and created values that were never attached.
+    /// Handles to removed values are invalidated.
+    /// Storages that cannot remove single values are left as they are.
+    ///
+    /// If the arena was loaded with [`LoadOptions::preserve_source`](crate::LoadOptions),
+    /// the symbol and object tables of the stream are cut off before the first removed value,
+    /// as a storage may reuse its handle.
+    /// Values whose spans reach past the cut are re-encoded instead of copied when dumping.
+    ///
+    /// # Returns
+    /// Returns the number of values that were removed.
+    pub fn gc(&mut self) -> usize {
+        let reachable: HashSet<ValueHandle> = self.post_order(self.root).into_iter().collect();
+        let unreachable: Vec<ValueHandle> = self
+            .iter()
+            .map(|(handle, _)| handle)
+            .filter(|handle| !reachable.contains(handle))
+            .collect();
+
+        let mut removed = HashSet::new();
+        for handle in unreachable {
+            if self.storage.remove(handle).is_some() {
+                removed.insert(handle);
+            }
+        }
+
+        self.dirty.retain(|handle| !removed.contains(handle));
+        if let Some(creation_order) = self.creation_order.as_mut() {
+            creation_order.retain(|handle| !removed.contains(handle));
+        }
+        if let Some(handle) = self.utf8_encoding_symbol {
+            if removed.contains(&handle.into_raw()) {
+                self.utf8_encoding_symbol = None;
+            }
+        }
+        if let Some(source) = self.source.as_mut() {
+            let symbol_links_len = source
+                .symbol_links
+                .iter()
+                .position(|handle| removed.contains(&handle.into_raw()))
+                .unwrap_or(source.symbol_links.len());
+            let object_links_len = source
+                .object_links
+                .iter()
+                .position(|handle| removed.contains(handle))
+                .unwrap_or(source.object_links.len());
+            source.symbol_links.truncate(symbol_links_len);
+            source.object_links.truncate(object_links_len);
+            source.spans.retain(|handle, span| {
+                !removed.contains(handle)
+                    && span.symbol_links.end <= symbol_links_len
+                    && span.object_links.end <= object_links_len
+            });
+        }
+
+        removed.len()
+    }
+
+    /// Redirect every reference to `old` to point at `new`, then remove unreachable values.
+    ///
+    /// This is [`replace_all`](Self::replace_all) followed by [`gc`](Self::gc),
+    /// so replacing a large subtree does not leave its values behind.
+    ///
+    /// # Returns
+    /// Returns the number of values that were removed.
+    ///
+    /// # Panics
+    /// Panics if `old` is a symbol and `new` is not.
+    pub fn replace_and_gc(&mut self, old: ValueHandle, new: ValueHandle) -> usize {
+        self.replace_all(old, new);
+        self.gc()
+    }
+
+    /// Collapse structurally-identical values reachable from the root into a single shared handle.
+    ///
+    /// References to duplicates are rewritten to point at the first equal value found.
+    /// Nil, bool, and Fixnum values are left alone, as they are never object links.
+    /// Ruby will see collapsed values as the same object,
+    /// so mutating one on the Ruby side will affect the others.
+    ///
+    /// # Returns
+    /// Returns the number of values that were collapsed.
+    pub fn deduplicate(&mut self) -> usize {
+        let order = self.post_order(self.root);
+
+        let mut replacements: HashMap<ValueHandle, ValueHandle> = HashMap::new();
+        let mut canonical: HashMap<u64, Vec<ValueHandle>> = HashMap::new();
+        for handle in order.iter().copied() {
+            // Children are visited first, so all but back-references have been resolved.
+            if let Some(value) = self.get_mut(handle) {
+                value.map_children(|child| replacements.get(&child).copied().unwrap_or(child));
+            }
+
+            match self[handle] {
+                Value::Nil(_) | Value::Bool(_) | Value::Fixnum(_) => continue,
+                _ => {}
+            }
+
+            let bucket = canonical.entry(self.content_hash(handle)).or_default();
+            match bucket
+                .iter()
+                .copied()
+                .find(|other| self.values_equal(*other, handle))
+            {
+                Some(other) => {
+                    replacements.insert(handle, other);
+                }
+                None => bucket.push(handle),
+            }
+        }
+
+        for handle in order.iter().copied() {
+            if let Some(value) = self.get_mut(handle) {
+                value.map_children(|child| replacements.get(&child).copied().unwrap_or(child));
+            }
+        }
+        if let Some(root) = replacements.get(&self.root) {
+            self.root = *root;
+        }
+
+        replacements.len()
+    }
 }
 
-impl Default for ValueArena {
+impl ValueArena {
+    /// Validate the value denoted by the given handle against a [`Schema`].
+    ///
+    /// This checks value kinds, class names, and instance variables recursively.
+    pub fn validate_against(
+        &self,
+        handle: ValueHandle,
+        schema: &Schema,
+    ) -> Result<(), SchemaError> {
+        schema.validate(self, handle)
+    }
+}
+
+impl<S> Default for GenericValueArena<S>
+where
+    S: Storage,
+{
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl std::ops::Index<ValueHandle> for ValueArena {
+impl<S> core::ops::Index<ValueHandle> for GenericValueArena<S>
+where
+    S: Storage,
+{
     type Output = Value;
 
     fn index(&self, index: ValueHandle) -> &Self::Output {
         self.get(index).expect("missing value")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dump;
+    use crate::load;
+    use crate::load_with_options;
+    use crate::FromValueContext;
+    use crate::FromValueError;
+    use crate::LoadOptions;
+
+    #[test]
+    fn clear() {
+        let mut arena = ValueArena::new();
+        let old_root = arena.root();
+
+        let values: Vec<_> = (0..100).map(|i| arena.create_fixnum(i).into()).collect();
+        let array = arena.create_array(values);
+        arena.replace_root(array);
+        assert!(arena.len() == 102);
+
+        let capacity = arena.capacity();
+        arena.clear();
+
+        assert!(arena.len() == 1);
+        assert!(!arena.is_empty());
+        assert!(arena.capacity() == capacity);
+        assert!(matches!(arena[arena.root()], Value::Nil(_)));
+        assert!(arena.get(array).is_none());
+        assert!(arena.get(old_root).is_none());
+    }
+
+    #[test]
+    fn keyed_entries() {
+        // Marshal.dump({ a: 1, "b" => 2, 3 => 4, c: 5 })
+        let data = b"\x04\x08{\x09:\x06ai\x06I\"\x06b\x06:\x06ETi\x07i\x08i\x09:\x06ci\x0a";
+        let arena = load(&data[..]).expect("failed to load");
+        let hash = match &arena[arena.root()] {
+            Value::Hash(hash) => hash,
+            _ => panic!("not a hash"),
+        };
+
+        let symbol_keys: Vec<_> = hash
+            .symbol_keyed_entries(&arena)
+            .map(|(key, value)| (key, arena[value].kind()))
+            .collect();
+        assert!(symbol_keys == [(&b"a"[..], ValueKind::Fixnum), (b"c", ValueKind::Fixnum)]);
+
+        let string_keys: Vec<_> = hash
+            .string_keyed_entries(&arena)
+            .map(|(key, _value)| key)
+            .collect();
+        assert!(string_keys == [b"b"]);
+    }
+
+    #[test]
+    fn hash_default_as() {
+        // Marshal.dump(Hash.new(0).merge(a: 1))
+        let data = b"\x04\x08}\x06:\x06ai\x06i\x00";
+        let arena = load(&data[..]).expect("failed to load");
+        let hash = match &arena[arena.root()] {
+            Value::Hash(hash) => hash,
+            _ => panic!("not a hash"),
+        };
+
+        let ctx = FromValueContext::new(&arena);
+        let default: i64 = hash
+            .default_as(&ctx)
+            .expect("missing default")
+            .expect("failed to decode default");
+        assert!(default == 0);
+
+        let error = hash
+            .default_as::<bool>(&ctx)
+            .expect("missing default")
+            .expect_err("decoded a Fixnum as a bool");
+        assert!(matches!(error, FromValueError::UnexpectedValueKind { .. }));
+
+        let arena = load(&b"\x04\x08{\x00"[..]).expect("failed to load");
+        let hash = match &arena[arena.root()] {
+            Value::Hash(hash) => hash,
+            _ => panic!("not a hash"),
+        };
+        let ctx = FromValueContext::new(&arena);
+        assert!(hash.default_as::<i64>(&ctx).is_none());
+    }
+
+    #[test]
+    fn decode_nested() {
+        fn decode(data: &[u8]) -> Result<ValueArena, Error> {
+            let arena = load(data).expect("failed to load");
+            let user_defined = match &arena[arena.root()] {
+                Value::UserDefined(value) => value,
+                value => panic!("expected a user defined value, got {:?}", value.kind()),
+            };
+            user_defined.decode_nested(&LoadOptions::default())
+        }
+
+        // An Entry whose _dump is Marshal.dump([1])
+        let nested =
+            decode(b"\x04\x08u:\x0aEntry\x0b\x04\x08[\x06i\x06").expect("failed to decode");
+        let array = nested[nested.root()].as_array().expect("not an array");
+        assert!(nested.get_ref(array.value()[0]) == 1);
+
+        // An Entry whose _dump is raw binary
+        let error = decode(b"\x04\x08u:\x0aEntry\x08\x89PN").expect_err("decoded raw binary");
+        assert!(matches!(error, Error::NotNestedMarshal));
+
+        let error = decode(b"\x04\x08u:\x0aEntry\x0b\x04\x08i\x06i\x06")
+            .expect_err("decoded trailing bytes");
+        assert!(matches!(error, Error::TrailingBytes { len: 2 }));
+    }
+
+    #[test]
+    fn encode_value() {
+        // Marshal.dump([Point.new(1, [2, :a]), :a])
+        let data = b"\x04\x08[\x07o:\x0aPoint\x07:\x07@xi\x06:\x07@y[\x07i\x07:\x06a;\x08";
+        let arena = load(&data[..]).expect("failed to load");
+        let root = arena[arena.root()].as_array().expect("not an array");
+        let point = root.value()[0];
+
+        let encoded = arena.encode_value(point).expect("failed to encode");
+        assert!(encoded == b"\x04\x08o:\x0aPoint\x07:\x07@xi\x06:\x07@y[\x07i\x07:\x06a");
+
+        let point_arena = load(&encoded[..]).expect("failed to load");
+        let object = point_arena[point_arena.root()]
+            .as_object()
+            .expect("not an object");
+        assert!(object.instance_variables().len() == 2);
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &point_arena).expect("failed to dump");
+        assert!(encoded == new_data);
+
+        let encoded = arena.encode_value(arena.root()).expect("failed to encode");
+        assert!(encoded[..] == data[..]);
+    }
+
+    #[test]
+    fn as_text_bytes() {
+        // Marshal.dump(["name", :name, 1])
+        let data = b"\x04\x08[\x08I\"\x09name\x06:\x06ET:\x09namei\x06";
+        let arena = load(&data[..]).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+
+        let text: Vec<Option<&[u8]>> = array
+            .value()
+            .iter()
+            .map(|handle| arena.as_text_bytes(*handle))
+            .collect();
+        assert!(text == [Some(&b"name"[..]), Some(&b"name"[..]), None]);
+    }
+
+    #[test]
+    fn to_events() {
+        // s = "hi"
+        // Marshal.dump([:a, :a, s, s, {1 => nil}, Point.new(1)])
+        let data = b"\x04\x08[\x0b:\x06a;\x00I\"\x07hi\x06:\x06ET@\x06{\x06i\x060\
+            o:\x0aPoint\x06:\x07@xi\x06";
+        let arena = load(&data[..]).expect("failed to load");
+
+        let events = arena.to_events(arena.root());
+        assert!(
+            events
+                == [
+                    Event::Array { len: 6 },
+                    Event::Symbol(b"a".to_vec()),
+                    Event::SymbolLink(0),
+                    Event::InstanceVariables { len: 1 },
+                    Event::String(b"hi".to_vec()),
+                    Event::Symbol(b"E".to_vec()),
+                    Event::Bool(true),
+                    Event::ObjectLink(1),
+                    Event::Hash {
+                        len: 1,
+                        has_default_value: false
+                    },
+                    Event::Fixnum(1),
+                    Event::Nil,
+                    Event::Object { len: 1 },
+                    Event::Symbol(b"Point".to_vec()),
+                    Event::Symbol(b"@x".to_vec()),
+                    Event::Fixnum(1),
+                ]
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn from_json() {
+        let mut object = serde_json::Map::new();
+        object.insert("a".into(), serde_json::Value::from(1));
+        object.insert(
+            "b".into(),
+            serde_json::Value::Array(vec![
+                serde_json::Value::Bool(true),
+                serde_json::Value::Null,
+                serde_json::Value::from(1.5),
+                serde_json::Value::from(1073741823),
+                serde_json::Value::from(1073741824),
+                serde_json::Value::from(4294967296_u64),
+            ]),
+        );
+        let arena = ValueArena::from_json(&serde_json::Value::Object(object));
+
+        // json = '{"a": 1, "b": [true, null, 1.5, 1073741823, 1073741824, 4294967296]}'
+        // Marshal.dump(JSON.parse(json))
+        let expected = b"\x04\x08{\x07I\"\x06a\x06:\x06ETi\x06I\"\x06b\x06;\x00T\
+            [\x0bT0f\x081.5i\x04\xff\xff\xff\x3fl+\x07\x00\x00\x00\x40\
+            l+\x08\x00\x00\x00\x00\x01\x00";
+        let mut data = Vec::new();
+        dump(&mut data, &arena).expect("failed to dump");
+        assert!(data == expected, "{data:?} != {expected:?}");
+    }
+
+    #[test]
+    fn create_utf8_string() {
+        let mut arena = ValueArena::new();
+        let a = arena.create_utf8_string("a").into_raw();
+        let b = arena.create_utf8_string("b").into_raw();
+        let array = arena.create_array(vec![a, b]);
+        arena.replace_root(array);
+        assert!(arena.symbol_count() == 1);
+
+        // Marshal.dump(["a", "b"])
+        let mut data = Vec::new();
+        dump(&mut data, &arena).expect("failed to dump");
+        assert!(data == b"\x04\x08[\x07I\"\x06a\x06:\x06ETI\"\x06b\x06;\x00T");
+    }
+
+    #[test]
+    fn symbol_count() {
+        // Marshal.dump([Point.new(1, 2), Point.new(3, :x)]), where Point has @x and @y.
+        let data =
+            b"\x04\x08[\x07o:\x0aPoint\x07:\x07@xi\x06:\x07@yi\x07o;\x00\x07;\x06i\x08;\x07:\x06x";
+        let arena = load(&data[..]).expect("failed to load");
+        assert!(arena.symbol_count() == 4);
+
+        assert!(ValueArena::new().symbol_count() == 0);
+    }
+
+    #[test]
+    fn tree_string() {
+        // Marshal.dump([[1, "a"], { b: [2] }, Point.new(:b, nil)]), where Point has @x and @y.
+        let data = b"\x04\x08[\x08[\x07i\x06\"\x06a{\x06:\x06b[\x06i\x07\
+                     o:\x0aPoint\x07:\x07@x;\x00:\x07@y0";
+        let arena = load(&data[..]).expect("failed to load");
+        let expected = "\
+#0 Array
+├─ #1 Array
+│  ├─ #2 Fixnum 1
+│  └─ #3 String \"a\"
+├─ #4 Hash
+│  ├─ Symbol :b
+│  └─ #5 Array
+│     └─ #6 Fixnum 2
+└─ #7 Object
+   ├─ Symbol :Point
+   ├─ Symbol :@x
+   ├─ Symbol :b
+   ├─ Symbol :@y
+   └─ #8 Nil
+";
+        let tree = arena.tree_string(arena.root());
+        assert!(tree == expected, "{tree}");
+
+        // a = [[1]]; a[0] << a; a << a[0]; Marshal.dump(a)
+        let data = b"\x04\x08[\x07[\x07i\x06@\x00@\x06";
+        let arena = load(&data[..]).expect("failed to load");
+        let expected = "\
+#0 Array
+├─ #1 Array
+│  ├─ #2 Fixnum 1
+│  └─ #0 Array (cycle)
+└─ #1 Array (shared)
+";
+        let tree = arena.tree_string(arena.root());
+        assert!(tree == expected, "{tree}");
+    }
+
+    #[test]
+    fn is_immediate() {
+        // Marshal.dump([nil, true, false, 1, :a, 2**64, 1.5, "b", [], {}, Object.new, String])
+        let data = b"\x04\x08[\x110TFi\x06:\x06al+\x0a\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\
+            f\x081.5I\"\x06b\x06:\x06ET[\x00{\x00o:\x0bObject\x00c\x0bString";
+        let arena = load(&data[..]).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+        let immediate: Vec<bool> = array
+            .value()
+            .iter()
+            .map(|handle| arena.is_immediate(*handle))
+            .collect();
+        assert!(
+            immediate
+                == [true, true, true, true, true, false, false, false, false, false, false, false]
+        );
+        assert!(!arena.is_immediate(arena.root()));
+    }
+
+    #[test]
+    fn path_between() {
+        // Marshal.dump([1, { "a" => Foo.new }]), where Foo has @bar = [nil, "x"].
+        let data = b"\x04\x08[\x07i\x06{\x06I\"\x06a\x06:\x06ET\
+            o:\x08Foo\x06:\x09@bar[\x070I\"\x06x\x06;\x00T";
+        let arena = load(&data[..]).expect("failed to load");
+        let root = arena[arena.root()].as_array().expect("not an array");
+        let hash = arena[root.value()[1]].as_hash().expect("not a hash");
+        let object = arena[hash.value()[0].1].as_object().expect("not an object");
+        let bar = arena[object.instance_variables()[0].1]
+            .as_array()
+            .expect("not an array");
+        let x = bar.value()[1];
+
+        let path = arena.path_between(arena.root(), x).expect("missing path");
+        assert!(
+            path == [
+                PathSegment::Index(1),
+                PathSegment::Key(b"a".to_vec()),
+                PathSegment::Key(b"@bar".to_vec()),
+                PathSegment::Index(1),
+            ]
+        );
+        assert!(arena.path_between(arena.root(), arena.root()) == Some(Vec::new()));
+        assert!(arena.path_between(x, arena.root()).is_none());
+    }
+
+    #[test]
+    fn shared_nodes() {
+        // s = "s"; Marshal.dump([s, s, "t", :u, :u, 1, 1])
+        let data = b"\x04\x08[\x0cI\"\x06s\x06:\x06ET@\x06I\"\x06t\x06;\x00T:\x06u;\x06i\x06i\x06";
+        let arena = load(&data[..]).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+        assert!(arena.shared_nodes() == HashSet::from([array.value()[0]]));
+
+        // a = []; a << a; Marshal.dump(a)
+        let data = b"\x04\x08[\x06@\x00";
+        let arena = load(&data[..]).expect("failed to load");
+        assert!(arena.shared_nodes() == HashSet::from([arena.root()]));
+    }
+
+    #[test]
+    fn values_equal_unordered_ivars() {
+        // Marshal.dump([Foo.new, Foo.new]), where the first sets @a = 1 then @b = [2],
+        // and the second sets @b = [2] then @a = 1.
+        let data = b"\x04\x08[\x07o:\x08Foo\x07:\x07@ai\x06:\x07@b[\x06i\x07\
+            o;\x00\x07;\x07[\x06i\x07;\x06i\x06";
+        let arena = load(&data[..]).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+        let (a, b) = (array.value()[0], array.value()[1]);
+
+        assert!(!arena.values_equal(a, b));
+        assert!(arena.values_equal_unordered_ivars(a, b));
+
+        // @a = 2 instead
+        let data = b"\x04\x08[\x07o:\x08Foo\x07:\x07@ai\x06:\x07@b[\x06i\x07\
+            o;\x00\x07;\x07[\x06i\x07;\x06i\x07";
+        let arena = load(&data[..]).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+        assert!(!arena.values_equal_unordered_ivars(array.value()[0], array.value()[1]));
+    }
+
+    #[test]
+    fn values_equal_symbol_instance_variables() {
+        // Marshal.dump([:"é", "é".b.to_sym])
+        let data = b"\x04\x08[\x07I:\x07\xc3\xa9\x06:\x06ET:\x07\xc3\xa9";
+        let arena = load(&data[..]).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+        let (a, b) = (array.value()[0], array.value()[1]);
+
+        assert!(!arena.values_equal(a, b));
+        assert!(!arena.values_equal(b, a));
+        assert!(!arena.values_equal_unordered_ivars(a, b));
+        assert!(arena.content_hash(a) != arena.content_hash(b));
+    }
+
+    #[test]
+    fn retag_class() {
+        // Marshal.dump({ Foo: Foo.new, bar: Foo.new })
+        let data = b"\x04\x08{\x07:\x08Fooo;\x00\x00:\x08baro;\x00\x00";
+        let mut arena = load(&data[..]).expect("failed to load");
+
+        assert!(arena.retag_class(b"Foo", b"Qux") == 2);
+        assert!(arena.retag_class(b"Missing", b"Qux") == 0);
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(new_data == b"\x04\x08{\x07:\x08Fooo:\x08Qux\x00:\x08baro;\x06\x00");
+    }
+
+    #[test]
+    fn creation_order() {
+        // Marshal.dump([1, "a", [2]])
+        let data = b"\x04\x08[\x08i\x06\"\x06a[\x06i\x07";
+        let options = LoadOptions {
+            record_creation_order: true,
+            ..LoadOptions::default()
+        };
+        let mut arena = load_with_options(&data[..], &options).expect("failed to load");
+
+        let root = arena.root();
+        let array = arena[root].as_array().expect("not an array").value();
+        let inner = arena[array[2]].as_array().expect("not an array").value();
+        let expected = [root, array[0], array[1], array[2], inner[0]];
+        assert!(arena.creation_order() == expected);
+
+        let nil = arena.create_nil().into_raw();
+        assert!(arena.creation_order().last() == Some(&nil));
+
+        let arena = load(&data[..]).expect("failed to load");
+        assert!(arena.creation_order().is_empty());
+    }
+
+    #[test]
+    fn max_depth() {
+        // Marshal.dump([[1, "a"], { b: [2] }])
+        let data = b"\x04\x08[\x07[\x07i\x06\"\x06a{\x06:\x06b[\x06i\x07";
+        let arena = load(&data[..]).expect("failed to load");
+        assert!(arena.max_depth(arena.root()) == 4);
+
+        let arena = load(&b"\x04\x08i\x06"[..]).expect("failed to load");
+        assert!(arena.max_depth(arena.root()) == 1);
+
+        // a = [[1]]; a[0] << a; Marshal.dump(a)
+        let data = b"\x04\x08[\x06[\x07i\x06@\x00";
+        let arena = load(&data[..]).expect("failed to load");
+        assert!(arena.max_depth(arena.root()) == 3);
+    }
+
+    #[test]
+    fn is_cyclic() {
+        // Marshal.dump([[1, "a"], { b: [2] }])
+        let data = b"\x04\x08[\x07[\x07i\x06\"\x06a{\x06:\x06b[\x06i\x07";
+        let arena = load(&data[..]).expect("failed to load");
+        assert!(!arena.is_cyclic(arena.root()));
+
+        // a = []; a << a; Marshal.dump(a)
+        let data = b"\x04\x08[\x06@\x00";
+        let arena = load(&data[..]).expect("failed to load");
+        assert!(arena.is_cyclic(arena.root()));
+    }
+
+    #[test]
+    fn deduplicate() {
+        let mut arena = ValueArena::new();
+
+        let mut hashes = Vec::new();
+        for _ in 0..2 {
+            let key = arena.create_string("key".into()).into();
+            let one = arena.create_fixnum(1).into();
+            let two = arena.create_fixnum(2).into();
+            let value = arena.create_array(vec![one, two]).into();
+            hashes.push(arena.create_hash(vec![(key, value)], None).into_raw());
+        }
+        let root = arena.create_array(hashes.clone());
+        arena.replace_root(root);
+
+        assert!(arena.values_equal(hashes[0], hashes[1]));
+        assert!(arena.content_hash(hashes[0]) == arena.content_hash(hashes[1]));
+
+        let mut old_data = Vec::new();
+        dump(&mut old_data, &arena).expect("failed to dump");
+
+        // The key strings, the value arrays, and the hashes.
+        assert!(arena.deduplicate() == 3);
+
+        let root = arena[arena.root()].as_array().expect("not an array");
+        assert!(root.value()[0] == root.value()[1]);
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(new_data.len() < old_data.len());
+        assert!(new_data == b"\x04\x08[\x07{\x06\"\x08key[\x07i\x06i\x07@\x06");
+    }
+
+    #[test]
+    fn replace_all() {
+        // ["secret", { "secret" => "secret" }, "other"], with one shared "secret" string.
+        let mut arena = ValueArena::new();
+        let secret = arena.create_string("secret".into()).into_raw();
+        let other = arena.create_string("other".into()).into_raw();
+        let hash = arena.create_hash(vec![(secret, secret)], None).into_raw();
+        let array = arena.create_array(vec![secret, hash, other]);
+        arena.replace_root(array);
+
+        let redacted = arena.create_string("xxx".into()).into_raw();
+        assert!(arena.replace_all(secret, redacted) == 3);
+        assert!(arena.replace_all(secret, redacted) == 0);
+
+        let mut data = Vec::new();
+        dump(&mut data, &arena).expect("failed to dump");
+        assert!(data == b"\x04\x08[\x08\"\x08xxx{\x06@\x06@\x06\"\x0aother");
+    }
+
+    #[test]
+    fn replace_and_gc() {
+        let mut arena = ValueArena::new();
+        let elements = (0..100)
+            .map(|i| arena.create_fixnum(i).into_raw())
+            .collect();
+        let inner = arena.create_array(elements).into_raw();
+        let outer = arena.create_array(vec![inner]);
+        arena.replace_root(outer);
+
+        // The original nil root.
+        assert!(arena.gc() == 1);
+        assert!(arena.len() == 102);
+
+        let nil = arena.create_nil().into_raw();
+        assert!(arena.replace_and_gc(inner, nil) == 101);
+        assert!(arena.len() == 2);
+        assert!(arena.get(inner).is_none());
+
+        let mut data = Vec::new();
+        dump(&mut data, &arena).expect("failed to dump");
+        assert!(data == b"\x04\x08[\x060");
+    }
+
+    #[test]
+    fn hash_builder() {
+        let mut arena = ValueArena::new();
+        let a = arena.create_symbol("a".into());
+        let one = arena.create_fixnum(1);
+        let b = arena.create_string("b".into());
+        let nil = arena.create_nil();
+        let default = arena.create_fixnum(0);
+        let hash = arena
+            .hash()
+            .entry(a, one)
+            .entry(b, nil)
+            .default(default)
+            .build();
+        arena.replace_root(hash);
+
+        let mut data = Vec::new();
+        dump(&mut data, &arena).expect("failed to dump");
+        // Hash.new(0).merge!(a: 1, "b" => nil)
+        assert!(data == b"\x04\x08}\x07:\x06ai\x06\"\x06b0i\x00");
+    }
+
+    #[test]
+    fn would_roundtrip() {
+        // Marshal.dump([:a, :a, "x"])
+        let data = b"\x04\x08[\x08:\x06a;\x00I\"\x06x\x06:\x06ET";
+        let mut arena = load(&data[..]).expect("failed to load");
+        assert!(arena.would_roundtrip(data).expect("failed to dump"));
+        assert!(!arena.would_roundtrip(&data[..6]).expect("failed to dump"));
+
+        let root = arena[arena.root()].as_array().expect("not an array");
+        let string = root.value()[2];
+        let new_string = arena.create_string("x".into()).into();
+        arena.replace_all(string, new_string);
+        assert!(!arena.would_roundtrip(data).expect("failed to dump"));
+    }
+
+    #[test]
+    fn gc_link_tables() {
+        // s = "b"; [["a"], s, s]
+        let data = b"\x04\x08[\x08[\x06I\"\x06a\x06:\x06ETI\"\x06b\x06;\x00T@\x08";
+        let options = LoadOptions {
+            preserve_source: true,
+            ..LoadOptions::default()
+        };
+        let mut arena = load_with_options(&data[..], &options).expect("failed to load");
+        let root = arena[arena.root()].as_array().expect("not an array");
+        let inner = root.value()[0];
+
+        let nil = arena.create_nil().into_raw();
+        // The original nil root, the inner array, "a", and its encoding flag.
+        assert!(arena.replace_and_gc(inner, nil) == 4);
+
+        // The object table is cut off before the inner array.
+        let source = arena.source.as_ref().expect("missing source");
+        assert!(source.object_links.len() == 1);
+        assert!(source.symbol_links.len() == 1);
+        assert!(source.spans.values().all(|span| span.object_links.end <= 1));
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(new_data == b"\x04\x08[\x080I\"\x06b\x06:\x06ET@\x06");
+    }
+
+    #[test]
+    fn create_exception() {
+        let mut arena = ValueArena::new();
+        let exception = arena.create_exception("RuntimeError", "boom", &["a.rb:1"]);
+        arena.replace_root(exception);
+
+        let mut data = Vec::new();
+        dump(&mut data, &arena).expect("failed to dump");
+        // e = RuntimeError.new("boom"); e.set_backtrace(["a.rb:1"]); Marshal.dump(e)
+        assert!(
+            data == b"\x04\x08o:\x11RuntimeError\x07:\x09mesgI\"\x09boom\x06:\x06ET\
+                :\x07bt[\x06I\"\x0ba.rb:1\x06;\x07T"
+        );
+    }
+
+    #[test]
+    fn kind_histogram() {
+        // class MyArray < Array; end
+        // Marshal.dump([Point.new(1, 2), MyArray[:a, :b], "c", Integer])
+        let data = b"\x04\x08[\x09o:\x0aPoint\x07:\x07@xi\x06:\x07@yi\x07\
+            C:\x0cMyArray[\x07:\x06a:\x06bI\"\x06c\x06:\x06ETc\x0cInteger";
+        let arena = load(&data[..]).expect("failed to load");
+
+        let histogram: Vec<(ValueKind, usize)> =
+            arena.kind_histogram(arena.root()).into_iter().collect();
+        assert!(
+            histogram
+                == [
+                    (ValueKind::Bool, 1),
+                    (ValueKind::Fixnum, 2),
+                    (ValueKind::Symbol, 7),
+                    (ValueKind::Array, 2),
+                    (ValueKind::Object, 1),
+                    (ValueKind::String, 1),
+                    (ValueKind::Class, 1),
+                ]
+        );
+
+        let names = arena.referenced_class_names(arena.root());
+        assert!(names == [&b"Integer"[..], b"MyArray", b"Point"]);
+    }
+
+    #[test]
+    fn user_defined_by_class() {
+        // Marshal.dump([Foo.new("a"), Bar.new("b"), Foo.new("c")]), where _dump returns the string
+        let data = b"\x04\x08[\x08u:\x08Foo\x06au:\x08Bar\x06bu;\x00\x06c";
+        let arena = load(&data[..]).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+
+        let foos = arena.user_defined_by_class(b"Foo");
+        assert!(foos == [(array.value()[0], &b"a"[..]), (array.value()[2], &b"c"[..])]);
+
+        let bars = arena.user_defined_by_class(b"Bar");
+        assert!(bars == [(array.value()[1], &b"b"[..])]);
+
+        assert!(arena.user_defined_by_class(b"Baz").is_empty());
+    }
+
+    #[test]
+    fn try_map() {
+        // Marshal.dump(["a", "b", "c"].map { _1.b })
+        let data = b"\x04\x08[\x08\"\x06a\"\x06b\"\x06c";
+        let mut arena = load(&data[..]).expect("failed to load");
+
+        let result = arena.try_map(arena.root(), |arena, handle| {
+            let Some(Value::String(value)) = arena.get(handle) else {
+                return Ok(());
+            };
+            if value.value() == b"b" {
+                return Err("found b");
+            }
+
+            let nil = arena.create_nil().into_raw();
+            arena.replace_all(handle, nil);
+            Ok(())
+        });
+        assert!(result == Err("found b"));
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(new_data == b"\x04\x08[\x080\"\x06b\"\x06c", "{new_data:?}");
+    }
+
+    #[test]
+    fn bignum_words() {
+        // Marshal.dump(-(2**70))
+        let data = b"\x04\x08l-\x0a\x00\x00\x00\x00\x00\x00\x00\x00\x40\x00";
+        let arena = load(&data[..]).expect("failed to load");
+        let bignum: &BignumValue = FromValueContext::new(&arena)
+            .from_value(arena.root())
+            .expect("not a bignum");
+        assert!(!bignum.is_positive());
+
+        let words: Vec<u16> = bignum.magnitude_words().collect();
+        assert!(words == [0, 0, 0, 0, 0x40]);
+        assert!(bignum.to_i128() == Some(-(1 << 70)));
+
+        // Marshal.dump(2**70)
+        let data = b"\x04\x08l+\x0a\x00\x00\x00\x00\x00\x00\x00\x00\x40\x00";
+        let arena = load(&data[..]).expect("failed to load");
+        let bignum: &BignumValue = FromValueContext::new(&arena)
+            .from_value(arena.root())
+            .expect("not a bignum");
+        assert!(bignum.to_i128() == Some(1 << 70));
+
+        let mut new_data = Vec::new();
+        dump(&mut new_data, &arena).expect("failed to dump");
+        assert!(data == &new_data[..], "{data:?} != {new_data:?}");
+    }
+
+    #[test]
+    fn subtree_size() {
+        // s = "x"; Marshal.dump([[s, s], :a, [:a]])
+        let data = b"\x04\x08[\x08[\x07I\"\x06x\x06:\x06ET@\x07:\x06a[\x06;\x06";
+        let arena = load(&data[..]).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+
+        for handle in [arena.root(), array.value()[0], array.value()[2]] {
+            let size = arena.subtree_size(handle).expect("failed to get size");
+            let encoded = arena.encode_value(handle).expect("failed to encode");
+            assert!(size == encoded.len(), "{size} != {}", encoded.len());
+        }
+        let size = arena
+            .subtree_size(arena.root())
+            .expect("failed to get size");
+        assert!(size == data.len());
+    }
+}
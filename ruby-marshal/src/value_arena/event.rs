@@ -0,0 +1,92 @@
+use alloc::vec::Vec;
+
+/// One step of the flat form of a value, in the order it appears in a Marshal stream.
+///
+/// Events that start a value with children give the number of children,
+/// and the children follow as their own events, so no closing event is needed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Nil
+    Nil,
+
+    /// A Bool
+    Bool(bool),
+
+    /// A Fixnum
+    Fixnum(i32),
+
+    /// A Bignum
+    Bignum {
+        /// Whether the Bignum is positive
+        is_positive: bool,
+
+        /// The bytes of the magnitude, least significant first
+        magnitude: Vec<u8>,
+    },
+
+    /// A Float
+    Float(f64),
+
+    /// The first occurrence of a Symbol
+    Symbol(Vec<u8>),
+
+    /// A repeated Symbol, by its index in the symbol table
+    SymbolLink(usize),
+
+    /// A repeated value, by its index in the object table
+    ObjectLink(usize),
+
+    /// Instance variables for the next value.
+    ///
+    /// The value follows, and then `len` pairs of a Symbol name and a value.
+    InstanceVariables { len: usize },
+
+    /// A user class for the next value.
+    ///
+    /// The class name Symbol follows, and then the value.
+    UserClass,
+
+    /// A module that the next value was extended with.
+    ///
+    /// The module name Symbol follows, and then the value.
+    Extended,
+
+    /// An Array, followed by `len` elements
+    Array { len: usize },
+
+    /// A Hash, followed by `len` pairs of a key and a value, and then the default value, if any
+    Hash {
+        /// The number of entries
+        len: usize,
+
+        /// Whether a default value follows the entries
+        has_default_value: bool,
+    },
+
+    /// An Object, followed by the class name Symbol and `len` instance variable pairs
+    Object { len: usize },
+
+    /// A Struct, followed by the class name Symbol and `len` member pairs
+    Struct { len: usize },
+
+    /// A String
+    String(Vec<u8>),
+
+    /// A Regexp
+    Regexp {
+        /// The source of the pattern
+        source: Vec<u8>,
+
+        /// The raw options byte
+        options: u8,
+    },
+
+    /// A User Defined Value with the given data, followed by the class name Symbol
+    UserDefined(Vec<u8>),
+
+    /// A User Marshal Value, followed by the class name Symbol and the value
+    UserMarshal,
+
+    /// A Class
+    Class(Vec<u8>),
+}
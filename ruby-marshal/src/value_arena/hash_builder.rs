@@ -0,0 +1,56 @@
+use super::GenericValueArena;
+use super::HashValue;
+use super::SlotMapStorage;
+use super::Storage;
+use super::TypedValueHandle;
+use super::ValueHandle;
+use alloc::vec::Vec;
+
+/// A builder for a `Hash` value, made with [`GenericValueArena::hash`].
+///
+/// Keys and values can be any handle, and are kept in the order they were added.
+pub struct HashBuilder<'a, S = SlotMapStorage> {
+    arena: &'a mut GenericValueArena<S>,
+    entries: Vec<(ValueHandle, ValueHandle)>,
+    default_value: Option<ValueHandle>,
+}
+
+impl<'a, S> HashBuilder<'a, S>
+where
+    S: Storage,
+{
+    /// Make a new [`HashBuilder`].
+    pub(super) fn new(arena: &'a mut GenericValueArena<S>) -> Self {
+        Self {
+            arena,
+            entries: Vec::new(),
+            default_value: None,
+        }
+    }
+
+    /// Add an entry.
+    ///
+    /// Duplicate keys are not checked for.
+    pub fn entry<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<ValueHandle>,
+        V: Into<ValueHandle>,
+    {
+        self.entries.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set the default value, replacing any previous one.
+    pub fn default<H>(mut self, default_value: H) -> Self
+    where
+        H: Into<ValueHandle>,
+    {
+        self.default_value = Some(default_value.into());
+        self
+    }
+
+    /// Create the orphan `Hash` value and return the handle.
+    pub fn build(self) -> TypedValueHandle<HashValue> {
+        self.arena.create_hash(self.entries, self.default_value)
+    }
+}
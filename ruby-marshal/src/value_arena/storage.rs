@@ -0,0 +1,170 @@
+use super::Value;
+use super::ValueHandle;
+use alloc::boxed::Box;
+use slotmap::SlotMap;
+
+/// The backing storage of a [`GenericValueArena`](crate::GenericValueArena).
+///
+/// Values are added one at a time, and removed all at once with [`Storage::clear`],
+/// or one at a time with [`Storage::remove`] if the storage supports it.
+/// Storages that do not use [`SlotMapStorage`] can make handles with [`ValueHandle::from_index`].
+pub trait Storage: Default {
+    /// Add a value, returning its handle.
+    fn push(&mut self, value: Value) -> ValueHandle;
+
+    /// Get a reference to the value denoted by the given handle.
+    fn get(&self, handle: ValueHandle) -> Option<&Value>;
+
+    /// Get a mutable reference to the value denoted by the given handle.
+    fn get_mut(&mut self, handle: ValueHandle) -> Option<&mut Value>;
+
+    /// Get the number of values in the storage.
+    fn len(&self) -> usize;
+
+    /// Check whether the storage holds no values.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the number of values the storage can hold without reallocating.
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    /// Reserve space for at least `additional` more values.
+    ///
+    /// This is only a hint, and does nothing by default.
+    fn reserve(&mut self, _additional: usize) {}
+
+    /// Remove a single value, invalidating its handle.
+    ///
+    /// Storages that cannot free single slots keep the value and return None, which they do
+    /// by default.
+    fn remove(&mut self, _handle: ValueHandle) -> Option<Value> {
+        None
+    }
+
+    /// Remove all values, invalidating every handle issued so far.
+    fn clear(&mut self);
+
+    /// Iterate over every value in the storage.
+    fn iter(&self) -> Box<dyn Iterator<Item = (ValueHandle, &Value)> + '_>;
+}
+
+/// The default [`Storage`], backed by a slot map.
+#[derive(Debug, Default)]
+pub struct SlotMapStorage {
+    values: SlotMap<slotmap::DefaultKey, Value>,
+}
+
+impl Storage for SlotMapStorage {
+    fn push(&mut self, value: Value) -> ValueHandle {
+        ValueHandle::new(self.values.insert(value))
+    }
+
+    fn get(&self, handle: ValueHandle) -> Option<&Value> {
+        self.values.get(handle.index)
+    }
+
+    fn get_mut(&mut self, handle: ValueHandle) -> Option<&mut Value> {
+        self.values.get_mut(handle.index)
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.values.capacity()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.values.reserve(additional);
+    }
+
+    fn remove(&mut self, handle: ValueHandle) -> Option<Value> {
+        self.values.remove(handle.index)
+    }
+
+    fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (ValueHandle, &Value)> + '_> {
+        Box::new(
+            self.values
+                .iter()
+                .map(|(index, value)| (ValueHandle::new(index), value)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dump;
+    use crate::load_with_storage;
+    use crate::GenericValueArena;
+    use crate::LoadOptions;
+
+    /// A storage that never reuses slots.
+    #[derive(Debug, Default)]
+    struct VecStorage {
+        values: Vec<Value>,
+    }
+
+    impl Storage for VecStorage {
+        fn push(&mut self, value: Value) -> ValueHandle {
+            let index = u32::try_from(self.values.len()).expect("too many values");
+            self.values.push(value);
+
+            ValueHandle::from_index(index)
+        }
+
+        fn get(&self, handle: ValueHandle) -> Option<&Value> {
+            self.values.get(usize::try_from(handle.to_index()).ok()?)
+        }
+
+        fn get_mut(&mut self, handle: ValueHandle) -> Option<&mut Value> {
+            self.values
+                .get_mut(usize::try_from(handle.to_index()).ok()?)
+        }
+
+        fn len(&self) -> usize {
+            self.values.len()
+        }
+
+        fn clear(&mut self) {
+            self.values.clear();
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = (ValueHandle, &Value)> + '_> {
+            Box::new(self.values.iter().enumerate().map(|(index, value)| {
+                let index = u32::try_from(index).expect("too many values");
+                (ValueHandle::from_index(index), value)
+            }))
+        }
+    }
+
+    #[test]
+    fn vec_storage() {
+        // [:a, :a, "b", 1]
+        let data = b"\x04\x08[\x09:\x06a;\x00I\"\x06b\x06:\x06ET\x69\x06";
+        let arena: GenericValueArena<VecStorage> =
+            load_with_storage(&data[..], &LoadOptions::default()).expect("failed to load");
+
+        let array = arena[arena.root()].as_array().expect("not an array");
+        assert!(array.len() == 4);
+        assert!(array.value()[0] == array.value()[1]);
+        assert!(arena.symbol_count() == 2);
+
+        let mut dumped = Vec::new();
+        dump(&mut dumped, &arena).expect("failed to dump");
+        assert!(dumped == data);
+
+        let mut arena = arena;
+        arena.clear();
+        assert!(arena.len() == 1);
+        assert!(matches!(arena[arena.root()], Value::Nil(_)));
+    }
+}
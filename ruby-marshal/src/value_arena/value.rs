@@ -1,5 +1,16 @@
+use crate::load_with_options;
+use crate::Error;
+use crate::FromValue;
+use crate::FromValueContext;
+use crate::FromValueError;
+use crate::LoadOptions;
 use crate::TypedValueHandle;
+use crate::ValueArena;
 use crate::ValueHandle;
+use crate::MAJOR_VERSION;
+use crate::MINOR_VERSION;
+use alloc::string::String;
+use alloc::vec::Vec;
 
 /// A Ruby Value
 #[derive(Debug)]
@@ -13,6 +24,9 @@ pub enum Value {
     /// A Fixnum
     Fixnum(FixnumValue),
 
+    /// A Bignum
+    Bignum(BignumValue),
+
     /// A Float
     Float(FloatValue),
 
@@ -28,12 +42,21 @@ pub enum Value {
     /// An Object
     Object(ObjectValue),
 
+    /// A Struct
+    Struct(StructValue),
+
     /// A String
     String(StringValue),
 
+    /// A Regexp
+    Regexp(RegexpValue),
+
     /// A User Defined Value
     UserDefined(UserDefinedValue),
 
+    /// A User Marshal Value
+    UserMarshal(UserMarshalValue),
+
     /// A Class
     Class(ClassValue),
 }
@@ -47,6 +70,22 @@ impl Value {
         }
     }
 
+    /// Get a ref to the [`ArrayValue`], if it is an array.
+    pub fn as_array(&self) -> Option<&ArrayValue> {
+        match self {
+            Self::Array(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Get a ref to the [`HashValue`], if it is a hash.
+    pub fn as_hash(&self) -> Option<&HashValue> {
+        match self {
+            Self::Hash(value) => Some(value),
+            _ => None,
+        }
+    }
+
     /// Get a ref to the [`ObjectValue`], if it is an object.
     pub fn as_object(&self) -> Option<&ObjectValue> {
         match self {
@@ -55,6 +94,14 @@ impl Value {
         }
     }
 
+    /// Get a ref to the [`StructValue`], if it is a struct.
+    pub fn as_struct(&self) -> Option<&StructValue> {
+        match self {
+            Self::Struct(value) => Some(value),
+            _ => None,
+        }
+    }
+
     /// Get a ref to the [`StringValue`], if it is a string.
     pub fn as_string(&self) -> Option<&StringValue> {
         match self {
@@ -63,19 +110,259 @@ impl Value {
         }
     }
 
+    /// Get a ref to the [`RegexpValue`], if it is a regexp.
+    pub fn as_regexp(&self) -> Option<&RegexpValue> {
+        match self {
+            Self::Regexp(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Get the instance variables, if this kind of value can have them and it has any.
+    pub(crate) fn instance_variables(
+        &self,
+    ) -> Option<&[(TypedValueHandle<SymbolValue>, ValueHandle)]> {
+        match self {
+            Self::Symbol(value) => value.instance_variables(),
+            Self::Array(value) => value.instance_variables(),
+            Self::Hash(value) => value.instance_variables(),
+            Self::Object(value) => Some(value.instance_variables()),
+            Self::String(value) => value.instance_variables(),
+            Self::Regexp(value) => value.instance_variables(),
+            Self::UserDefined(value) => value.instance_variables(),
+            _ => None,
+        }
+    }
+
+    /// Get the names of the modules the value was extended with, outermost first.
+    ///
+    /// Values that cannot be extended have none.
+    pub(crate) fn extended_modules(&self) -> &[TypedValueHandle<SymbolValue>] {
+        match self {
+            Self::Array(value) => &value.extended_modules,
+            Self::Hash(value) => &value.extended_modules,
+            Self::Object(value) => &value.extended_modules,
+            Self::Struct(value) => &value.extended_modules,
+            Self::String(value) => &value.extended_modules,
+            Self::Regexp(value) => &value.extended_modules,
+            Self::UserDefined(value) => &value.extended_modules,
+            Self::UserMarshal(value) => &value.extended_modules,
+            _ => &[],
+        }
+    }
+
+    /// Get the names of the modules the value was extended with, if it can be extended.
+    pub(crate) fn extended_modules_mut(
+        &mut self,
+    ) -> Option<&mut Vec<TypedValueHandle<SymbolValue>>> {
+        match self {
+            Self::Array(value) => Some(&mut value.extended_modules),
+            Self::Hash(value) => Some(&mut value.extended_modules),
+            Self::Object(value) => Some(&mut value.extended_modules),
+            Self::Struct(value) => Some(&mut value.extended_modules),
+            Self::String(value) => Some(&mut value.extended_modules),
+            Self::Regexp(value) => Some(&mut value.extended_modules),
+            Self::UserDefined(value) => Some(&mut value.extended_modules),
+            Self::UserMarshal(value) => Some(&mut value.extended_modules),
+            _ => None,
+        }
+    }
+
+    /// Call `f` with the handle of every value this value directly references.
+    ///
+    /// This includes class name, module name and instance variable name symbols.
+    pub(crate) fn for_each_child<F>(&self, mut f: F)
+    where
+        F: FnMut(ValueHandle),
+    {
+        for module in self.extended_modules() {
+            f((*module).into());
+        }
+        match self {
+            Self::Nil(_)
+            | Self::Bool(_)
+            | Self::Fixnum(_)
+            | Self::Bignum(_)
+            | Self::Float(_)
+            | Self::Class(_) => {}
+            Self::Symbol(value) => {
+                for (name, value) in value.instance_variables.iter().flatten() {
+                    f((*name).into());
+                    f(*value);
+                }
+            }
+            Self::Array(value) => {
+                if let Some(user_class) = value.user_class {
+                    f(user_class.into());
+                }
+                for handle in value.value.iter() {
+                    f(*handle);
+                }
+                for (name, value) in value.instance_variables.iter().flatten() {
+                    f((*name).into());
+                    f(*value);
+                }
+            }
+            Self::Hash(value) => {
+                if let Some(user_class) = value.user_class {
+                    f(user_class.into());
+                }
+                if let Some(identity_class) = value.identity_class {
+                    f(identity_class.into());
+                }
+                for (key, value) in value.value.iter() {
+                    f(*key);
+                    f(*value);
+                }
+                if let Some(default_value) = value.default_value {
+                    f(default_value);
+                }
+                for (name, value) in value.instance_variables.iter().flatten() {
+                    f((*name).into());
+                    f(*value);
+                }
+            }
+            Self::Object(value) => {
+                f(value.name.into());
+                for (name, value) in value.instance_variables.iter() {
+                    f((*name).into());
+                    f(*value);
+                }
+            }
+            Self::Struct(value) => {
+                f(value.name.into());
+                for (name, value) in value.members.iter() {
+                    f((*name).into());
+                    f(*value);
+                }
+            }
+            Self::String(value) => {
+                if let Some(user_class) = value.user_class {
+                    f(user_class.into());
+                }
+                for (name, value) in value.instance_variables.iter().flatten() {
+                    f((*name).into());
+                    f(*value);
+                }
+            }
+            Self::Regexp(value) => {
+                if let Some(user_class) = value.user_class {
+                    f(user_class.into());
+                }
+                for (name, value) in value.instance_variables.iter().flatten() {
+                    f((*name).into());
+                    f(*value);
+                }
+            }
+            Self::UserDefined(value) => {
+                f(value.name.into());
+                for (name, value) in value.instance_variables.iter().flatten() {
+                    f((*name).into());
+                    f(*value);
+                }
+            }
+            Self::UserMarshal(value) => {
+                f(value.name.into());
+                f(value.value);
+            }
+        }
+    }
+
+    /// Replace the handle of every value this value directly references with the result of `f`.
+    ///
+    /// Symbol handles must be mapped to symbol handles.
+    pub(crate) fn map_children<F>(&mut self, mut f: F)
+    where
+        F: FnMut(ValueHandle) -> ValueHandle,
+    {
+        for module in self.extended_modules_mut().into_iter().flatten() {
+            *module = TypedValueHandle::new_unchecked(f((*module).into()));
+        }
+        match self {
+            Self::Nil(_)
+            | Self::Bool(_)
+            | Self::Fixnum(_)
+            | Self::Bignum(_)
+            | Self::Float(_)
+            | Self::Class(_) => {}
+            Self::Symbol(value) => {
+                if let Some(instance_variables) = value.instance_variables.as_mut() {
+                    map_instance_variables(instance_variables, &mut f);
+                }
+            }
+            Self::Array(value) => {
+                map_user_class(&mut value.user_class, &mut f);
+                for handle in value.value.iter_mut() {
+                    *handle = f(*handle);
+                }
+                if let Some(instance_variables) = value.instance_variables.as_mut() {
+                    map_instance_variables(instance_variables, &mut f);
+                }
+            }
+            Self::Hash(value) => {
+                map_user_class(&mut value.user_class, &mut f);
+                map_user_class(&mut value.identity_class, &mut f);
+                for (key, value) in value.value.iter_mut() {
+                    *key = f(*key);
+                    *value = f(*value);
+                }
+                if let Some(default_value) = value.default_value.as_mut() {
+                    *default_value = f(*default_value);
+                }
+                if let Some(instance_variables) = value.instance_variables.as_mut() {
+                    map_instance_variables(instance_variables, &mut f);
+                }
+            }
+            Self::Object(value) => {
+                value.name = TypedValueHandle::new_unchecked(f(value.name.into()));
+                map_instance_variables(&mut value.instance_variables, &mut f);
+            }
+            Self::Struct(value) => {
+                value.name = TypedValueHandle::new_unchecked(f(value.name.into()));
+                map_instance_variables(&mut value.members, &mut f);
+            }
+            Self::String(value) => {
+                map_user_class(&mut value.user_class, &mut f);
+                if let Some(instance_variables) = value.instance_variables.as_mut() {
+                    map_instance_variables(instance_variables, &mut f);
+                }
+            }
+            Self::Regexp(value) => {
+                map_user_class(&mut value.user_class, &mut f);
+                if let Some(instance_variables) = value.instance_variables.as_mut() {
+                    map_instance_variables(instance_variables, &mut f);
+                }
+            }
+            Self::UserDefined(value) => {
+                value.name = TypedValueHandle::new_unchecked(f(value.name.into()));
+                if let Some(instance_variables) = value.instance_variables.as_mut() {
+                    map_instance_variables(instance_variables, &mut f);
+                }
+            }
+            Self::UserMarshal(value) => {
+                value.name = TypedValueHandle::new_unchecked(f(value.name.into()));
+                value.value = f(value.value);
+            }
+        }
+    }
+
     /// Get the kind of value.
     pub fn kind(&self) -> ValueKind {
         match self {
             Self::Nil(_) => ValueKind::Nil,
             Self::Bool(_) => ValueKind::Bool,
             Self::Fixnum(_) => ValueKind::Fixnum,
+            Self::Bignum(_) => ValueKind::Bignum,
             Self::Float(_) => ValueKind::Float,
             Self::Symbol(_) => ValueKind::Symbol,
             Self::Array(_) => ValueKind::Array,
             Self::Hash(_) => ValueKind::Hash,
             Self::Object(_) => ValueKind::Object,
+            Self::Struct(_) => ValueKind::Struct,
             Self::String(_) => ValueKind::String,
+            Self::Regexp(_) => ValueKind::Regexp,
             Self::UserDefined(_) => ValueKind::UserDefined,
+            Self::UserMarshal(_) => ValueKind::UserMarshal,
             Self::Class(_) => ValueKind::Class,
         }
     }
@@ -99,6 +386,12 @@ impl From<FixnumValue> for Value {
     }
 }
 
+impl From<BignumValue> for Value {
+    fn from(value: BignumValue) -> Self {
+        Self::Bignum(value)
+    }
+}
+
 impl From<FloatValue> for Value {
     fn from(value: FloatValue) -> Self {
         Self::Float(value)
@@ -129,24 +422,65 @@ impl From<ObjectValue> for Value {
     }
 }
 
+impl From<StructValue> for Value {
+    fn from(value: StructValue) -> Self {
+        Self::Struct(value)
+    }
+}
+
 impl From<StringValue> for Value {
     fn from(value: StringValue) -> Self {
         Self::String(value)
     }
 }
 
+impl From<RegexpValue> for Value {
+    fn from(value: RegexpValue) -> Self {
+        Self::Regexp(value)
+    }
+}
+
 impl From<UserDefinedValue> for Value {
     fn from(value: UserDefinedValue) -> Self {
         Self::UserDefined(value)
     }
 }
 
+impl From<UserMarshalValue> for Value {
+    fn from(value: UserMarshalValue) -> Self {
+        Self::UserMarshal(value)
+    }
+}
+
 impl From<ClassValue> for Value {
     fn from(value: ClassValue) -> Self {
         Self::Class(value)
     }
 }
 
+/// Replace a user class name handle, if present, with the result of `f`.
+fn map_user_class<F>(user_class: &mut Option<TypedValueHandle<SymbolValue>>, f: &mut F)
+where
+    F: FnMut(ValueHandle) -> ValueHandle,
+{
+    if let Some(user_class) = user_class.as_mut() {
+        *user_class = TypedValueHandle::new_unchecked(f((*user_class).into()));
+    }
+}
+
+/// Replace the name and value handles of instance variables with the result of `f`.
+fn map_instance_variables<F>(
+    instance_variables: &mut [(TypedValueHandle<SymbolValue>, ValueHandle)],
+    f: &mut F,
+) where
+    F: FnMut(ValueHandle) -> ValueHandle,
+{
+    for (name, value) in instance_variables.iter_mut() {
+        *name = TypedValueHandle::new_unchecked(f((*name).into()));
+        *value = f(*value);
+    }
+}
+
 /// A Nil value.
 #[derive(Debug)]
 pub struct NilValue;
@@ -187,6 +521,79 @@ impl FixnumValue {
     }
 }
 
+/// A Bignum Value
+#[derive(Debug)]
+pub struct BignumValue {
+    positive: bool,
+    magnitude: Vec<u8>,
+}
+
+impl BignumValue {
+    /// Create a new [`BignumValue`].
+    pub(super) fn new(positive: bool, magnitude: Vec<u8>) -> Self {
+        Self {
+            positive,
+            magnitude,
+        }
+    }
+
+    /// Check whether the value is positive.
+    pub fn is_positive(&self) -> bool {
+        self.positive
+    }
+
+    /// Get the magnitude, as little-endian bytes.
+    ///
+    /// Ruby writes the magnitude in 16-bit words,
+    /// so this may end in a zero byte.
+    pub fn magnitude(&self) -> &[u8] {
+        &self.magnitude
+    }
+
+    /// Get the magnitude, as the little-endian 16-bit words Ruby writes.
+    ///
+    /// The value is the sum of each word shifted left by 16 times its index.
+    pub fn magnitude_words(&self) -> impl Iterator<Item = u16> + '_ {
+        self.magnitude
+            .chunks(2)
+            .map(|word| u16::from_le_bytes([word[0], *word.get(1).unwrap_or(&0)]))
+    }
+
+    /// Get the value as an [`i128`], if it fits.
+    pub fn to_i128(&self) -> Option<i128> {
+        let len = self
+            .magnitude
+            .iter()
+            .rposition(|byte| *byte != 0)
+            .map_or(0, |i| i + 1);
+        if len > core::mem::size_of::<u128>() {
+            return None;
+        }
+
+        let mut bytes = [0; core::mem::size_of::<u128>()];
+        bytes[..len].copy_from_slice(&self.magnitude[..len]);
+        let magnitude = u128::from_le_bytes(bytes);
+
+        if self.positive {
+            i128::try_from(magnitude).ok()
+        } else {
+            0_i128.checked_sub_unsigned(magnitude)
+        }
+    }
+
+    /// Get the value as a [`BigInt`](num_bigint::BigInt).
+    #[cfg(feature = "bigint")]
+    pub fn to_bigint(&self) -> num_bigint::BigInt {
+        let sign = if self.positive {
+            num_bigint::Sign::Plus
+        } else {
+            num_bigint::Sign::Minus
+        };
+
+        num_bigint::BigInt::from_bytes_le(sign, &self.magnitude)
+    }
+}
+
 /// A Float Value
 #[derive(Debug, Copy, Clone)]
 pub struct FloatValue {
@@ -209,30 +616,61 @@ impl FloatValue {
 #[derive(Debug)]
 pub struct SymbolValue {
     value: Vec<u8>,
+    instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
 }
 
 impl SymbolValue {
     /// Create a new [`SymbolValue`].
     pub(super) fn new(value: Vec<u8>) -> Self {
-        Self { value }
+        Self {
+            value,
+            instance_variables: None,
+        }
     }
 
     /// Get the inner value.
     pub fn value(&self) -> &[u8] {
         &self.value
     }
+
+    /// Get the instance variables.
+    ///
+    /// Ruby only gives symbols an instance variable for the encoding of non-ASCII names.
+    pub fn instance_variables(&self) -> Option<&[(TypedValueHandle<SymbolValue>, ValueHandle)]> {
+        self.instance_variables.as_deref()
+    }
+
+    /// Set the instance variables.
+    ///
+    /// # Returns
+    /// Returns the old instance variables
+    pub(crate) fn set_instance_variables(
+        &mut self,
+        mut instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
+    ) -> Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>> {
+        core::mem::swap(&mut self.instance_variables, &mut instance_variables);
+        instance_variables
+    }
 }
 
 /// An Array
 #[derive(Debug)]
 pub struct ArrayValue {
     value: Vec<ValueHandle>,
+    user_class: Option<TypedValueHandle<SymbolValue>>,
+    extended_modules: Vec<TypedValueHandle<SymbolValue>>,
+    instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
 }
 
 impl ArrayValue {
     /// Create a new [`Array`].
     pub(crate) fn new(value: Vec<ValueHandle>) -> Self {
-        Self { value }
+        Self {
+            value,
+            user_class: None,
+            extended_modules: Vec::new(),
+            instance_variables: None,
+        }
     }
 
     /// Get the inner value.
@@ -249,6 +687,45 @@ impl ArrayValue {
     pub fn is_empty(&self) -> bool {
         self.value.is_empty()
     }
+
+    /// Get the user class name, if this is an instance of a subclass.
+    pub fn user_class(&self) -> Option<TypedValueHandle<SymbolValue>> {
+        self.user_class
+    }
+
+    /// Set the user class name.
+    ///
+    /// # Returns
+    /// Returns the old user class name
+    pub(crate) fn set_user_class(
+        &mut self,
+        mut user_class: Option<TypedValueHandle<SymbolValue>>,
+    ) -> Option<TypedValueHandle<SymbolValue>> {
+        core::mem::swap(&mut self.user_class, &mut user_class);
+        user_class
+    }
+
+    /// Get the names of the modules this value was extended with, outermost first.
+    pub fn extended_modules(&self) -> &[TypedValueHandle<SymbolValue>] {
+        &self.extended_modules
+    }
+
+    /// Get the instance variables
+    pub fn instance_variables(&self) -> Option<&[(TypedValueHandle<SymbolValue>, ValueHandle)]> {
+        self.instance_variables.as_deref()
+    }
+
+    /// Set the instance variables.
+    ///
+    /// # Returns
+    /// Returns the old instance variables
+    pub(crate) fn set_instance_variables(
+        &mut self,
+        mut instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
+    ) -> Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>> {
+        core::mem::swap(&mut self.instance_variables, &mut instance_variables);
+        instance_variables
+    }
 }
 
 /// A Hash
@@ -256,6 +733,12 @@ impl ArrayValue {
 pub struct HashValue {
     value: Vec<(ValueHandle, ValueHandle)>,
     default_value: Option<ValueHandle>,
+    user_class: Option<TypedValueHandle<SymbolValue>>,
+
+    /// The `Hash` class name that Ruby writes as an inner user class for `compare_by_identity`.
+    identity_class: Option<TypedValueHandle<SymbolValue>>,
+    extended_modules: Vec<TypedValueHandle<SymbolValue>>,
+    instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
 }
 
 impl HashValue {
@@ -267,6 +750,10 @@ impl HashValue {
         Self {
             value,
             default_value,
+            user_class: None,
+            identity_class: None,
+            extended_modules: Vec::new(),
+            instance_variables: None,
         }
     }
 
@@ -279,6 +766,101 @@ impl HashValue {
     pub fn default_value(&self) -> Option<ValueHandle> {
         self.default_value
     }
+
+    /// Decode the default value into a Rust type, if there is a default value.
+    pub fn default_as<'a, T>(&self, ctx: &FromValueContext<'a>) -> Option<Result<T, FromValueError>>
+    where
+        T: FromValue<'a>,
+    {
+        self.default_value.map(|handle| ctx.from_value(handle))
+    }
+
+    /// Iterate over the entries with Symbol keys, resolving the key bytes.
+    ///
+    /// Entries with other kinds of keys are skipped.
+    pub fn symbol_keyed_entries<'a>(
+        &'a self,
+        arena: &'a ValueArena,
+    ) -> impl Iterator<Item = (&'a [u8], ValueHandle)> + 'a {
+        self.value.iter().filter_map(|(key, value)| {
+            let key = arena.get(*key)?.as_symbol()?;
+            Some((key.value(), *value))
+        })
+    }
+
+    /// Iterate over the entries with String keys, resolving the key bytes.
+    ///
+    /// Entries with other kinds of keys are skipped.
+    pub fn string_keyed_entries<'a>(
+        &'a self,
+        arena: &'a ValueArena,
+    ) -> impl Iterator<Item = (&'a [u8], ValueHandle)> + 'a {
+        self.value.iter().filter_map(|(key, value)| {
+            let key = arena.get(*key)?.as_string()?;
+            Some((key.value(), *value))
+        })
+    }
+
+    /// Get the user class name, if this is an instance of a subclass.
+    pub fn user_class(&self) -> Option<TypedValueHandle<SymbolValue>> {
+        self.user_class
+    }
+
+    /// Set the user class name.
+    ///
+    /// # Returns
+    /// Returns the old user class name
+    pub(crate) fn set_user_class(
+        &mut self,
+        mut user_class: Option<TypedValueHandle<SymbolValue>>,
+    ) -> Option<TypedValueHandle<SymbolValue>> {
+        core::mem::swap(&mut self.user_class, &mut user_class);
+        user_class
+    }
+
+    /// Get the names of the modules this value was extended with, outermost first.
+    pub fn extended_modules(&self) -> &[TypedValueHandle<SymbolValue>] {
+        &self.extended_modules
+    }
+
+    /// Check whether the hash compares keys by identity, as with Ruby's `compare_by_identity`.
+    pub fn compare_by_identity(&self) -> bool {
+        self.identity_class.is_some()
+    }
+
+    /// Get the `Hash` class name that marks the hash as comparing keys by identity.
+    pub(crate) fn identity_class(&self) -> Option<TypedValueHandle<SymbolValue>> {
+        self.identity_class
+    }
+
+    /// Set the `Hash` class name that marks the hash as comparing keys by identity.
+    ///
+    /// # Returns
+    /// Returns the old class name
+    pub(crate) fn set_identity_class(
+        &mut self,
+        mut identity_class: Option<TypedValueHandle<SymbolValue>>,
+    ) -> Option<TypedValueHandle<SymbolValue>> {
+        core::mem::swap(&mut self.identity_class, &mut identity_class);
+        identity_class
+    }
+
+    /// Get the instance variables
+    pub fn instance_variables(&self) -> Option<&[(TypedValueHandle<SymbolValue>, ValueHandle)]> {
+        self.instance_variables.as_deref()
+    }
+
+    /// Set the instance variables.
+    ///
+    /// # Returns
+    /// Returns the old instance variables
+    pub(crate) fn set_instance_variables(
+        &mut self,
+        mut instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
+    ) -> Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>> {
+        core::mem::swap(&mut self.instance_variables, &mut instance_variables);
+        instance_variables
+    }
 }
 
 /// An object
@@ -286,6 +868,7 @@ impl HashValue {
 pub struct ObjectValue {
     name: TypedValueHandle<SymbolValue>,
     instance_variables: Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>,
+    extended_modules: Vec<TypedValueHandle<SymbolValue>>,
 }
 
 impl ObjectValue {
@@ -297,6 +880,7 @@ impl ObjectValue {
         Self {
             name,
             instance_variables,
+            extended_modules: Vec::new(),
         }
     }
 
@@ -305,16 +889,89 @@ impl ObjectValue {
         self.name
     }
 
+    /// Set the name.
+    ///
+    /// # Returns
+    /// Returns the old name
+    pub(crate) fn set_name(
+        &mut self,
+        mut name: TypedValueHandle<SymbolValue>,
+    ) -> TypedValueHandle<SymbolValue> {
+        core::mem::swap(&mut self.name, &mut name);
+        name
+    }
+
     /// Get the instance variables
     pub fn instance_variables(&self) -> &[(TypedValueHandle<SymbolValue>, ValueHandle)] {
         &self.instance_variables
     }
+
+    /// Get the names of the modules this value was extended with, outermost first.
+    pub fn extended_modules(&self) -> &[TypedValueHandle<SymbolValue>] {
+        &self.extended_modules
+    }
+}
+
+/// A Struct
+#[derive(Debug)]
+pub struct StructValue {
+    name: TypedValueHandle<SymbolValue>,
+    members: Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>,
+    extended_modules: Vec<TypedValueHandle<SymbolValue>>,
+}
+
+impl StructValue {
+    /// Create a new [`StructValue`].
+    pub(crate) fn new(
+        name: TypedValueHandle<SymbolValue>,
+        members: Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>,
+    ) -> Self {
+        Self {
+            name,
+            members,
+            extended_modules: Vec::new(),
+        }
+    }
+
+    /// Get the name.
+    pub fn name(&self) -> TypedValueHandle<SymbolValue> {
+        self.name
+    }
+
+    /// Set the name.
+    ///
+    /// # Returns
+    /// Returns the old name
+    pub(crate) fn set_name(
+        &mut self,
+        mut name: TypedValueHandle<SymbolValue>,
+    ) -> TypedValueHandle<SymbolValue> {
+        core::mem::swap(&mut self.name, &mut name);
+        name
+    }
+
+    /// Get the members, in the order they were defined.
+    pub fn members(&self) -> &[(TypedValueHandle<SymbolValue>, ValueHandle)] {
+        &self.members
+    }
+
+    /// Get the value of the member at the given index.
+    pub fn member_at(&self, index: usize) -> Option<ValueHandle> {
+        self.members.get(index).map(|(_, value)| *value)
+    }
+
+    /// Get the names of the modules this value was extended with, outermost first.
+    pub fn extended_modules(&self) -> &[TypedValueHandle<SymbolValue>] {
+        &self.extended_modules
+    }
 }
 
 /// A String
 #[derive(Debug)]
 pub struct StringValue {
     value: Vec<u8>,
+    user_class: Option<TypedValueHandle<SymbolValue>>,
+    extended_modules: Vec<TypedValueHandle<SymbolValue>>,
     instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
 }
 
@@ -323,6 +980,8 @@ impl StringValue {
     pub(crate) fn new(value: Vec<u8>) -> Self {
         Self {
             value,
+            user_class: None,
+            extended_modules: Vec::new(),
             instance_variables: None,
         }
     }
@@ -332,6 +991,114 @@ impl StringValue {
         &self.value
     }
 
+    /// Set the inner value.
+    ///
+    /// # Returns
+    /// Returns the old inner value
+    pub(crate) fn set_value(&mut self, mut value: Vec<u8>) -> Vec<u8> {
+        core::mem::swap(&mut self.value, &mut value);
+        value
+    }
+
+    /// Get the user class name, if this is an instance of a subclass.
+    pub fn user_class(&self) -> Option<TypedValueHandle<SymbolValue>> {
+        self.user_class
+    }
+
+    /// Set the user class name.
+    ///
+    /// # Returns
+    /// Returns the old user class name
+    pub(crate) fn set_user_class(
+        &mut self,
+        mut user_class: Option<TypedValueHandle<SymbolValue>>,
+    ) -> Option<TypedValueHandle<SymbolValue>> {
+        core::mem::swap(&mut self.user_class, &mut user_class);
+        user_class
+    }
+
+    /// Get the names of the modules this value was extended with, outermost first.
+    pub fn extended_modules(&self) -> &[TypedValueHandle<SymbolValue>] {
+        &self.extended_modules
+    }
+
+    /// Get the instance variables
+    pub fn instance_variables(&self) -> Option<&[(TypedValueHandle<SymbolValue>, ValueHandle)]> {
+        self.instance_variables.as_deref()
+    }
+
+    /// Set the instance variables.
+    ///
+    /// # Returns
+    /// Returns the old instance variables
+    pub(crate) fn set_instance_variables(
+        &mut self,
+        mut instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
+    ) -> Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>> {
+        core::mem::swap(&mut self.instance_variables, &mut instance_variables);
+        instance_variables
+    }
+}
+
+/// A Regexp
+#[derive(Debug)]
+pub struct RegexpValue {
+    source: Vec<u8>,
+    options: u8,
+    user_class: Option<TypedValueHandle<SymbolValue>>,
+    extended_modules: Vec<TypedValueHandle<SymbolValue>>,
+    instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
+}
+
+impl RegexpValue {
+    /// Create a new [`RegexpValue`].
+    pub(crate) fn new(source: Vec<u8>, options: u8) -> Self {
+        Self {
+            source,
+            options,
+            user_class: None,
+            extended_modules: Vec::new(),
+            instance_variables: None,
+        }
+    }
+
+    /// Get the source of the pattern.
+    pub fn source(&self) -> &[u8] {
+        &self.source
+    }
+
+    /// Get the raw options byte.
+    pub fn raw_options(&self) -> u8 {
+        self.options
+    }
+
+    /// Get the options.
+    pub fn options(&self) -> RegexpOptions {
+        RegexpOptions::from_bits(self.options)
+    }
+
+    /// Get the user class name, if this is an instance of a subclass.
+    pub fn user_class(&self) -> Option<TypedValueHandle<SymbolValue>> {
+        self.user_class
+    }
+
+    /// Set the user class name.
+    ///
+    /// # Returns
+    /// Returns the old user class name
+    pub(crate) fn set_user_class(
+        &mut self,
+        mut user_class: Option<TypedValueHandle<SymbolValue>>,
+    ) -> Option<TypedValueHandle<SymbolValue>> {
+        core::mem::swap(&mut self.user_class, &mut user_class);
+        user_class
+    }
+
+    /// Get the names of the modules this value was extended with, outermost first.
+    pub fn extended_modules(&self) -> &[TypedValueHandle<SymbolValue>] {
+        &self.extended_modules
+    }
+
     /// Get the instance variables
     pub fn instance_variables(&self) -> Option<&[(TypedValueHandle<SymbolValue>, ValueHandle)]> {
         self.instance_variables.as_deref()
@@ -345,16 +1112,78 @@ impl StringValue {
         &mut self,
         mut instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
     ) -> Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>> {
-        std::mem::swap(&mut self.instance_variables, &mut instance_variables);
+        core::mem::swap(&mut self.instance_variables, &mut instance_variables);
         instance_variables
     }
 }
 
+/// The options of a Regexp, as in Ruby's `Regexp#options`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RegexpOptions(u8);
+
+impl RegexpOptions {
+    /// Ruby's `Regexp::IGNORECASE`, the `i` flag.
+    pub const IGNORECASE: u8 = 1;
+
+    /// Ruby's `Regexp::EXTENDED`, the `x` flag.
+    pub const EXTENDED: u8 = 2;
+
+    /// Ruby's `Regexp::MULTILINE`, the `m` flag.
+    pub const MULTILINE: u8 = 4;
+
+    /// Wrap a raw options byte.
+    ///
+    /// Bits without a constant, like Ruby's encoding flags, are kept.
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    /// Get the raw options byte.
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Check whether matching ignores case.
+    pub fn ignore_case(self) -> bool {
+        self.0 & Self::IGNORECASE != 0
+    }
+
+    /// Check whether whitespace and comments in the pattern are ignored.
+    pub fn extended(self) -> bool {
+        self.0 & Self::EXTENDED != 0
+    }
+
+    /// Check whether `.` matches newlines.
+    pub fn multiline(self) -> bool {
+        self.0 & Self::MULTILINE != 0
+    }
+
+    /// Get the equivalent inline flags of the `regex` crate, as in `(?flags)`.
+    ///
+    /// Ruby's `^` and `$` always match at line breaks, so the `m` flag is always included.
+    /// Ruby's multiline is the `regex` crate's `s` flag.
+    pub fn to_rust_regex_flags(self) -> String {
+        let mut flags = String::new();
+        if self.ignore_case() {
+            flags.push('i');
+        }
+        flags.push('m');
+        if self.multiline() {
+            flags.push('s');
+        }
+        if self.extended() {
+            flags.push('x');
+        }
+        flags
+    }
+}
+
 /// A User Defined value
 #[derive(Debug)]
 pub struct UserDefinedValue {
     name: TypedValueHandle<SymbolValue>,
     value: Vec<u8>,
+    extended_modules: Vec<TypedValueHandle<SymbolValue>>,
     instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
 }
 
@@ -364,6 +1193,7 @@ impl UserDefinedValue {
         Self {
             name,
             value,
+            extended_modules: Vec::new(),
             instance_variables: None,
         }
     }
@@ -373,11 +1203,57 @@ impl UserDefinedValue {
         self.name
     }
 
+    /// Set the name.
+    ///
+    /// # Returns
+    /// Returns the old name
+    pub(crate) fn set_name(
+        &mut self,
+        mut name: TypedValueHandle<SymbolValue>,
+    ) -> TypedValueHandle<SymbolValue> {
+        core::mem::swap(&mut self.name, &mut name);
+        name
+    }
+
     /// Get the inner value.
     pub fn value(&self) -> &[u8] {
         &self.value
     }
 
+    /// Set the inner value.
+    ///
+    /// # Returns
+    /// Returns the old inner value
+    pub(crate) fn set_value(&mut self, mut value: Vec<u8>) -> Vec<u8> {
+        core::mem::swap(&mut self.value, &mut value);
+        value
+    }
+
+    /// Load the inner value as a nested Marshal stream, header included.
+    ///
+    /// This is for classes whose `_dump` returns the output of `Marshal.dump`.
+    /// Fails with [`Error::NotNestedMarshal`] if the payload does not start with a supported
+    /// header, and with [`Error::TrailingBytes`] if the nested stream ends before the payload does.
+    pub fn decode_nested(&self, options: &LoadOptions) -> Result<ValueArena, Error> {
+        match self.value.as_slice() {
+            [major, minor, ..] if *major == MAJOR_VERSION && *minor <= MINOR_VERSION => {}
+            _ => return Err(Error::NotNestedMarshal),
+        }
+
+        let mut reader = self.value.as_slice();
+        let arena = load_with_options(&mut reader, options)?;
+        if !reader.is_empty() {
+            return Err(Error::TrailingBytes { len: reader.len() });
+        }
+
+        Ok(arena)
+    }
+
+    /// Get the names of the modules this value was extended with, outermost first.
+    pub fn extended_modules(&self) -> &[TypedValueHandle<SymbolValue>] {
+        &self.extended_modules
+    }
+
     /// Get the instance variables
     pub fn instance_variables(&self) -> Option<&[(TypedValueHandle<SymbolValue>, ValueHandle)]> {
         self.instance_variables.as_deref()
@@ -391,11 +1267,57 @@ impl UserDefinedValue {
         &mut self,
         mut instance_variables: Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>>,
     ) -> Option<Vec<(TypedValueHandle<SymbolValue>, ValueHandle)>> {
-        std::mem::swap(&mut self.instance_variables, &mut instance_variables);
+        core::mem::swap(&mut self.instance_variables, &mut instance_variables);
         instance_variables
     }
 }
 
+/// A User Marshal value, from a class with `marshal_dump` and `marshal_load`
+#[derive(Debug)]
+pub struct UserMarshalValue {
+    name: TypedValueHandle<SymbolValue>,
+    value: ValueHandle,
+    extended_modules: Vec<TypedValueHandle<SymbolValue>>,
+}
+
+impl UserMarshalValue {
+    /// Create a new [`UserMarshalValue`].
+    pub(crate) fn new(name: TypedValueHandle<SymbolValue>, value: ValueHandle) -> Self {
+        Self {
+            name,
+            value,
+            extended_modules: Vec::new(),
+        }
+    }
+
+    /// Get the name.
+    pub fn name(&self) -> TypedValueHandle<SymbolValue> {
+        self.name
+    }
+
+    /// Set the name.
+    ///
+    /// # Returns
+    /// Returns the old name
+    pub(crate) fn set_name(
+        &mut self,
+        mut name: TypedValueHandle<SymbolValue>,
+    ) -> TypedValueHandle<SymbolValue> {
+        core::mem::swap(&mut self.name, &mut name);
+        name
+    }
+
+    /// Get the value returned by `marshal_dump`.
+    pub fn value(&self) -> ValueHandle {
+        self.value
+    }
+
+    /// Get the names of the modules this value was extended with, outermost first.
+    pub fn extended_modules(&self) -> &[TypedValueHandle<SymbolValue>] {
+        &self.extended_modules
+    }
+}
+
 /// A Class
 #[derive(Debug)]
 pub struct ClassValue {
@@ -415,17 +1337,21 @@ impl ClassValue {
 }
 
 /// The kind of value
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum ValueKind {
     Nil,
     Bool,
     Fixnum,
+    Bignum,
     Float,
     Symbol,
     Array,
     Hash,
     Object,
+    Struct,
     String,
+    Regexp,
     UserDefined,
+    UserMarshal,
     Class,
 }
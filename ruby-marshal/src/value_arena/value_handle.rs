@@ -1,6 +1,7 @@
-use std::hash::Hash;
-use std::hash::Hasher;
-use std::marker::PhantomData;
+use core::hash::Hash;
+use core::hash::Hasher;
+use core::marker::PhantomData;
+use slotmap::Key;
 
 /// A handle around a Ruby Value.
 #[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
@@ -15,6 +16,17 @@ impl ValueHandle {
     pub(super) fn new(index: slotmap::DefaultKey) -> Self {
         Self { index }
     }
+
+    /// Create a [`ValueHandle`] for the given slot of a custom [`Storage`](crate::Storage).
+    pub fn from_index(index: u32) -> Self {
+        Self::new(slotmap::KeyData::from_ffi((1 << 32) | u64::from(index)).into())
+    }
+
+    /// Get the slot of this handle, as given to [`ValueHandle::from_index`].
+    pub fn to_index(self) -> u32 {
+        // The lower half is the slot, and the upper half is its version.
+        self.index.data().as_ffi() as u32
+    }
 }
 
 /// A typed version of a [`ValueHandle`].
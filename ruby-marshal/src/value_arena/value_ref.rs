@@ -0,0 +1,171 @@
+use super::GenericValueArena;
+use super::SlotMapStorage;
+use super::Storage;
+use super::Value;
+use super::ValueHandle;
+
+/// A handle resolved against its arena, that can be compared to Rust values.
+///
+/// Integers compare equal to Fixnums and Bignums, strings to Strings and Symbols,
+/// and `()` to nil.
+/// Invalid handles compare unequal to everything.
+pub struct ValueRef<'a, S = SlotMapStorage> {
+    arena: &'a GenericValueArena<S>,
+    handle: ValueHandle,
+}
+
+impl<'a, S> ValueRef<'a, S>
+where
+    S: Storage,
+{
+    /// Make a new [`ValueRef`].
+    pub(super) fn new(arena: &'a GenericValueArena<S>, handle: ValueHandle) -> Self {
+        Self { arena, handle }
+    }
+
+    /// Get the handle.
+    pub fn handle(&self) -> ValueHandle {
+        self.handle
+    }
+
+    /// Get the value, if the handle is valid.
+    pub fn value(&self) -> Option<&'a Value> {
+        self.arena.get(self.handle)
+    }
+
+    /// Get the bytes of a String or Symbol, if the value is one.
+    fn text_bytes(&self) -> Option<&'a [u8]> {
+        self.arena.as_text_bytes(self.handle)
+    }
+
+    /// Get the value of a Fixnum or Bignum, if the value is one and it fits.
+    fn integer(&self) -> Option<i128> {
+        match self.value()? {
+            Value::Fixnum(value) => Some(value.value().into()),
+            Value::Bignum(value) => value.to_i128(),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, S> core::fmt::Debug for ValueRef<'a, S>
+where
+    S: Storage,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ValueRef")
+            .field("handle", &self.handle)
+            .field("value", &self.value())
+            .finish()
+    }
+}
+
+impl<'a, S> PartialEq<()> for ValueRef<'a, S>
+where
+    S: Storage,
+{
+    fn eq(&self, _other: &()) -> bool {
+        matches!(self.value(), Some(Value::Nil(_)))
+    }
+}
+
+impl<'a, S> PartialEq<bool> for ValueRef<'a, S>
+where
+    S: Storage,
+{
+    fn eq(&self, other: &bool) -> bool {
+        matches!(self.value(), Some(Value::Bool(value)) if value.value() == *other)
+    }
+}
+
+impl<'a, S> PartialEq<i32> for ValueRef<'a, S>
+where
+    S: Storage,
+{
+    fn eq(&self, other: &i32) -> bool {
+        self.integer() == Some((*other).into())
+    }
+}
+
+impl<'a, S> PartialEq<i64> for ValueRef<'a, S>
+where
+    S: Storage,
+{
+    fn eq(&self, other: &i64) -> bool {
+        self.integer() == Some((*other).into())
+    }
+}
+
+impl<'a, S> PartialEq<i128> for ValueRef<'a, S>
+where
+    S: Storage,
+{
+    fn eq(&self, other: &i128) -> bool {
+        self.integer() == Some(*other)
+    }
+}
+
+impl<'a, S> PartialEq<f64> for ValueRef<'a, S>
+where
+    S: Storage,
+{
+    fn eq(&self, other: &f64) -> bool {
+        matches!(self.value(), Some(Value::Float(value)) if value.value() == *other)
+    }
+}
+
+impl<'a, S> PartialEq<str> for ValueRef<'a, S>
+where
+    S: Storage,
+{
+    fn eq(&self, other: &str) -> bool {
+        self.text_bytes() == Some(other.as_bytes())
+    }
+}
+
+impl<'a, 'b, S> PartialEq<&'b str> for ValueRef<'a, S>
+where
+    S: Storage,
+{
+    fn eq(&self, other: &&'b str) -> bool {
+        *self == **other
+    }
+}
+
+impl<'a, 'b, S> PartialEq<&'b [u8]> for ValueRef<'a, S>
+where
+    S: Storage,
+{
+    fn eq(&self, other: &&'b [u8]) -> bool {
+        self.text_bytes() == Some(*other)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::load;
+
+    #[test]
+    fn compare_to_rust_values() {
+        // Marshal.dump([nil, true, 42, 2**64, 1.5, "str", :sym])
+        let data = b"\x04\x08[\x0c0Ti\x2fl+\x0a\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\
+            f\x081.5I\"\x08str\x06:\x06ET:\x08sym";
+        let arena = load(&data[..]).expect("failed to load");
+        let array = arena[arena.root()].as_array().expect("not an array");
+        let values: Vec<_> = array.value().iter().map(|h| arena.get_ref(*h)).collect();
+
+        assert!(values[0] == ());
+        assert!(values[1] == true);
+        assert!(values[1] != false);
+        assert!(values[2] == 42);
+        assert!(values[2] == 42_i64);
+        assert!(values[2] != "42");
+        assert!(values[3] == 1_i128 << 64);
+        assert!(values[3] != 0_i64);
+        assert!(values[4] == 1.5);
+        assert!(values[5] == "str");
+        assert!(values[5] == &b"str"[..]);
+        assert!(values[6] == "sym");
+        assert!(values[6] != ());
+    }
+}
@@ -0,0 +1,73 @@
+use ruby_marshal::load;
+use ruby_marshal::validate_stream;
+use ruby_marshal::LoadOptions;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::cell::Cell;
+
+/// An allocator that tracks the peak number of bytes allocated by each thread.
+struct PeakAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let _ = ALLOCATED.try_with(|allocated| {
+                allocated.set(allocated.get() + layout.size());
+                let _ = PEAK.try_with(|peak| peak.set(peak.get().max(allocated.get())));
+            });
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        // Memory may be freed by a different thread than allocated it.
+        let _ = ALLOCATED
+            .try_with(|allocated| allocated.set(allocated.get().saturating_sub(layout.size())));
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAllocator = PeakAllocator;
+
+/// Get the most bytes allocated at once on this thread by a function, beyond what was
+/// already allocated.
+fn peak_allocated<F>(f: F) -> usize
+where
+    F: FnOnce(),
+{
+    let start = ALLOCATED.with(Cell::get);
+    PEAK.with(|peak| peak.set(start));
+    f();
+
+    PEAK.with(Cell::get) - start
+}
+
+#[test]
+fn validate_stream_memory() {
+    // Marshal.dump(Array.new(1000) { |i| "#{i}".ljust(1000, "a").b })
+    let mut data = b"\x04\x08[\x02\xe8\x03".to_vec();
+    for i in 0..1000 {
+        data.extend(b"\"\x02\xe8\x03");
+        data.extend(format!("{i:a<1000}").bytes());
+    }
+
+    let load_peak = peak_allocated(|| {
+        load(&data[..]).expect("failed to load");
+    });
+    let validate_peak = peak_allocated(|| {
+        validate_stream(&data[..], &LoadOptions::default()).expect("failed to validate");
+    });
+    assert!(load_peak > data.len(), "{load_peak}");
+    assert!(
+        validate_peak * 100 < load_peak,
+        "{validate_peak} >= {load_peak} / 100"
+    );
+}